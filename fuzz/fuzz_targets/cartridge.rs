@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8_emulator::cartridge::Cartridge;
+use chip8_emulator::cpu::Cpu;
+
+// Any file, however large, loads without panicking.
+fuzz_target!(|data: &[u8]| {
+    let mut cpu = Cpu::new();
+    cpu.load_cartridge(Cartridge::new(data));
+    cpu.reset();
+    cpu.load_cartridge(Cartridge::new(data));
+    assert_eq!(cpu.state().pc, 0x200);
+});
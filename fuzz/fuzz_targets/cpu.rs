@@ -0,0 +1,30 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8_emulator::cartridge::Cartridge;
+use chip8_emulator::cpu::Cpu;
+
+const FRAMES: usize = 60;
+const CYCLES_PER_FRAME: u32 = 10;
+
+// The first two bytes are the held keys, one bit per key, the rest is the ROM.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let keys = u16::from_le_bytes([data[0], data[1]]);
+
+    let mut cpu = Cpu::new();
+    cpu.load_cartridge(Cartridge::new(&data[2..]));
+    cpu.set_keypad_state(keys);
+
+    for _ in 0..FRAMES {
+        cpu.run_frame(CYCLES_PER_FRAME);
+        let state = cpu.state();
+        assert!(state.pc < 0x1000, "pc out of memory: {:04X}", state.pc);
+        assert!(state.sp <= 16, "sp out of the stack: {}", state.sp);
+        if cpu.fault().is_some() {
+            break;
+        }
+    }
+});
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8_emulator::cartridge::Cartridge;
+
+// Any patch applies or fails without panicking, and the ones made from two
+// cartridges give back the target. The first byte splits the rest of the
+// input in a ROM and a patch or a target.
+fuzz_target!(|data: &[u8]| {
+    let (split, data) = match data.split_first() {
+        Some((&split, data)) => ((split as usize).min(data.len()), data),
+        None => return,
+    };
+    let (rom, other) = data.split_at(split);
+    let source = Cartridge::new(rom);
+    let _ = source.apply_patch(other);
+
+    let target = Cartridge::new(other);
+    for patch in &[source.create_ips_patch(&target), source.create_bps_patch(&target)] {
+        let patched = source.apply_patch(patch).unwrap();
+        assert_eq!(patched.get_memory(), target.get_memory());
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use chip8_emulator::cpu::Cpu;
+
+// Random bytes are either rejected or restore a state the cpu can run from.
+fuzz_target!(|data: &[u8]| {
+    let mut cpu = Cpu::new();
+    if cpu.load_state(data).is_ok() {
+        assert_eq!(cpu.save_state(), data, "the state round trips");
+        cpu.run_frame(100);
+        let state = cpu.state();
+        assert!(state.pc < 0x1000, "pc out of memory: {:04X}", state.pc);
+        assert!(state.sp <= 16, "sp out of the stack: {}", state.sp);
+    }
+});
@@ -0,0 +1,463 @@
+// Static analysis of CHIP-8 programs: control-flow graph, subroutines,
+// unreachable code and writes into the code, without running them.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+use crate::opcode::{disassemble, Instruction};
+use crate::MEMORY_SIZE;
+
+const START: u16 = 0x200;
+const ADDRESS_MASK: u16 = (MEMORY_SIZE - 1) as u16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    // the next instruction, or the return site of a CALL
+    Next,
+    Jump,
+    // the instruction after the skipped one
+    Skip,
+    Call,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: u16,
+}
+
+// Straight-line run of instructions, only entered at its start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: u16,
+    // address following the last instruction
+    pub end: u16,
+    pub edges: Vec<Edge>,
+}
+
+impl Block {
+    // Address of every instruction of the block.
+    pub fn instructions(&self) -> impl Iterator<Item = u16> {
+        (self.start..self.end).step_by(2)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subroutine {
+    pub entry: u16,
+    // start of the blocks reachable from the entry without returning
+    pub blocks: Vec<u16>,
+    // whether any path reaches a RET
+    pub returns: bool,
+}
+
+// `LD B, Vx` or `LD [I], Vx` at `pc` writing `len` bytes at `start`, some of
+// which are instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodeWrite {
+    pub pc: u16,
+    pub start: u16,
+    pub len: u16,
+}
+
+pub struct Analysis {
+    memory: Vec<u8>,
+    // address following the program
+    end: u16,
+    // whether each byte of memory belongs to a reachable instruction
+    code: Vec<bool>,
+    blocks: BTreeMap<u16, Block>,
+    subroutines: Vec<Subroutine>,
+    computed_jumps: Vec<u16>,
+    invalid: Vec<u16>,
+    code_writes: Vec<CodeWrite>,
+}
+
+fn next(addr: u16, offset: u16) -> u16 {
+    addr.wrapping_add(offset) & ADDRESS_MASK
+}
+
+// Successors of the instruction at `addr`, and whether it ends its block.
+fn successors(addr: u16, instruction: Option<Instruction>) -> (Vec<Edge>, bool) {
+    let edge = |kind, target| Edge { kind, target };
+    match instruction {
+        Some(Instruction::Jp(nnn)) => (vec![edge(EdgeKind::Jump, nnn)], true),
+        Some(Instruction::Call(nnn)) => (
+            vec![
+                edge(EdgeKind::Call, nnn),
+                edge(EdgeKind::Next, next(addr, 2)),
+            ],
+            true,
+        ),
+        // the target of JP V0 depends on V0: the analysis stops there
+        Some(Instruction::Ret) | Some(Instruction::JpV0(_)) | None => (Vec::new(), true),
+        Some(instruction) if instruction.is_skip() => (
+            vec![
+                edge(EdgeKind::Next, next(addr, 2)),
+                edge(EdgeKind::Skip, next(addr, 4)),
+            ],
+            true,
+        ),
+        Some(_) => (vec![edge(EdgeKind::Next, next(addr, 2))], false),
+    }
+}
+
+impl Analysis {
+    fn opcode(&self, addr: u16) -> u16 {
+        (self.memory[addr as usize] as u16) << 8 | self.memory[next(addr, 1) as usize] as u16
+    }
+
+    fn instruction(&self, addr: u16) -> Option<Instruction> {
+        Instruction::decode(self.opcode(addr))
+    }
+
+    // Analyze a program loaded at 0x200, following every path from there.
+    pub fn new(program: &[u8]) -> Self {
+        let len = program.len().min(MEMORY_SIZE - START as usize);
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[START as usize..START as usize + len].copy_from_slice(&program[..len]);
+
+        let mut analysis = Analysis {
+            memory,
+            end: START + len as u16,
+            code: vec![false; MEMORY_SIZE],
+            blocks: BTreeMap::new(),
+            subroutines: Vec::new(),
+            computed_jumps: Vec::new(),
+            invalid: Vec::new(),
+            code_writes: Vec::new(),
+        };
+        let leaders = analysis.explore();
+        analysis.build_blocks(&leaders);
+        analysis.find_subroutines();
+        analysis.find_code_writes();
+        analysis
+    }
+
+    // Find every reachable instruction, and the ones starting a block.
+    fn explore(&mut self) -> BTreeSet<u16> {
+        let mut visited = BTreeSet::new();
+        let mut leaders = BTreeSet::new();
+        leaders.insert(START);
+        let mut pending = vec![START];
+        while let Some(addr) = pending.pop() {
+            if !visited.insert(addr) {
+                continue;
+            }
+            self.code[addr as usize] = true;
+            self.code[next(addr, 1) as usize] = true;
+
+            let instruction = self.instruction(addr);
+            match instruction {
+                Some(Instruction::JpV0(_)) => self.computed_jumps.push(addr),
+                None => self.invalid.push(addr),
+                _ => (),
+            }
+            let (edges, ends_block) = successors(addr, instruction);
+            for edge in edges {
+                // wrapping around the memory starts a new block as well
+                if ends_block || edge.target < addr {
+                    leaders.insert(edge.target);
+                }
+                pending.push(edge.target);
+            }
+        }
+        leaders
+    }
+
+    fn build_blocks(&mut self, leaders: &BTreeSet<u16>) {
+        for &start in leaders.iter() {
+            let mut addr = start;
+            let edges = loop {
+                let (edges, ends_block) = successors(addr, self.instruction(addr));
+                let following = next(addr, 2);
+                if ends_block || leaders.contains(&following) || following < addr {
+                    break edges;
+                }
+                addr = following;
+            };
+            let end = addr + 2;
+            self.blocks.insert(start, Block { start, end, edges });
+        }
+    }
+
+    fn find_subroutines(&mut self) {
+        let entries: BTreeSet<u16> = self
+            .blocks
+            .values()
+            .flat_map(|block| block.edges.iter())
+            .filter(|edge| edge.kind == EdgeKind::Call)
+            .map(|edge| edge.target)
+            .collect();
+        for entry in entries {
+            let mut blocks = BTreeSet::new();
+            let mut returns = false;
+            let mut pending = VecDeque::new();
+            pending.push_back(entry);
+            while let Some(start) = pending.pop_front() {
+                if !blocks.insert(start) {
+                    continue;
+                }
+                let block = &self.blocks[&start];
+                let last = block.end - 2;
+                if self.instruction(last) == Some(Instruction::Ret) {
+                    returns = true;
+                }
+                for edge in block.edges.iter() {
+                    if edge.kind != EdgeKind::Call {
+                        pending.push_back(edge.target);
+                    }
+                }
+            }
+            self.subroutines.push(Subroutine {
+                entry,
+                blocks: blocks.into_iter().collect(),
+                returns,
+            });
+        }
+    }
+
+    // Follow the value of I through each block, as long as it comes from a
+    // `LD I, addr`, to find the writes landing on instructions.
+    fn find_code_writes(&mut self) {
+        let mut writes = Vec::new();
+        for block in self.blocks.values() {
+            let mut i = None;
+            for addr in block.instructions() {
+                match self.instruction(addr) {
+                    Some(Instruction::LdI(nnn)) => i = Some(nnn),
+                    Some(Instruction::AddI(_)) | Some(Instruction::LdF(_)) => i = None,
+                    Some(instruction) => {
+                        let range = i.and_then(|i| instruction.memory_write(i));
+                        if let Some((start, len)) = range {
+                            if (0..len).any(|offset| self.code[next(start, offset) as usize]) {
+                                writes.push(CodeWrite {
+                                    pc: addr,
+                                    start,
+                                    len,
+                                });
+                            }
+                        }
+                    }
+                    None => (),
+                }
+            }
+        }
+        self.code_writes = writes;
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.values()
+    }
+
+    pub fn block(&self, start: u16) -> Option<&Block> {
+        self.blocks.get(&start)
+    }
+
+    pub fn subroutines(&self) -> &[Subroutine] {
+        &self.subroutines
+    }
+
+    // Address of the `JP V0, addr` instructions, whose targets are unknown.
+    pub fn computed_jumps(&self) -> &[u16] {
+        &self.computed_jumps
+    }
+
+    // Address of the reachable opcodes that do not decode.
+    pub fn invalid_opcodes(&self) -> &[u16] {
+        &self.invalid
+    }
+
+    pub fn code_writes(&self) -> &[CodeWrite] {
+        &self.code_writes
+    }
+
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.code[(addr & ADDRESS_MASK) as usize]
+    }
+
+    // Ranges of the program, start and end, never reached as code. These are
+    // sprites and other data, or code only reached through computed jumps.
+    pub fn unreachable(&self) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for addr in START..self.end {
+            if self.code[addr as usize] {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.1 == addr => range.1 = addr + 1,
+                _ => ranges.push((addr, addr + 1)),
+            }
+        }
+        ranges
+    }
+
+    // Graphviz rendering of the graph, one cluster per subroutine.
+    pub fn to_dot(&self) -> String {
+        let mut owner = BTreeMap::new();
+        for subroutine in self.subroutines.iter() {
+            for &block in subroutine.blocks.iter() {
+                owner.entry(block).or_insert(subroutine.entry);
+            }
+        }
+
+        let mut dot = String::new();
+        writeln!(dot, "digraph cfg {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        let node = |dot: &mut String, block: &Block, indent: &str| {
+            let mut label = String::new();
+            for addr in block.instructions() {
+                write!(label, "{:03X}: {}\\l", addr, disassemble(self.opcode(addr))).unwrap();
+            }
+            let color = if self.computed_jumps.contains(&(block.end - 2)) {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "{}\"{:03X}\" [label=\"{}\"{}];",
+                indent, block.start, label, color
+            )
+            .unwrap();
+        };
+        for block in self
+            .blocks
+            .values()
+            .filter(|block| !owner.contains_key(&block.start))
+        {
+            node(&mut dot, block, "    ");
+        }
+        for subroutine in self.subroutines.iter() {
+            writeln!(dot, "    subgraph cluster_{:03X} {{", subroutine.entry).unwrap();
+            writeln!(dot, "        label=\"sub_{:03X}\";", subroutine.entry).unwrap();
+            for block in subroutine.blocks.iter() {
+                if owner[block] == subroutine.entry {
+                    node(&mut dot, &self.blocks[block], "        ");
+                }
+            }
+            writeln!(dot, "    }}").unwrap();
+        }
+        for block in self.blocks.values() {
+            for edge in block.edges.iter() {
+                let style = match edge.kind {
+                    EdgeKind::Next => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                };
+                writeln!(
+                    dot,
+                    "    \"{:03X}\" -> \"{:03X}\"{};",
+                    block.start, edge.target, style
+                )
+                .unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CALL 0x20A - SE V0, 0x00 - JP 0x208 - JP V0, 0x300 - JP 0x208
+    // 0x20A: LD I, 0x20C - LD [I], V0 - RET - sprite
+    const PROGRAM: [u8; 17] = [
+        0x22, 0x0A, 0x30, 0x00, 0x12, 0x08, 0xB3, 0x00, 0x12, 0x08, 0xA2, 0x0C, 0xF0, 0x55, 0x00,
+        0xEE, 0xFF,
+    ];
+
+    #[test]
+    fn it_builds_the_control_flow_graph() {
+        let analysis = Analysis::new(&PROGRAM);
+        let starts: Vec<u16> = analysis.blocks().map(|block| block.start).collect();
+        assert_eq!(starts, vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(
+            analysis.block(0x200).unwrap().edges,
+            vec![
+                Edge {
+                    kind: EdgeKind::Call,
+                    target: 0x20A
+                },
+                Edge {
+                    kind: EdgeKind::Next,
+                    target: 0x202
+                },
+            ]
+        );
+        assert_eq!(analysis.block(0x20A).unwrap().end, 0x210);
+        assert_eq!(
+            analysis.block(0x202).unwrap().edges[1],
+            Edge {
+                kind: EdgeKind::Skip,
+                target: 0x206
+            }
+        );
+        assert_eq!(analysis.computed_jumps(), &[0x206]);
+        assert!(analysis.invalid_opcodes().is_empty());
+    }
+
+    #[test]
+    fn it_finds_subroutines_and_unreachable_code() {
+        let analysis = Analysis::new(&PROGRAM);
+        assert_eq!(
+            analysis.subroutines(),
+            &[Subroutine {
+                entry: 0x20A,
+                blocks: vec![0x20A],
+                returns: true,
+            }]
+        );
+        assert_eq!(analysis.unreachable(), vec![(0x210, 0x211)]);
+        assert!(analysis.is_code(0x20F));
+        assert!(!analysis.is_code(0x210));
+    }
+
+    #[test]
+    fn it_flags_writes_into_code() {
+        let analysis = Analysis::new(&PROGRAM);
+        assert_eq!(
+            analysis.code_writes(),
+            &[CodeWrite {
+                pc: 0x20C,
+                start: 0x20C,
+                len: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn it_exports_graphviz() {
+        let dot = Analysis::new(&PROGRAM).to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("    \"200\" [label=\"200: CALL 0x20A\\l\"];\n"));
+        assert!(dot.contains("    \"206\" [label=\"206: JP V0, 0x300\\l\", color=red];\n"));
+        assert!(dot.contains(
+            "    subgraph cluster_20A {\n        label=\"sub_20A\";\n        \"20A\" [label=\"20A: LD I, 0x20C\\l20C: LD [I], V0\\l20E: RET\\l\"];\n    }\n"
+        ));
+        assert!(dot.contains("    \"200\" -> \"20A\" [label=\"call\", style=dashed];\n"));
+        assert!(dot.contains("    \"202\" -> \"206\" [label=\"skip\"];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn it_analyzes_the_bundled_roms() {
+        for rom in [
+            &include_bytes!("../web/roms/INVADERS.ch8")[..],
+            &include_bytes!("../web/roms/TETRIS.ch8")[..],
+            &include_bytes!("../web/roms/UFO.ch8")[..],
+        ]
+        .iter()
+        {
+            let analysis = Analysis::new(rom);
+            assert!(analysis.blocks().count() > 10);
+            assert!(!analysis.subroutines().is_empty());
+            for subroutine in analysis.subroutines() {
+                assert!(analysis.block(subroutine.entry).is_some());
+            }
+        }
+    }
+}
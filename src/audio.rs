@@ -0,0 +1,146 @@
+use wasm_bindgen::prelude::*;
+
+pub mod wav;
+
+pub use self::wav::WavWriter;
+
+// The emulator runs at 60 frames per second.
+const FRAMES_PER_SECOND: f64 = 60.0;
+// Duration of the fade in and out of the tone, long enough to avoid clicks.
+const ENVELOPE_SECONDS: f32 = 0.002;
+
+// Square wave synthesizer driven by the sound timer. It generates the PCM
+// samples of one emulated frame at a time.
+#[wasm_bindgen]
+pub struct Audio {
+    sample_rate: u32,
+    // frequency of the tone in Hz
+    pitch: f32,
+    // peak amplitude, from 0.0 to 1.0
+    volume: f32,
+    // position in the current period of the wave, from 0.0 to 1.0
+    phase: f32,
+    // current gain of the envelope, from 0.0 to 1.0
+    gain: f32,
+    // fraction of a sample left over by the previous frames
+    sample_debt: f64,
+    // samples of the last rendered frame
+    samples: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl Audio {
+    pub fn new(sample_rate: u32) -> Audio {
+        Audio {
+            sample_rate: sample_rate.max(1),
+            pitch: 440.0,
+            volume: 0.25,
+            phase: 0.0,
+            gain: 0.0,
+            sample_debt: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch.max(0.0);
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+}
+
+impl Audio {
+    // Generate the samples of a frame. `gates` holds, for every instruction
+    // executed during the frame, whether the sound timer was active after it:
+    // the samples are spread evenly over the instructions, so the tone starts
+    // and stops at the right sample.
+    pub fn render_frame(&mut self, gates: &[bool]) -> &[f32] {
+        let exact = self.sample_rate as f64 / FRAMES_PER_SECOND + self.sample_debt;
+        let count = exact.floor() as usize;
+        self.sample_debt = exact - count as f64;
+
+        let envelope_step = 1.0 / (ENVELOPE_SECONDS * self.sample_rate as f32);
+        let phase_step = self.pitch / self.sample_rate as f32;
+
+        self.samples.clear();
+        for s in 0..count {
+            let on = match gates.len() {
+                0 => false,
+                len => gates[s * len / count],
+            };
+            self.gain = if on {
+                (self.gain + envelope_step).min(1.0)
+            } else {
+                (self.gain - envelope_step).max(0.0)
+            };
+            let level = if self.phase < 0.5 { 1.0 } else { -1.0 };
+            self.samples.push(level * self.volume * self.gain);
+            self.phase = (self.phase + phase_step).fract();
+        }
+        &self.samples
+    }
+
+    // Samples of the last rendered frame.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_one_frame_worth_of_samples() {
+        let mut audio = Audio::new(48000);
+        assert_eq!(audio.render_frame(&[false; 10]).len(), 800);
+
+        // 22050 / 60 = 367.5 samples per frame
+        let mut audio = Audio::new(22050);
+        let total: usize = (0..60).map(|_| audio.render_frame(&[]).len()).sum();
+        assert_eq!(total, 22050);
+    }
+
+    #[test]
+    fn it_is_silent_when_the_timer_is_off() {
+        let mut audio = Audio::new(44100);
+        assert!(audio.render_frame(&[false; 10]).iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn it_plays_a_square_wave_with_an_envelope() {
+        let mut audio = Audio::new(48000);
+        audio.set_pitch(1000.0);
+        audio.set_volume(0.5);
+        let samples = audio.render_frame(&[true; 10]).to_vec();
+
+        // fade in over 2ms = 96 samples, no click on the first sample
+        assert!(samples[0].abs() < 0.01);
+        assert!(samples[1..95].iter().all(|s| s.abs() < 0.5));
+        // 48 samples per period: 24 high, 24 low
+        assert_eq!(samples[96 + 6], 0.5);
+        assert_eq!(samples[96 + 30], -0.5);
+        assert_eq!(samples[96 + 54], 0.5);
+        assert!(samples.iter().all(|s| s.abs() <= 0.5));
+    }
+
+    #[test]
+    fn it_follows_the_timer_within_the_frame() {
+        let mut audio = Audio::new(48000);
+        // the timer expires after half of the instructions
+        let gates = [
+            true, true, true, true, true, false, false, false, false, false,
+        ];
+        let samples = audio.render_frame(&gates).to_vec();
+
+        assert!(samples[..400].iter().any(|&s| s != 0.0));
+        // silent once the release of the envelope is over
+        assert!(samples[400 + 96..].iter().all(|&s| s == 0.0));
+    }
+}
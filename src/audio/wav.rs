@@ -0,0 +1,109 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+const HEADER_SIZE: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+const CHANNELS: u16 = 1;
+
+// Audio capture sink writing mono 16-bit PCM samples to a RIFF/WAVE stream.
+// The sizes in the header are patched when the capture is finished.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+    // number of samples written so far
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        write_header(&mut writer, sample_rate, 0)?;
+        Ok(WavWriter {
+            writer,
+            sample_rate,
+            samples: 0,
+        })
+    }
+
+    // Append samples between -1.0 and 1.0, values out of range are clipped.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            bytes.extend_from_slice(&pcm.to_le_bytes());
+        }
+        self.writer.write_all(&bytes)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    // Write the final sizes in the header and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, self.sample_rate, self.samples)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, sample_rate: u32, samples: u32) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_size = samples * block_align as u32;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM format
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    writer.write_all(&header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn it_writes_an_empty_wav() {
+        let wav = WavWriter::new(Cursor::new(Vec::new()), 8000)
+            .unwrap()
+            .finish()
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(wav.len(), 44);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &36u32.to_le_bytes());
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&wav[28..32], &16000u32.to_le_bytes());
+        assert_eq!(&wav[36..44], &[b'd', b'a', b't', b'a', 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn it_writes_pcm_samples_and_patches_the_sizes() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        writer.write_samples(&[0.0, 1.0]).unwrap();
+        writer.write_samples(&[-1.0, 2.0]).unwrap();
+        let wav = writer.finish().unwrap().into_inner();
+
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &8u32.to_le_bytes());
+        assert_eq!(&wav[44..46], &0i16.to_le_bytes());
+        assert_eq!(&wav[46..48], &i16::MAX.to_le_bytes());
+        assert_eq!(&wav[48..50], &(-i16::MAX).to_le_bytes());
+        assert_eq!(&wav[50..52], &i16::MAX.to_le_bytes(), "clipped");
+    }
+}
@@ -0,0 +1,235 @@
+// Many independent machines stepped together by frames, for sweeps over a
+// ROM library: each instance has its own program, seed, speed, quirks and
+// inputs.
+//
+// With the `parallel` feature, the instances of a batch run on all the
+// cores with rayon. The wasm build has no threads and always runs them one
+// after the other, as does the default build.
+
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+use rayon::prelude::*;
+
+use crate::cartridge::Cartridge;
+use crate::cpu::{Cpu, Quirks};
+
+// A machine with the inputs to replay on it.
+pub struct Instance {
+    cpu: Cpu,
+    cycles_per_frame: u32,
+    // keys held from a frame on, by frame
+    inputs: Vec<(u64, u16)>,
+    // frames run so far
+    frame: u64,
+}
+
+impl Instance {
+    // A machine running `program`, with the seed 1 and 10 instructions per
+    // frame like the frontends.
+    pub fn new(program: &[u8]) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(program));
+        Instance {
+            cpu,
+            cycles_per_frame: 10,
+            inputs: Vec::new(),
+            frame: 0,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.cpu.set_seed(seed);
+        self
+    }
+
+    pub fn with_cycles_per_frame(mut self, cycles: u32) -> Self {
+        self.cycles_per_frame = cycles;
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.cpu.set_quirks(quirks);
+        self
+    }
+
+    // Hold the keys whose bit is set from `frame` on, until the next input.
+    pub fn with_input(mut self, frame: u64, keys: u16) -> Self {
+        let at = self.inputs.partition_point(|&(other, _)| other <= frame);
+        self.inputs.insert(at, (frame, keys));
+        self
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    // Number of frames run so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            let held = self
+                .inputs
+                .partition_point(|&(frame, _)| frame <= self.frame);
+            if held > 0 && self.inputs[held - 1].0 == self.frame {
+                self.cpu.set_keypad_state(self.inputs[held - 1].1);
+            }
+            self.cpu.run_frame(self.cycles_per_frame);
+            self.frame += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Batch {
+    instances: Vec<Instance>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    // Add an instance, returning its index.
+    pub fn add(&mut self, instance: Instance) -> usize {
+        self.instances.push(instance);
+        self.instances.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    pub fn instances_mut(&mut self) -> &mut [Instance] {
+        &mut self.instances
+    }
+
+    // Run every instance for `frames` frames.
+    pub fn run_frames(&mut self, frames: u32) {
+        self.for_each(|instance| instance.run_frames(frames));
+    }
+
+    // Call `f` on every instance, in parallel with the `parallel` feature.
+    pub fn for_each<F>(&mut self, f: F)
+    where
+        F: Fn(&mut Instance) + Send + Sync,
+    {
+        #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+        self.instances.par_iter_mut().for_each(f);
+        #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+        self.instances.iter_mut().for_each(f);
+    }
+
+    // The result of `f` for every instance, in order.
+    pub fn map<T, F>(&self, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&Instance) -> T + Send + Sync,
+    {
+        #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+        let results = self.instances.par_iter().map(f).collect();
+        #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+        let results = self.instances.iter().map(f).collect();
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws a random digit at the key pressed, or waits for one.
+    const PROGRAM: &[u8] = &[
+        0xF1, 0x0A, // LD V1, K
+        0xC0, 0x0F, // RND V0, 0x0F
+        0xF0, 0x29, // LD F, V0
+        0xD1, 0x15, // DRW V1, V1, 5
+        0x12, 0x00, // JP 0x200
+    ];
+
+    #[test]
+    fn instances_run_independently() {
+        let mut batch = Batch::new();
+        for seed in 1..=4 {
+            batch.add(Instance::new(PROGRAM).with_seed(seed).with_input(2, 1 << 5));
+        }
+        let waiting = batch.add(Instance::new(PROGRAM).with_cycles_per_frame(1));
+        batch.run_frames(3);
+
+        let frames = batch.map(|instance| instance.frame());
+        assert_eq!(frames, vec![3; 5]);
+        let states = batch.map(|instance| instance.cpu().state());
+        assert!(states[..4].iter().all(|state| state.v[1] == 5));
+        assert!(states[1..4]
+            .iter()
+            .any(|state| state.v[0] != states[0].v[0]));
+        assert_eq!(states[waiting].pc, 0x200);
+        assert_eq!(states[waiting].v[1], 0);
+    }
+
+    #[test]
+    fn instances_are_deterministic() {
+        let run = || {
+            let mut batch = Batch::new();
+            for seed in 0..8 {
+                let instance = Instance::new(PROGRAM)
+                    .with_seed(seed)
+                    .with_input(1, 1 << (seed % 16))
+                    .with_input(4, 0)
+                    .with_input(6, 1 << 3);
+                batch.add(instance);
+            }
+            batch.run_frames(10);
+            batch.map(|instance| instance.cpu().state())
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn inputs_are_held_until_the_next_one() {
+        let mut instance = Instance::new(PROGRAM)
+            .with_input(3, 0)
+            .with_input(1, 1 << 7);
+        instance.run_frames(2);
+        assert_eq!(instance.cpu().state().v[1], 7);
+        instance.run_frames(3);
+        assert_eq!(instance.cpu().state().pc, 0x200);
+    }
+
+    #[test]
+    fn instances_have_their_own_quirks() {
+        // LD V1, 0x81 - SHR V0, V1
+        let program = [0x61, 0x81, 0x80, 0x16];
+        let mut batch = Batch::new();
+        for quirks in Quirks::all() {
+            batch.add(Instance::new(&program).with_quirks(quirks));
+        }
+        batch.run_frames(1);
+
+        let shifted = batch.map(|instance| {
+            let state = instance.cpu().state();
+            (instance.cpu().quirks().shift_vy, state.v[0], state.v[0xF])
+        });
+        assert_eq!(shifted.len(), 16);
+        for (shift_vy, v0, vf) in shifted {
+            assert_eq!((v0, vf), if shift_vy { (0x40, 1) } else { (0, 0) });
+        }
+    }
+}
@@ -0,0 +1,421 @@
+// Headless runner: executes a ROM for a number of frames and exports what
+// happened on the display.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+use chip8_emulator::analysis::Analysis;
+use chip8_emulator::audio::WavWriter;
+use chip8_emulator::cartridge::{patch, Cartridge};
+use chip8_emulator::cpu::{Cpu, SmcAction, TraceFormat};
+use chip8_emulator::decompiler::decompile;
+use chip8_emulator::display::{Palette, PalettePreset};
+use chip8_emulator::gdb::GdbServer;
+use chip8_emulator::octo::{self, Target};
+
+// a 64x64 square per pixel already makes an 8192x4096 image in high
+// resolution
+const MAX_SCALE: usize = 64;
+
+const USAGE: &str = "usage: chip8 ROM [options]
+
+ROMs ending in .8o are Octo source, compiled before running.
+
+options:
+    --frames N             number of frames to run (default: 600)
+    --cycles-per-frame N   instructions executed per frame (default: 10)
+    --palette NAME         classic, amber, lcd or high-contrast (default: classic)
+    --scale N              size of an exported pixel, from 1 to 64 (default: 8)
+    --screenshot-at FRAME  save the display after FRAME frames to screenshot-FRAME.png
+    --dump-frames DIR      save the display after every frame to DIR/frame-NNNNNN.png
+    --record-gif FILE      record the display to an animated GIF
+    --record-start FRAME   first frame of the recording (default: 1)
+    --record-stop FRAME    last frame of the recording (default: the last one)
+    --record-wav FILE      record the sound of the whole run to a WAV file
+    --sample-rate N        sample rate of the recorded sound (default: 44100)
+    --trace FILE           write every executed instruction to FILE
+    --trace-format FORMAT  compact (PC, opcode, V0-VF, I, SP) or full (default: full)
+    --profile FILE         write execution statistics to FILE as JSON and print a summary
+    --coverage FILE        write a disassembly of the ROM marking the code that never ran
+    --cfg FILE             write the control-flow graph of the ROM to FILE in Graphviz format
+    --decompile FILE       write the ROM to FILE as Octo source
+    --smc ACTION           report the writes into code: log each one, break on the first one
+                           or count them
+    --gdb ADDRESS          wait for a GDB client on ADDRESS, like 127.0.0.1:1234, before running
+    --symbols FILE         load labels and source lines from a symbol file for the trace,
+                           the coverage listing and the breakpoints
+    --break LOCATION       stop at a label, label+offset or address, like draw_player+0x4
+                           (can be repeated)
+    --cheats FILE          apply the cheats saved for the ROM in FILE
+    --patch FILE           apply an IPS or BPS patch to the ROM
+    --target TARGET        compile .8o sources for chip8, schip or xochip (default: chip8)
+    --save-rom FILE        write the compiled .8o source to FILE
+    --save-symbols FILE    write the symbols of the compiled .8o source to FILE";
+
+struct Options {
+    rom: PathBuf,
+    frames: u32,
+    cycles_per_frame: u32,
+    palette: PalettePreset,
+    scale: usize,
+    screenshots: Vec<u32>,
+    dump_frames: Option<PathBuf>,
+    record_gif: Option<PathBuf>,
+    record_start: Option<u32>,
+    record_stop: Option<u32>,
+    record_wav: Option<PathBuf>,
+    sample_rate: u32,
+    trace: Option<PathBuf>,
+    trace_format: TraceFormat,
+    profile: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    cfg: Option<PathBuf>,
+    decompile: Option<PathBuf>,
+    smc: Option<SmcAction>,
+    gdb: Option<String>,
+    symbols: Option<PathBuf>,
+    breakpoints: Vec<String>,
+    cheats: Option<PathBuf>,
+    patch: Option<PathBuf>,
+    target: Target,
+    save_rom: Option<PathBuf>,
+    save_symbols: Option<PathBuf>,
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got {}", flag, value))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom: PathBuf::new(),
+        frames: 600,
+        cycles_per_frame: 10,
+        palette: PalettePreset::Classic,
+        scale: 8,
+        screenshots: Vec::new(),
+        dump_frames: None,
+        record_gif: None,
+        record_start: None,
+        record_stop: None,
+        record_wav: None,
+        sample_rate: 44100,
+        trace: None,
+        trace_format: TraceFormat::Full,
+        profile: None,
+        coverage: None,
+        cfg: None,
+        decompile: None,
+        smc: None,
+        gdb: None,
+        symbols: None,
+        breakpoints: Vec::new(),
+        cheats: None,
+        patch: None,
+        target: Target::Chip8,
+        save_rom: None,
+        save_symbols: None,
+    };
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--frames" => options.frames = parse_number(&arg, args.next())?,
+            "--cycles-per-frame" => options.cycles_per_frame = parse_number(&arg, args.next())?,
+            "--scale" => options.scale = parse_number(&arg, args.next())?,
+            "--screenshot-at" => options.screenshots.push(parse_number(&arg, args.next())?),
+            "--palette" => {
+                let name = args.next().ok_or("--palette expects a value")?;
+                options.palette = name.parse()?;
+            }
+            "--dump-frames" => {
+                let dir = args.next().ok_or("--dump-frames expects a directory")?;
+                options.dump_frames = Some(PathBuf::from(dir));
+            }
+            "--record-gif" => {
+                let file = args.next().ok_or("--record-gif expects a file")?;
+                options.record_gif = Some(PathBuf::from(file));
+            }
+            "--record-start" => options.record_start = Some(parse_number(&arg, args.next())?),
+            "--record-stop" => options.record_stop = Some(parse_number(&arg, args.next())?),
+            "--record-wav" => {
+                let file = args.next().ok_or("--record-wav expects a file")?;
+                options.record_wav = Some(PathBuf::from(file));
+            }
+            "--sample-rate" => options.sample_rate = parse_number(&arg, args.next())?,
+            "--trace" => {
+                let file = args.next().ok_or("--trace expects a file")?;
+                options.trace = Some(PathBuf::from(file));
+            }
+            "--trace-format" => {
+                options.trace_format = match args.next().as_deref() {
+                    Some("compact") => TraceFormat::Compact,
+                    Some("full") => TraceFormat::Full,
+                    _ => return Err("--trace-format expects compact or full".to_string()),
+                }
+            }
+            "--profile" => {
+                let file = args.next().ok_or("--profile expects a file")?;
+                options.profile = Some(PathBuf::from(file));
+            }
+            "--coverage" => {
+                let file = args.next().ok_or("--coverage expects a file")?;
+                options.coverage = Some(PathBuf::from(file));
+            }
+            "--cfg" => {
+                let file = args.next().ok_or("--cfg expects a file")?;
+                options.cfg = Some(PathBuf::from(file));
+            }
+            "--decompile" => {
+                let file = args.next().ok_or("--decompile expects a file")?;
+                options.decompile = Some(PathBuf::from(file));
+            }
+            "--smc" => {
+                options.smc = match args.next().as_deref() {
+                    Some("log") => Some(SmcAction::Log),
+                    Some("break") => Some(SmcAction::Break),
+                    Some("count") => Some(SmcAction::Count),
+                    _ => return Err("--smc expects log, break or count".to_string()),
+                }
+            }
+            "--gdb" => options.gdb = Some(args.next().ok_or("--gdb expects an address")?),
+            "--symbols" => {
+                let file = args.next().ok_or("--symbols expects a file")?;
+                options.symbols = Some(PathBuf::from(file));
+            }
+            "--break" => {
+                let location = args.next().ok_or("--break expects a location")?;
+                options.breakpoints.push(location);
+            }
+            "--cheats" => {
+                let file = args.next().ok_or("--cheats expects a file")?;
+                options.cheats = Some(PathBuf::from(file));
+            }
+            "--patch" => {
+                let file = args.next().ok_or("--patch expects a file")?;
+                options.patch = Some(PathBuf::from(file));
+            }
+            "--target" => {
+                let name = args.next().ok_or("--target expects a value")?;
+                options.target = name.parse()?;
+            }
+            "--save-rom" => {
+                let file = args.next().ok_or("--save-rom expects a file")?;
+                options.save_rom = Some(PathBuf::from(file));
+            }
+            "--save-symbols" => {
+                let file = args.next().ok_or("--save-symbols expects a file")?;
+                options.save_symbols = Some(PathBuf::from(file));
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    options.rom = rom.ok_or("missing ROM")?;
+    if options.scale == 0 || options.scale > MAX_SCALE {
+        return Err(format!("--scale expects a number from 1 to {}", MAX_SCALE));
+    }
+    if options.sample_rate == 0 {
+        return Err("--sample-rate expects a positive number".to_string());
+    }
+    if options.record_gif.is_none() {
+        if options.record_start.is_some() || options.record_stop.is_some() {
+            return Err("--record-start and --record-stop need --record-gif".to_string());
+        }
+    } else {
+        let start = options.record_start.unwrap_or(1);
+        let stop = options.record_stop.unwrap_or(options.frames);
+        if start == 0 || start > stop || stop > options.frames {
+            return Err("the recording must start and stop within the frames run".to_string());
+        }
+    }
+    Ok(options)
+}
+
+fn write_file(path: &PathBuf, data: &[u8]) -> Result<(), String> {
+    fs::write(path, data).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+// Writes the GIF if a recording is running, does nothing otherwise.
+fn save_recording(options: &Options, cpu: &mut Cpu) -> Result<(), String> {
+    match (&options.record_gif, cpu.stop_recording()) {
+        (Some(path), Some(gif)) => write_file(path, &gif),
+        _ => Ok(()),
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut program = fs::read(&options.rom)
+        .map_err(|e| format!("cannot read {}: {}", options.rom.display(), e))?;
+    let mut symbols = None;
+    if options.rom.extension() == Some("8o".as_ref()) {
+        let source = String::from_utf8(program)
+            .map_err(|_| format!("{} is not UTF-8 text", options.rom.display()))?;
+        let file = options.rom.display().to_string();
+        let compiled = octo::compile(&file, &source, options.target).map_err(|e| e.to_string())?;
+        if let Some(path) = &options.save_rom {
+            write_file(path, &compiled.bytes)?;
+        }
+        if let Some(path) = &options.save_symbols {
+            write_file(path, compiled.symbols.to_json().as_bytes())?;
+        }
+        program = compiled.bytes;
+        symbols = Some(compiled.symbols);
+    }
+    if let Some(path) = &options.patch {
+        let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        program =
+            patch::apply(&program, &bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    if let Some(dir) = &options.dump_frames {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+
+    if let Some(path) = &options.cfg {
+        write_file(path, Analysis::new(&program).to_dot().as_bytes())?;
+    }
+    if let Some(path) = &options.decompile {
+        write_file(path, decompile(&program).as_bytes())?;
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.set_palette(Palette::preset(options.palette));
+    cpu.load_cartridge(Cartridge::new(&program));
+    if let Some(symbols) = symbols {
+        cpu.set_symbols(symbols);
+    }
+    if let Some(path) = &options.symbols {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        cpu.load_symbols(&json)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    if let Some(path) = &options.cheats {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        cpu.load_cheats(&json)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    let mut wav = match &options.record_wav {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("cannot create {}: {}", path.display(), e))?;
+            cpu.enable_audio(options.sample_rate);
+            Some(
+                WavWriter::new(BufWriter::new(file), options.sample_rate)
+                    .map_err(|e| e.to_string())?,
+            )
+        }
+        None => None,
+    };
+
+    let mut trace = match &options.trace {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("cannot create {}: {}", path.display(), e))?;
+            // the trace is drained every frame, a frame worth of entries is enough
+            cpu.enable_trace(options.cycles_per_frame as usize, options.trace_format);
+            Some(BufWriter::new(file))
+        }
+        None => None,
+    };
+
+    if options.profile.is_some() {
+        cpu.enable_profiler();
+    }
+    if options.coverage.is_some() {
+        cpu.enable_coverage();
+    }
+    if let Some(action) = options.smc {
+        cpu.detect_self_modifying_code(action);
+    }
+
+    if let Some(addr) = &options.gdb {
+        let server = GdbServer::bind(addr.as_str())
+            .map_err(|e| format!("cannot listen on {}: {}", addr, e))?;
+        eprintln!("waiting for GDB on {}", addr);
+        server.serve(&mut cpu).map_err(|e| e.to_string())?;
+        // the frames run from where the debugger left the cpu
+        cpu.clear_breakpoints();
+        cpu.resume();
+    }
+    for location in options.breakpoints.iter() {
+        cpu.add_breakpoint_at(location)?;
+    }
+
+    let record_start = options.record_start.unwrap_or(1);
+    let record_stop = options.record_stop.unwrap_or(options.frames);
+    for frame in 1..=options.frames {
+        if options.record_gif.is_some() && frame == record_start {
+            cpu.start_recording(options.scale);
+        }
+
+        cpu.run_frame(options.cycles_per_frame);
+        if let Some(trace) = &mut trace {
+            for entry in cpu.take_trace() {
+                let line = entry.format_with_symbols(options.trace_format, cpu.symbols());
+                writeln!(trace, "{}", line).map_err(|e| e.to_string())?;
+            }
+        }
+        if let Some(wav) = &mut wav {
+            wav.write_samples(cpu.audio_samples())
+                .map_err(|e| e.to_string())?;
+        }
+        eprint!("{}", cpu.self_modifying_log());
+
+        if options.screenshots.contains(&frame) {
+            let path = PathBuf::from(format!("screenshot-{}.png", frame));
+            write_file(&path, &cpu.screenshot_png(options.scale)?)?;
+        }
+        if let Some(dir) = &options.dump_frames {
+            let path = dir.join(format!("frame-{:06}.png", frame));
+            write_file(&path, &cpu.screenshot_png(options.scale)?)?;
+        }
+        if frame == record_stop {
+            save_recording(&options, &mut cpu)?;
+        }
+        if let Some(reason) = cpu.break_message() {
+            eprintln!("frame {}: {}", frame, reason);
+            break;
+        }
+    }
+    // a break stops the frames before the end of the recording
+    save_recording(&options, &mut cpu)?;
+    if options.smc == Some(SmcAction::Count) {
+        eprintln!("{} self-modifying writes", cpu.self_modifying_writes());
+    }
+    if let Some(wav) = wav {
+        wav.finish().map_err(|e| e.to_string())?;
+    }
+    if let (Some(path), Some(json)) = (&options.profile, cpu.profile_json()) {
+        write_file(path, json.as_bytes())?;
+        print!("{}", cpu.profile_report(20).unwrap_or_default());
+    }
+    if let (Some(path), Some(listing)) = (&options.coverage, cpu.coverage_listing()) {
+        write_file(path, listing.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -1,5 +1,11 @@
 use wasm_bindgen::prelude::*;
 
+pub mod patch;
+
+pub use self::patch::PatchError;
+
+use crate::decompiler::decompile;
+
 #[wasm_bindgen]
 pub struct Cartridge {
     memory: Vec<u8>,
@@ -16,6 +22,35 @@ impl Cartridge {
     pub fn get_memory(&self) -> Vec<u8> {
         self.memory.clone()
     }
+
+    // The program as Octo source, see the `decompiler` module.
+    pub fn decompile(&self) -> String {
+        decompile(&self.memory)
+    }
+
+    // Apply an IPS or BPS patch, see the `patch` module.
+    pub fn apply_patch(&self, patch: &[u8]) -> Result<Cartridge, String> {
+        self.patched(patch).map_err(|e| e.to_string())
+    }
+
+    // An IPS patch turning this cartridge into `target`.
+    pub fn create_ips_patch(&self, target: &Cartridge) -> Vec<u8> {
+        patch::create_ips(&self.memory, &target.memory)
+    }
+
+    // A BPS patch turning this cartridge into `target`, only applying to
+    // this very cartridge.
+    pub fn create_bps_patch(&self, target: &Cartridge) -> Vec<u8> {
+        patch::create_bps(&self.memory, &target.memory)
+    }
+}
+
+impl Cartridge {
+    pub fn patched(&self, patch: &[u8]) -> Result<Cartridge, PatchError> {
+        Ok(Cartridge {
+            memory: patch::apply(&self.memory, patch)?,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -27,4 +62,21 @@ mod tests {
         let cartridge = Cartridge::new(&[2, 3, 4, 5]);
         assert_eq!(cartridge.get_memory().len(), 4);
     }
+
+    #[test]
+    fn cartridges_can_be_patched() {
+        let original = Cartridge::new(&[0x00, 0xE0, 0x12, 0x00]);
+        let fixed = Cartridge::new(&[0x00, 0xE0, 0x12, 0x02, 0x12, 0x02]);
+        for patch in &[
+            original.create_ips_patch(&fixed),
+            original.create_bps_patch(&fixed),
+        ] {
+            let patched = original.apply_patch(patch).unwrap();
+            assert_eq!(patched.get_memory(), fixed.get_memory());
+        }
+        assert_eq!(
+            original.apply_patch(b"UPS1").err(),
+            Some("not an IPS or BPS patch".to_string())
+        );
+    }
 }
@@ -0,0 +1,521 @@
+// IPS and BPS patches, to distribute fixes and translations of a ROM
+// without the ROM itself.
+//
+// IPS is a list of records overwriting the source, each one an offset and
+// the bytes to write there, or a byte repeated (RLE). A three bytes
+// extension after the `EOF` marker truncates the result.
+//
+// BPS encodes the target as reads from the source or from the patch, and
+// copies from anywhere in the source or the target already written. It
+// ends with the CRC-32 of the source, the target and the patch itself,
+// so that a patch never applies silently to the wrong ROM.
+
+use std::fmt;
+
+use crate::checksum::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+// records longer than that are split
+const IPS_MAX_RECORD: usize = 0xFFFF;
+// unchanged bytes between two changes cost less than a new record
+const IPS_RECORD_HEADER: usize = 5;
+const BPS_MAGIC: &[u8] = b"BPS1";
+// source, target and patch CRC-32
+const BPS_FOOTER: usize = 12;
+// the 64 KiB of XO-CHIP, far more than any program
+pub const MAX_PATCHED_SIZE: usize = 0x10000;
+
+// Reason a patch could not be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    UnknownFormat,
+    // the patch ends in the middle of a record or an action
+    Truncated,
+    // the result would be larger than `MAX_PATCHED_SIZE`
+    TooLarge(u64),
+    // an action reads past the end of the source or the target
+    OutOfBounds,
+    PatchChecksum { expected: u32, actual: u32 },
+    // the patch is for another ROM
+    SourceChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "truncated patch"),
+            PatchError::TooLarge(size) => write!(
+                f,
+                "patched ROM would be {} bytes long, at most {} are supported",
+                size, MAX_PATCHED_SIZE
+            ),
+            PatchError::OutOfBounds => write!(f, "patch reads out of the ROM"),
+            PatchError::PatchChecksum { expected, actual } => write!(
+                f,
+                "corrupted patch: CRC-32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            PatchError::SourceChecksum { expected, actual } => write!(
+                f,
+                "patch is for another ROM: CRC-32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            PatchError::TargetChecksum { expected, actual } => write!(
+                f,
+                "patched ROM has CRC-32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+// Cursor over the bytes of a patch.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if len > self.bytes.len() {
+            return Err(PatchError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .take(len)?
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    // BPS numbers: 7 bits per byte, the last one flagged with the high bit.
+    fn number(&mut self) -> Result<u64, PatchError> {
+        let mut value: u64 = 0;
+        let mut shift: u64 = 1;
+        loop {
+            let byte = self.u8()?;
+            value = value.saturating_add((byte as u64 & 0x7F).saturating_mul(shift));
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.saturating_mul(0x80);
+            value = value.saturating_add(shift);
+        }
+    }
+}
+
+fn check_size(size: u64) -> Result<usize, PatchError> {
+    if size > MAX_PATCHED_SIZE as u64 {
+        Err(PatchError::TooLarge(size))
+    } else {
+        Ok(size as usize)
+    }
+}
+
+// Apply an IPS or BPS patch, told apart by their header.
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(source, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(source, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = Reader { bytes: patch };
+    if reader.take(IPS_MAGIC.len())? != IPS_MAGIC {
+        return Err(PatchError::UnknownFormat);
+    }
+    let mut target = source.to_vec();
+    loop {
+        if reader.bytes.starts_with(IPS_EOF) {
+            reader.take(IPS_EOF.len())?;
+            break;
+        }
+        let offset = reader.be(3)?;
+        let len = reader.be(2)?;
+        let (len, data) = if len == 0 {
+            let len = reader.be(2)?;
+            (len, None)
+        } else {
+            (len, Some(reader.take(len)?))
+        };
+        let end = check_size((offset + len) as u64)?;
+        if target.len() < end {
+            target.resize(end, 0);
+        }
+        match data {
+            Some(data) => target[offset..end].copy_from_slice(data),
+            None => {
+                let value = reader.u8()?;
+                target[offset..end]
+                    .iter_mut()
+                    .for_each(|byte| *byte = value);
+            }
+        }
+    }
+    if reader.bytes.len() >= 3 {
+        let len = reader.be(3)?;
+        target.truncate(len);
+    }
+    Ok(target)
+}
+
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER {
+        return Err(PatchError::Truncated);
+    }
+    let (body, footer) = patch.split_at(patch.len() - BPS_FOOTER);
+    let crc = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let checksums = [crc(&footer[0..]), crc(&footer[4..]), crc(&footer[8..])];
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != checksums[2] {
+        return Err(PatchError::PatchChecksum {
+            expected: checksums[2],
+            actual,
+        });
+    }
+    let actual = crc32(source);
+    if actual != checksums[0] {
+        return Err(PatchError::SourceChecksum {
+            expected: checksums[0],
+            actual,
+        });
+    }
+
+    let mut reader = Reader { bytes: body };
+    if reader.take(BPS_MAGIC.len())? != BPS_MAGIC {
+        return Err(PatchError::UnknownFormat);
+    }
+    let source_size = reader.number()?;
+    if source_size != source.len() as u64 {
+        return Err(PatchError::OutOfBounds);
+    }
+    let target_size = check_size(reader.number()?)?;
+    let metadata_size = reader.number()?;
+    reader.take(check_size(metadata_size)?)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: i64 = 0;
+    let mut target_offset: i64 = 0;
+    while !reader.bytes.is_empty() {
+        let action = reader.number()?;
+        let len = check_size((action >> 2) + 1)?;
+        if target.len() + len > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+        match action & 3 {
+            // source read, at the same offset as the target
+            0 => {
+                let start = target.len();
+                let bytes = source
+                    .get(start..start + len)
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+            }
+            // target read, from the patch
+            1 => target.extend_from_slice(reader.take(len)?),
+            // source copy, from anywhere in the source
+            2 => {
+                source_offset += relative_offset(reader.number()?);
+                let start = usize_offset(source_offset)?;
+                let bytes = source
+                    .get(start..start + len)
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+                source_offset += len as i64;
+            }
+            // target copy, overlapping what it writes to repeat a pattern
+            _ => {
+                target_offset += relative_offset(reader.number()?);
+                let start = usize_offset(target_offset)?;
+                for i in start..start + len {
+                    let byte = *target.get(i).ok_or(PatchError::OutOfBounds)?;
+                    target.push(byte);
+                }
+                target_offset += len as i64;
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    let actual = crc32(&target);
+    if actual != checksums[1] {
+        return Err(PatchError::TargetChecksum {
+            expected: checksums[1],
+            actual,
+        });
+    }
+    Ok(target)
+}
+
+// Copy offsets are relative to the previous copy, the sign in the low bit.
+fn relative_offset(number: u64) -> i64 {
+    let value = (number >> 1).min(i32::MAX as u64) as i64;
+    if number & 1 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+fn usize_offset(offset: i64) -> Result<usize, PatchError> {
+    if offset < 0 || offset > MAX_PATCHED_SIZE as i64 {
+        Err(PatchError::OutOfBounds)
+    } else {
+        Ok(offset as usize)
+    }
+}
+
+// The ranges of `target` that differ from `source` or lie past its end,
+// merged when they are closer than `gap`.
+fn changes(source: &[u8], target: &[u8], gap: usize) -> Vec<(usize, usize)> {
+    let differs = |i: usize| source.get(i) != target.get(i);
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < target.len() && differs(i) {
+            i += 1;
+        }
+        match ranges.last_mut() {
+            Some(last) if start - last.1 < gap => last.1 = i,
+            _ => ranges.push((start, i)),
+        }
+    }
+    ranges
+}
+
+// An IPS patch turning `source` into `target`. Runs of a single byte are
+// written as RLE records.
+pub fn create_ips(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut record = |offset: usize, bytes: &[u8]| {
+        patch.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+        if bytes.len() > IPS_RECORD_HEADER - 2 && bytes.iter().all(|&byte| byte == bytes[0]) {
+            patch.extend_from_slice(&[0, 0]);
+            patch.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            patch.push(bytes[0]);
+        } else {
+            patch.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            patch.extend_from_slice(bytes);
+        }
+    };
+    for (start, end) in changes(source, target, IPS_RECORD_HEADER) {
+        for offset in (start..end).step_by(IPS_MAX_RECORD) {
+            record(offset, &target[offset..end.min(offset + IPS_MAX_RECORD)]);
+        }
+    }
+    patch.extend_from_slice(IPS_EOF);
+    if target.len() < source.len() {
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    patch
+}
+
+fn push_number(patch: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | byte);
+            return;
+        }
+        patch.push(byte);
+        value -= 1;
+    }
+}
+
+// A BPS patch turning `source` into `target`, made of reads from the
+// source where the bytes are unchanged and from the patch elsewhere.
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    push_number(&mut patch, source.len() as u64);
+    push_number(&mut patch, target.len() as u64);
+    // no metadata
+    push_number(&mut patch, 0);
+    let mut i = 0;
+    while i < target.len() {
+        let unchanged = source.get(i) == Some(&target[i]);
+        let start = i;
+        while i < target.len() && (source.get(i) == Some(&target[i])) == unchanged {
+            i += 1;
+        }
+        let action = if unchanged { 0 } else { 1 };
+        push_number(&mut patch, ((i - start - 1) as u64) << 2 | action);
+        if !unchanged {
+            patch.extend_from_slice(&target[start..i]);
+        }
+    }
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let crc = crc32(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A BPS patch with the given actions, and valid checksums unless told
+    // otherwise.
+    fn bps(source: &[u8], target: &[u8], actions: &[u8], target_crc: Option<u32>) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len() as u64);
+        push_number(&mut patch, target.len() as u64);
+        push_number(&mut patch, 3);
+        patch.extend_from_slice(b"xyz");
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&target_crc.unwrap_or_else(|| crc32(target)).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ips_records_are_applied() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let mut patch = b"PATCH".to_vec();
+        // two bytes at 1
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        // 0xCC three times at 7, past the end
+        patch.extend_from_slice(&[0, 0, 7, 0, 0, 0, 3, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(&source, &patch),
+            Ok(vec![1, 0xAA, 0xBB, 4, 5, 6, 0, 0xCC, 0xCC, 0xCC])
+        );
+        // truncated to 2 bytes
+        patch.extend_from_slice(&[0, 0, 2]);
+        assert_eq!(apply(&source, &patch), Ok(vec![1, 0xAA]));
+
+        assert_eq!(
+            apply(&source, b"PATCH\x00\x00\x01\x00\x02\xAA"),
+            Err(PatchError::Truncated)
+        );
+        assert_eq!(
+            apply(&source, b"PATCH\xFF\xFF\xFF\x00\x01\xAAEOF"),
+            Err(PatchError::TooLarge(0x100_0000))
+        );
+    }
+
+    #[test]
+    fn ips_patches_round_trip() {
+        let source: Vec<u8> = (0..=255).collect();
+        let mut grown = source.clone();
+        grown[3] = 0;
+        grown[10..40].iter_mut().for_each(|byte| *byte = 7);
+        grown.extend_from_slice(&[0; 20]);
+        let shrunk = &source[..100];
+        let large = vec![1; MAX_PATCHED_SIZE];
+        for target in &[&grown[..], shrunk, &source[..], &large[..], &[]] {
+            let patch = create_ips(&source, target);
+            assert_eq!(apply(&source, &patch).as_deref(), Ok(*target));
+        }
+        // a byte changed every other byte makes a single record
+        let mut striped = source.clone();
+        (0..8).for_each(|i| striped[i * 2] ^= 0xFF);
+        assert_eq!(create_ips(&source, &striped).len(), 5 + 5 + 15 + 3);
+    }
+
+    #[test]
+    fn bps_actions_are_applied() {
+        let source = b"abcdef";
+        let target = b"abXYZYZYdefab";
+        let mut actions = Vec::new();
+        // source read of "ab"
+        push_number(&mut actions, 1 << 2);
+        // target read of "XY"
+        push_number(&mut actions, 1 << 2 | 1);
+        actions.extend_from_slice(b"XY");
+        // "Z", then a target copy of 3 bytes from 3, overlapping itself
+        push_number(&mut actions, 1);
+        actions.push(b'Z');
+        push_number(&mut actions, 2 << 2 | 3);
+        push_number(&mut actions, 3 << 1);
+        // source copy of "def" from 3, then of "ab" from 0
+        push_number(&mut actions, 2 << 2 | 2);
+        push_number(&mut actions, 3 << 1);
+        push_number(&mut actions, 1 << 2 | 2);
+        push_number(&mut actions, 6 << 1 | 1);
+        let patch = bps(source, target, &actions, None);
+        assert_eq!(apply(source, &patch).as_deref(), Ok(&target[..]));
+    }
+
+    #[test]
+    fn bps_checksums_are_verified() {
+        let source = b"abcdef";
+        let target = b"abcdeg";
+        let patch = create_bps(source, target);
+        assert_eq!(apply(source, &patch).as_deref(), Ok(&target[..]));
+
+        let actual = crc32(b"abcdez");
+        assert_eq!(
+            apply(b"abcdez", &patch),
+            Err(PatchError::SourceChecksum {
+                expected: crc32(source),
+                actual
+            })
+        );
+
+        let mut corrupted = patch.clone();
+        corrupted[8] ^= 1;
+        assert!(matches!(
+            apply(source, &corrupted),
+            Err(PatchError::PatchChecksum { .. })
+        ));
+
+        let mut actions = Vec::new();
+        push_number(&mut actions, 5 << 2);
+        let patch = bps(source, target, &actions, Some(crc32(target)));
+        assert_eq!(
+            apply(source, &patch),
+            Err(PatchError::TargetChecksum {
+                expected: crc32(target),
+                actual: crc32(source)
+            })
+        );
+
+        // reads past the end of the source
+        let mut actions = Vec::new();
+        push_number(&mut actions, 1 << 2 | 2);
+        push_number(&mut actions, 5 << 1);
+        assert_eq!(
+            apply(source, &bps(source, b"fg", &actions, None)),
+            Err(PatchError::OutOfBounds)
+        );
+        assert_eq!(apply(source, b"BPS1"), Err(PatchError::Truncated));
+    }
+
+    #[test]
+    fn bps_patches_round_trip() {
+        let source: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut target = source.clone();
+        target[0] = 9;
+        target[500..520].iter_mut().for_each(|byte| *byte = 0);
+        target.extend_from_slice(b"more");
+        for target in &[&target[..], &source[..10], &[]] {
+            let patch = create_bps(&source, target);
+            assert_eq!(apply(&source, &patch).as_deref(), Ok(*target));
+        }
+    }
+}
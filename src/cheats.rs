@@ -0,0 +1,543 @@
+// Memory search and cheats, to reach the late levels of a game quickly.
+//
+// A search starts from a snapshot of the memory and the registers, and each
+// filter keeps the locations whose value compares as asked with the last
+// snapshot: the lives counter decreased after losing a life, the level
+// increased... Cheats then freeze a location to a value or patch a byte of
+// the program, and are applied before every frame.
+//
+// Cheats are saved as JSON, along with the CRC-32 of the ROM they are for:
+//
+//     {
+//       "version": 1,
+//       "rom": 3735928559,
+//       "cheats": [
+//         { "name": "lives", "freeze": 758, "value": 3, "enabled": true },
+//         { "name": "speed", "freeze_register": 3, "value": 1, "enabled": true },
+//         { "name": "no deaths", "patch": 718, "value": 18, "compare": 63, "enabled": false }
+//       ]
+//     }
+//
+// Addresses and register numbers are plain JSON numbers: `freeze` holds an
+// address, `freeze_register` the number of a V register. A patch only
+// writes its byte while the program still holds `compare`, when given, so
+// that it stays out of the way of code loaded over it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use wasm_bindgen::prelude::*;
+
+use crate::json::{self, FileError, Value};
+use crate::octo::parse_number;
+use crate::MEMORY_SIZE;
+
+const VERSION: u64 = 1;
+const FILE: &str = "cheat";
+
+// A byte of memory or a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Memory(u16),
+    Register(usize),
+}
+
+impl Location {
+    // Every location, the memory first.
+    pub fn all() -> impl Iterator<Item = Location> {
+        (0..MEMORY_SIZE as u16)
+            .map(Location::Memory)
+            .chain((0..16).map(Location::Register))
+    }
+
+    pub fn read(self, memory: &[u8], v: &[u8; 16]) -> u8 {
+        match self {
+            Location::Memory(addr) => memory[addr as usize % MEMORY_SIZE],
+            Location::Register(x) => v[x & 0xF],
+        }
+    }
+
+    pub fn write(self, memory: &mut [u8], v: &mut [u8; 16], value: u8) {
+        match self {
+            Location::Memory(addr) => memory[addr as usize % MEMORY_SIZE] = value,
+            Location::Register(x) => v[x & 0xF] = value,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Memory(addr) => write!(f, "0x{:03X}", addr),
+            Location::Register(x) => write!(f, "V{:X}", x),
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    // `V0` to `VF`, or an address in decimal, hexadecimal or binary.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid location {}", text);
+        if let Some(digit) = text.strip_prefix('V').or_else(|| text.strip_prefix('v')) {
+            return match usize::from_str_radix(digit, 16) {
+                Ok(x) if digit.len() == 1 => Ok(Location::Register(x)),
+                _ => Err(invalid()),
+            };
+        }
+        match parse_number(text) {
+            Some(addr) if (0..MEMORY_SIZE as i64).contains(&addr) => {
+                Ok(Location::Memory(addr as u16))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// How a search compares the current values with the last snapshot.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchFilter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    // equal to a given value, whatever the snapshot
+    Value,
+}
+
+// The locations still matching the filters applied so far, with their
+// values at the last one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RamSearch {
+    candidates: Vec<(Location, u8)>,
+}
+
+impl RamSearch {
+    pub fn new(memory: &[u8], v: &[u8; 16]) -> Self {
+        RamSearch {
+            candidates: Location::all()
+                .map(|location| (location, location.read(memory, v)))
+                .collect(),
+        }
+    }
+
+    // Keep the matching locations and take a new snapshot of them,
+    // returning how many are left. `value` is only used by
+    // `SearchFilter::Value`.
+    pub fn filter(
+        &mut self,
+        memory: &[u8],
+        v: &[u8; 16],
+        filter: SearchFilter,
+        value: u8,
+    ) -> usize {
+        self.candidates.retain(|(location, previous)| {
+            let current = location.read(memory, v);
+            match filter {
+                SearchFilter::Equal => current == *previous,
+                SearchFilter::Changed => current != *previous,
+                SearchFilter::Increased => current > *previous,
+                SearchFilter::Decreased => current < *previous,
+                SearchFilter::Value => current == value,
+            }
+        });
+        for (location, previous) in self.candidates.iter_mut() {
+            *previous = location.read(memory, v);
+        }
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[(Location, u8)] {
+        &self.candidates
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cheat {
+    // keep a location at a value
+    Freeze {
+        location: Location,
+        value: u8,
+    },
+    // replace a byte of the program, only while it holds `compare` if given
+    Patch {
+        addr: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+}
+
+impl Cheat {
+    pub fn apply(&self, memory: &mut [u8], v: &mut [u8; 16]) {
+        match *self {
+            Cheat::Freeze { location, value } => location.write(memory, v, value),
+            Cheat::Patch {
+                addr,
+                value,
+                compare,
+            } => {
+                let location = Location::Memory(addr);
+                if compare.is_none() || compare == Some(location.read(memory, v)) {
+                    location.write(memory, v, value);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cheat::Freeze { location, value } => write!(f, "freeze {} = {}", location, value),
+            Cheat::Patch {
+                addr,
+                value,
+                compare: Some(compare),
+            } => write!(
+                f,
+                "patch 0x{:03X} = 0x{:02X} if 0x{:02X}",
+                addr, value, compare
+            ),
+            Cheat::Patch { addr, value, .. } => write!(f, "patch 0x{:03X} = 0x{:02X}", addr, value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheatEntry {
+    pub name: String,
+    pub cheat: Cheat,
+    pub enabled: bool,
+}
+
+fn invalid(what: &'static str) -> FileError {
+    FileError::invalid(FILE, what)
+}
+
+fn byte(value: Option<&Value>, what: &'static str) -> Result<u8, FileError> {
+    value
+        .and_then(Value::as_u64)
+        .filter(|&value| value <= 0xFF)
+        .map(|value| value as u8)
+        .ok_or_else(|| invalid(what))
+}
+
+// The cheats of a ROM, identified by its CRC-32.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheatList {
+    rom: u32,
+    entries: Vec<CheatEntry>,
+}
+
+impl CheatList {
+    pub fn new(rom: u32) -> Self {
+        CheatList {
+            rom,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Self, FileError> {
+        let root = json::parse_file(json, FILE, VERSION)?;
+        let rom = root
+            .get("rom")
+            .and_then(Value::as_u64)
+            .filter(|&rom| rom <= u32::MAX as u64)
+            .ok_or_else(|| invalid("rom"))?;
+
+        let mut list = CheatList::new(rom as u32);
+        let cheats = root
+            .get("cheats")
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid("cheats"))?;
+        for cheat in cheats {
+            let name = cheat
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid("cheat name"))?;
+            let value = byte(cheat.get("value"), "cheat value")?;
+            let parsed = if let Some(addr) = cheat.get("freeze") {
+                let addr = addr
+                    .as_u64()
+                    .filter(|&addr| addr < MEMORY_SIZE as u64)
+                    .ok_or_else(|| invalid("frozen address"))?;
+                Cheat::Freeze {
+                    location: Location::Memory(addr as u16),
+                    value,
+                }
+            } else if let Some(x) = cheat.get("freeze_register") {
+                let x = x
+                    .as_u64()
+                    .filter(|&x| x < 16)
+                    .ok_or_else(|| invalid("frozen register"))?;
+                Cheat::Freeze {
+                    location: Location::Register(x as usize),
+                    value,
+                }
+            } else {
+                let addr = cheat
+                    .get("patch")
+                    .and_then(Value::as_u64)
+                    .filter(|&addr| addr < MEMORY_SIZE as u64)
+                    .ok_or_else(|| invalid("patch address"))?;
+                let compare = match cheat.get("compare") {
+                    Some(_) => Some(byte(cheat.get("compare"), "patch compare")?),
+                    None => None,
+                };
+                Cheat::Patch {
+                    addr: addr as u16,
+                    value,
+                    compare,
+                }
+            };
+            let enabled = match cheat.get("enabled") {
+                Some(enabled) => enabled.as_bool().ok_or_else(|| invalid("cheat enabled"))?,
+                None => true,
+            };
+            let index = list.add(name, parsed);
+            list.set_enabled(index, enabled);
+        }
+        Ok(list)
+    }
+
+    pub fn to_json(&self) -> String {
+        let cheats: Vec<Value> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let cheat = Value::object().with("name", entry.name.as_str());
+                let cheat = match entry.cheat {
+                    Cheat::Freeze {
+                        location: Location::Memory(addr),
+                        value,
+                    } => cheat.with("freeze", addr).with("value", value as u16),
+                    Cheat::Freeze {
+                        location: Location::Register(x),
+                        value,
+                    } => cheat.with("freeze_register", x).with("value", value as u16),
+                    Cheat::Patch {
+                        addr,
+                        value,
+                        compare,
+                    } => {
+                        let cheat = cheat.with("patch", addr).with("value", value as u16);
+                        match compare {
+                            Some(compare) => cheat.with("compare", compare as u16),
+                            None => cheat,
+                        }
+                    }
+                };
+                cheat.with("enabled", entry.enabled)
+            })
+            .collect();
+        Value::object()
+            .with("version", VERSION)
+            .with("rom", self.rom)
+            .with("cheats", cheats)
+            .to_string()
+    }
+
+    // CRC-32 of the ROM the cheats are for.
+    pub fn rom(&self) -> u32 {
+        self.rom
+    }
+
+    // Add an enabled cheat, returning its index.
+    pub fn add(&mut self, name: &str, cheat: Cheat) -> usize {
+        self.entries.push(CheatEntry {
+            name: name.to_string(),
+            cheat,
+            enabled: true,
+        });
+        self.entries.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<CheatEntry> {
+        if index < self.entries.len() {
+            Some(self.entries.remove(index))
+        } else {
+            None
+        }
+    }
+
+    // Enable or disable a cheat, returning false if there is none at `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.entries.get_mut(index) {
+            Some(entry) => {
+                entry.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn entries(&self) -> &[CheatEntry] {
+        &self.entries
+    }
+
+    pub fn apply(&self, memory: &mut [u8], v: &mut [u8; 16]) {
+        for entry in self.entries.iter().filter(|entry| entry.enabled) {
+            entry.cheat.apply(memory, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_narrows_down_the_candidates() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut v = [0; 16];
+        memory[0x2F6] = 3;
+        memory[0x300] = 3;
+        v[5] = 3;
+        let mut search = RamSearch::new(&memory, &v);
+        assert_eq!(search.len(), MEMORY_SIZE + 16);
+
+        // a life lost
+        memory[0x2F6] = 2;
+        v[5] = 2;
+        assert_eq!(search.filter(&memory, &v, SearchFilter::Decreased, 0), 2);
+        assert_eq!(search.filter(&memory, &v, SearchFilter::Equal, 0), 2);
+        memory[0x2F6] = 1;
+        assert_eq!(search.filter(&memory, &v, SearchFilter::Changed, 0), 1);
+        assert_eq!(search.candidates(), &[(Location::Memory(0x2F6), 1)]);
+        assert_eq!(search.filter(&memory, &v, SearchFilter::Value, 7), 0);
+        assert!(search.is_empty());
+    }
+
+    #[test]
+    fn cheats_freeze_and_patch() {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let mut v = [0; 16];
+        memory[0x2CE] = 0x3F;
+        memory[0x2D0] = 0x40;
+        let mut list = CheatList::new(0);
+        list.add(
+            "lives",
+            Cheat::Freeze {
+                location: "V3".parse().unwrap(),
+                value: 9,
+            },
+        );
+        list.add(
+            "no deaths",
+            Cheat::Patch {
+                addr: 0x2CE,
+                value: 0x12,
+                compare: Some(0x3F),
+            },
+        );
+        list.add(
+            "skipped",
+            Cheat::Patch {
+                addr: 0x2D0,
+                value: 0x12,
+                compare: Some(0x3F),
+            },
+        );
+        let disabled = list.add(
+            "disabled",
+            Cheat::Freeze {
+                location: Location::Memory(0x300),
+                value: 1,
+            },
+        );
+        assert!(list.set_enabled(disabled, false));
+        list.apply(&mut memory, &mut v);
+        assert_eq!(v[3], 9);
+        assert_eq!(
+            (memory[0x2CE], memory[0x2D0], memory[0x300]),
+            (0x12, 0x40, 0)
+        );
+    }
+
+    #[test]
+    fn cheat_files_round_trip() {
+        let mut list = CheatList::new(0xDEAD_BEEF);
+        list.add(
+            "lives",
+            Cheat::Freeze {
+                location: Location::Memory(0x2F6),
+                value: 3,
+            },
+        );
+        let index = list.add(
+            "no deaths",
+            Cheat::Patch {
+                addr: 718,
+                value: 18,
+                compare: None,
+            },
+        );
+        list.set_enabled(index, false);
+        list.add(
+            "speed",
+            Cheat::Freeze {
+                location: Location::Register(3),
+                value: 1,
+            },
+        );
+        let json = list.to_json();
+        assert!(json.contains(r#""freeze":758,"#));
+        assert!(json.contains(r#""freeze_register":3,"#));
+        assert_eq!(CheatList::parse(&json), Ok(list));
+
+        let json = r#"{"version":1,"rom":1,"cheats":[{"name":"x","freeze_register":3,"value":3}]}"#;
+        let list = CheatList::parse(json).unwrap();
+        assert_eq!(list.rom(), 1);
+        assert!(list.entries()[0].enabled);
+        assert_eq!(list.entries()[0].cheat.to_string(), "freeze V3 = 3");
+    }
+
+    #[test]
+    fn invalid_cheat_files_are_rejected() {
+        let parse = |json: &str| CheatList::parse(json).map_err(|e| e.to_string());
+        assert_eq!(
+            parse(r#"{"version":2,"rom":1,"cheats":[]}"#),
+            Err("unsupported cheat file version 2".to_string())
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"rom":1,"cheats":[{"name":"x","freeze":"0x2F6","value":3}]}"#),
+            Err("invalid frozen address in cheat file".to_string())
+        );
+        assert_eq!(
+            parse(
+                r#"{"version":1,"rom":1,"cheats":[{"name":"x","freeze_register":16,"value":3}]}"#
+            ),
+            Err("invalid frozen register in cheat file".to_string())
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"rom":1,"cheats":[{"name":"x","patch":4096,"value":3}]}"#),
+            Err("invalid patch address in cheat file".to_string())
+        );
+        assert_eq!(
+            parse(r#"{"version":1,"rom":1,"cheats":[{"name":"x","patch":512,"value":256}]}"#),
+            Err("invalid cheat value in cheat file".to_string())
+        );
+    }
+
+    #[test]
+    fn locations_are_parsed() {
+        assert_eq!("vA".parse(), Ok(Location::Register(10)));
+        assert_eq!("0x2F6".parse(), Ok(Location::Memory(0x2F6)));
+        assert_eq!("512".parse(), Ok(Location::Memory(0x200)));
+        assert_eq!(
+            "0x1000".parse::<Location>(),
+            Err("invalid location 0x1000".to_string())
+        );
+        assert!("V10".parse::<Location>().is_err());
+    }
+}
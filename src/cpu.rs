@@ -1,30 +1,99 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
 use wasm_bindgen::prelude::*;
 
+pub mod coverage;
+pub mod debugger;
+pub mod profiler;
+pub mod quirks;
+pub mod savestate;
+pub mod smc;
+pub mod trace;
+
+pub use self::coverage::Coverage;
+pub use self::debugger::Register;
+pub use self::profiler::Profiler;
+pub use self::quirks::Quirks;
+pub use self::savestate::StateError;
+pub use self::smc::{SelfModifyingWrite, SmcAction, SmcDetector};
+pub use self::trace::{Change, TraceEntry, TraceFormat, Tracer};
+
+use super::audio::Audio;
 use super::cartridge::Cartridge;
-use super::display::Display;
+use super::cheats::{Cheat, CheatList, Location, RamSearch, SearchFilter};
+use super::checksum::crc32;
+use super::display::{DirtyRect, Display, Palette, Persistence};
 use super::font::FONT_SET;
 use super::keypad::Keypad;
+use super::octo::{self, Target};
+use super::opcode::Instruction;
 use super::rand::ComplementaryMultiplyWithCarryGen;
+use super::symbols::SymbolMap;
 
 use super::MEMORY_SIZE;
 
+// addresses wrap around the 4 KiB of memory
+const ADDRESS_MASK: u16 = (MEMORY_SIZE - 1) as u16;
+// the largest program fitting in memory after 0x200
+pub const MAX_PROGRAM_SIZE: usize = MEMORY_SIZE - 0x200;
+
+// Error that halts the cpu until it is reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    // CALL with the 16 levels of the stack already in use
+    StackOverflow { pc: u16 },
+    // RET outside of a subroutine
+    StackUnderflow { pc: u16 },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:04X} at {:03X}", opcode, pc)
+            }
+            Fault::StackOverflow { pc } => write!(f, "stack overflow at {:03X}", pc),
+            Fault::StackUnderflow { pc } => write!(f, "stack underflow at {:03X}", pc),
+        }
+    }
+}
+
+// Why the cpu paused. It stays paused until `resume` is called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakReason {
+    // the program counter reached a breakpoint
+    Breakpoint(u16),
+    SelfModifyingWrite(SelfModifyingWrite),
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakReason::Breakpoint(addr) => write!(f, "breakpoint at 0x{:03X}", addr),
+            BreakReason::SelfModifyingWrite(write) => write!(f, "{}", write),
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub struct ExecutionResult {
-    display_state: Vec<u8>,
+    frame_changed: bool,
     should_beep: bool,
 }
 
 #[wasm_bindgen]
 impl ExecutionResult {
-    pub fn new(display_state: Vec<u8>, should_beep: bool) -> ExecutionResult {
+    pub fn new(frame_changed: bool, should_beep: bool) -> ExecutionResult {
         ExecutionResult {
-            display_state,
+            frame_changed,
             should_beep,
         }
     }
 
-    pub fn get_display_state(&self) -> Vec<u8> {
-        self.display_state.clone()
+    pub fn get_frame_changed(&self) -> bool {
+        self.frame_changed
     }
 
     pub fn get_should_beep(&self) -> bool {
@@ -32,6 +101,44 @@ impl ExecutionResult {
     }
 }
 
+// Full state of the machine, used to compare the cpu with other
+// implementations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+    pub v: [u8; 16],
+    // the return addresses on the stack, the deepest call last
+    pub stack: Vec<u16>,
+    pub memory: Vec<u8>,
+    // one byte per pixel, 1 when the pixel is on
+    pub display: Vec<u8>,
+}
+
+impl CpuState {
+    // The registers on a single line, in the format of the tracer.
+    pub fn summary(&self) -> String {
+        let registers: Vec<String> = self
+            .v
+            .iter()
+            .enumerate()
+            .map(|(x, value)| format!("V{:X}:{:02X}", x, value))
+            .collect();
+        format!(
+            "PC:{:04X} {} I:{:04X} SP:{:02X} DT:{:02X} ST:{:02X}",
+            self.pc,
+            registers.join(" "),
+            self.i,
+            self.sp,
+            self.dt,
+            self.st
+        )
+    }
+}
+
 #[wasm_bindgen]
 pub struct Cpu {
     // index register
@@ -53,10 +160,41 @@ pub struct Cpu {
     st: u8,
     // random number generator using CMWC algo
     rand: ComplementaryMultiplyWithCarryGen,
+    // seed of the generator, again after a reset
+    seed: u32,
     // display
     display: Display,
     // keypad
     keypad: Keypad,
+    // sound synthesizer, enabled by the frontends that play sound
+    audio: Option<Audio>,
+    // state of the sound timer after every instruction of the current frame
+    sound_gates: Vec<bool>,
+    // instruction trace, when enabled
+    tracer: Option<Tracer>,
+    // set when the cpu halts on an error
+    fault: Option<Fault>,
+    // execution statistics, when enabled
+    profiler: Option<Profiler>,
+    // code and data coverage, when enabled
+    coverage: Option<Coverage>,
+    // detection of the writes into code, when enabled
+    smc: Option<SmcDetector>,
+    // set while the cpu is paused
+    break_reason: Option<BreakReason>,
+    breakpoints: BTreeSet<u16>,
+    // set by `resume` so that the instruction under a breakpoint runs
+    skip_breakpoint: bool,
+    // labels and source lines of the program, when loaded
+    symbols: Option<SymbolMap>,
+    // CRC-32 of the loaded program
+    rom_crc: u32,
+    // cheats of the loaded program, applied before every frame
+    cheats: CheatList,
+    // memory search, once started
+    search: Option<RamSearch>,
+    // interpreter behaviours of the loaded program
+    quirks: Quirks,
 }
 
 #[wasm_bindgen]
@@ -76,15 +214,39 @@ impl Cpu {
             dt: 0,
             st: 0,
             rand: ComplementaryMultiplyWithCarryGen::new(1),
+            seed: 1,
             display: Display::new(),
             keypad: Keypad::new(),
+            audio: None,
+            sound_gates: Vec::new(),
+            tracer: None,
+            fault: None,
+            profiler: None,
+            coverage: None,
+            smc: None,
+            break_reason: None,
+            breakpoints: BTreeSet::new(),
+            skip_breakpoint: false,
+            symbols: None,
+            rom_crc: crc32(&[]),
+            cheats: CheatList::new(crc32(&[])),
+            search: None,
+            quirks: Quirks::default(),
         }
     }
 
+    // Load the program at 0x200. Programs are truncated to the memory size.
     pub fn load_cartridge(&mut self, program: Cartridge) {
         let program_memory = program.get_memory();
+        let len = program_memory.len().min(MAX_PROGRAM_SIZE);
         // init the memory with the program starting at the addr 0x200
-        self.memory[0x200..0x200 + program_memory.len()].copy_from_slice(&program_memory);
+        self.memory[0x200..0x200 + len].copy_from_slice(&program_memory[..len]);
+        // the cheats and the search only make sense for the same program
+        self.rom_crc = crc32(&program_memory[..len]);
+        if self.cheats.rom() != self.rom_crc {
+            self.cheats = CheatList::new(self.rom_crc);
+            self.search = None;
+        }
     }
 
     pub fn reset(&mut self) {
@@ -97,8 +259,391 @@ impl Cpu {
         self.sp = 0;
         self.dt = 0;
         self.st = 0;
-        self.rand = ComplementaryMultiplyWithCarryGen::new(1);
-        self.display.cls();
+        self.rand = ComplementaryMultiplyWithCarryGen::new(self.seed);
+        self.display.set_hires(false);
+        self.fault = None;
+        self.break_reason = None;
+    }
+
+    // Restart the random number generator from another seed, which resets
+    // keep. Runs with the same seed and inputs are identical.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.rand = ComplementaryMultiplyWithCarryGen::new(seed);
+    }
+
+    // Description of the error that halted the cpu, if any.
+    pub fn fault_message(&self) -> Option<String> {
+        self.fault.map(|fault| fault.to_string())
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.display.set_palette(palette);
+    }
+
+    // Enable a persistence post-processing stage to reduce flickering.
+    // It only affects the output of `render_rgba`, and starts from the
+    // pixels shown.
+    pub fn set_persistence(&mut self, persistence: Persistence) {
+        self.display.set_persistence(Some(persistence));
+    }
+
+    pub fn disable_persistence(&mut self) {
+        self.display.set_persistence(None);
+    }
+
+    // Render the display with the active palette and return a pointer to the
+    // RGBA framebuffer in the wasm memory: JS can wrap it in an `ImageData`
+    // without copying. The pointer is valid until the next call into the cpu.
+    pub fn render_rgba(&mut self) -> *const u8 {
+        self.display.render_rgba().as_ptr()
+    }
+
+    pub fn rgba_len(&self) -> usize {
+        self.display.vram().len() * 4
+    }
+
+    // Encode the display as PNG bytes with the active palette.
+    pub fn screenshot_png(&self, scale: usize) -> Result<Vec<u8>, String> {
+        self.display.to_png(scale).map_err(|e| e.to_string())
+    }
+
+    // Start recording the display to an animated GIF, one frame per call
+    // to `run_frame`, every pixel scaled to a `scale` x `scale` square.
+    pub fn start_recording(&mut self, scale: usize) {
+        self.display.start_recording(scale);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.display.is_recording()
+    }
+
+    // Stop the recording and return the GIF bytes, if a recording was active.
+    pub fn stop_recording(&mut self) -> Option<Vec<u8>> {
+        self.display.stop_recording()
+    }
+
+    // Pointer to the vram in the wasm memory (one byte per pixel, 1 when on),
+    // meant to be wrapped by a `Uint8Array` view of `display_len()` bytes.
+    // The buffer is reallocated when the resolution changes.
+    pub fn display_ptr(&self) -> *const u8 {
+        self.display.vram().as_ptr()
+    }
+
+    pub fn display_len(&self) -> usize {
+        self.display.vram().len()
+    }
+
+    // Size of the display in pixels: 64x32, or 128x64 in the SUPER-CHIP high
+    // resolution.
+    pub fn display_width(&self) -> usize {
+        self.display.width()
+    }
+
+    pub fn display_height(&self) -> usize {
+        self.display.height()
+    }
+
+    // Whether the display was drawn since the beginning of the current frame
+    // or cycle, or the persistence stage is still fading pixels out.
+    pub fn frame_changed(&self) -> bool {
+        self.display.is_dirty() || self.display.is_fading()
+    }
+
+    // Area of the display drawn since the beginning of the current frame or
+    // cycle.
+    pub fn dirty_rect(&self) -> Option<DirtyRect> {
+        self.display.dirty_rect()
+    }
+
+    // Generate the sound of every frame run by `run_frame` at the given
+    // sample rate.
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        self.audio = Some(Audio::new(sample_rate));
+    }
+
+    pub fn disable_audio(&mut self) {
+        self.audio = None;
+    }
+
+    pub fn set_audio_pitch(&mut self, pitch: f32) {
+        if let Some(audio) = &mut self.audio {
+            audio.set_pitch(pitch);
+        }
+    }
+
+    pub fn set_audio_volume(&mut self, volume: f32) {
+        if let Some(audio) = &mut self.audio {
+            audio.set_volume(volume);
+        }
+    }
+
+    // Pointer to the samples of the last frame in the wasm memory, meant to
+    // be wrapped by a `Float32Array` view of `audio_len()` samples.
+    pub fn audio_ptr(&self) -> *const f32 {
+        self.audio_samples().as_ptr()
+    }
+
+    pub fn audio_len(&self) -> usize {
+        self.audio_samples().len()
+    }
+
+    // Record the last `capacity` executed instructions.
+    pub fn enable_trace(&mut self, capacity: usize, format: TraceFormat) {
+        self.tracer = Some(Tracer::new(capacity, format));
+    }
+
+    pub fn disable_trace(&mut self) {
+        self.tracer = None;
+    }
+
+    // The recorded instructions, one line each, oldest first.
+    pub fn trace_log(&self) -> String {
+        match &self.tracer {
+            Some(tracer) => tracer.log(self.symbols.as_ref()),
+            None => String::new(),
+        }
+    }
+
+    // Start collecting execution statistics, discarding the previous ones.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn disable_profiler(&mut self) {
+        self.profiler = None;
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiler.is_some()
+    }
+
+    pub fn profile_json(&self) -> Option<String> {
+        self.profiler.as_ref().map(|profiler| profiler.to_json())
+    }
+
+    // Human readable profile, listing the `limit` most executed addresses.
+    pub fn profile_report(&self, limit: usize) -> Option<String> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.report(limit))
+    }
+
+    // Executions of every address of the memory, scaled to 0-255.
+    pub fn profile_heat_map(&self) -> Option<Vec<u8>> {
+        self.profiler.as_ref().map(|profiler| profiler.heat_map())
+    }
+
+    // Start tracking which bytes are executed, read and written.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn disable_coverage(&mut self) {
+        self.coverage = None;
+    }
+
+    // Disassembly of the program marking the code that never ran.
+    pub fn coverage_listing(&self) -> Option<String> {
+        self.coverage
+            .as_ref()
+            .map(|coverage| coverage.listing(&self.memory, self.symbols.as_ref()))
+    }
+
+    // Watch for instructions writing into code: bytes already executed, or
+    // decoded as code by the static analysis of the loaded program.
+    pub fn detect_self_modifying_code(&mut self, action: SmcAction) {
+        self.smc = Some(SmcDetector::new(action, &self.memory));
+    }
+
+    pub fn disable_self_modifying_code_detection(&mut self) {
+        self.smc = None;
+    }
+
+    // Number of bytes written into code since the detection was enabled.
+    pub fn self_modifying_writes(&self) -> u32 {
+        self.smc.as_ref().map_or(0, |detector| detector.count())
+    }
+
+    // The logged writes into code, one per line, oldest first. The log is
+    // emptied.
+    pub fn self_modifying_log(&mut self) -> String {
+        self.take_self_modifying_writes()
+            .iter()
+            .map(|write| format!("{}\n", write))
+            .collect()
+    }
+
+    // Why the cpu paused, if it did.
+    pub fn break_message(&self) -> Option<String> {
+        match (self.break_reason, &self.symbols) {
+            (Some(BreakReason::Breakpoint(addr)), Some(symbols)) => {
+                let mut message = format!("breakpoint at {}", symbols.format_address(addr));
+                if let Some(line) = symbols.source_line(addr) {
+                    message.push_str(&format!(" ({})", line));
+                }
+                Some(message)
+            }
+            (reason, _) => reason.map(|reason| reason.to_string()),
+        }
+    }
+
+    // Load a symbol file, as described in the `symbols` module, for the
+    // traces, listings and breakpoints to use labels.
+    pub fn load_symbols(&mut self, json: &str) -> Result<(), String> {
+        self.symbols = Some(SymbolMap::parse(json).map_err(|e| e.to_string())?);
+        Ok(())
+    }
+
+    // Compile an Octo program for `target` and load it, with its symbols and
+    // its `:breakpoint`s. `file` names the source in errors and symbols.
+    pub fn load_octo(&mut self, file: &str, source: &str, target: Target) -> Result<(), String> {
+        let program = octo::compile(file, source, target).map_err(|e| e.to_string())?;
+        self.load_cartridge(program.cartridge());
+        for &addr in program.breakpoints.iter() {
+            self.add_breakpoint(addr);
+        }
+        self.symbols = Some(program.symbols);
+        Ok(())
+    }
+
+    pub fn clear_symbols(&mut self) {
+        self.symbols = None;
+    }
+
+    // `label+0x4` for an address, or `0x2A6` without symbols.
+    pub fn symbolize(&self, addr: u16) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.format_address(addr & ADDRESS_MASK),
+            None => format!("0x{:03X}", addr & ADDRESS_MASK),
+        }
+    }
+
+    // Add a breakpoint at a label, `label+offset` or address.
+    pub fn add_breakpoint_at(&mut self, location: &str) -> Result<(), String> {
+        let addr = self.resolve(location)?;
+        self.add_breakpoint(addr);
+        Ok(())
+    }
+
+    pub fn remove_breakpoint_at(&mut self, location: &str) -> Result<(), String> {
+        let addr = self.resolve(location)?;
+        self.remove_breakpoint(addr);
+        Ok(())
+    }
+
+    pub fn resume(&mut self) {
+        self.break_reason = None;
+        self.skip_breakpoint = true;
+    }
+
+    // Pause the cpu before it executes the instruction at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr & ADDRESS_MASK);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&(addr & ADDRESS_MASK));
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // CRC-32 of the loaded program, the key of its cheats.
+    pub fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    // Snapshot the memory and the registers to start a new search.
+    pub fn search_start(&mut self) {
+        self.search = Some(RamSearch::new(&self.memory, &self.v));
+    }
+
+    // Filter the search against the current values, returning how many
+    // locations are left. `value` is for `SearchFilter::Value`.
+    pub fn search_filter(&mut self, filter: SearchFilter, value: u8) -> Result<usize, String> {
+        let search = self.search.as_mut().ok_or("no search started")?;
+        Ok(search.filter(&self.memory, &self.v, filter, value))
+    }
+
+    pub fn search_count(&self) -> usize {
+        self.search.as_ref().map_or(0, |search| search.len())
+    }
+
+    // The first `limit` locations left, one `0x2F6: 3` per line.
+    pub fn search_results(&self, limit: usize) -> String {
+        let candidates = self.search.iter().flat_map(|search| search.candidates());
+        candidates
+            .take(limit)
+            .map(|(location, value)| format!("{}: {}\n", location, value))
+            .collect()
+    }
+
+    // Keep a register, like `V3`, or a byte at a label or address to a
+    // value. Returns the index of the cheat.
+    pub fn add_freeze(&mut self, name: &str, location: &str, value: u8) -> Result<usize, String> {
+        let location = match location.parse() {
+            Ok(location) => location,
+            Err(_) => Location::Memory(self.resolve(location)?),
+        };
+        Ok(self.cheats.add(name, Cheat::Freeze { location, value }))
+    }
+
+    // Replace a byte of the program at a label or address, while it still
+    // holds its current value.
+    pub fn add_patch(&mut self, name: &str, location: &str, value: u8) -> Result<usize, String> {
+        let addr = self.resolve(location)?;
+        let cheat = Cheat::Patch {
+            addr,
+            value,
+            compare: Some(self.read_memory(addr)),
+        };
+        Ok(self.cheats.add(name, cheat))
+    }
+
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> Result<(), String> {
+        if self.cheats.set_enabled(index, enabled) {
+            Ok(())
+        } else {
+            Err(format!("no cheat {}", index))
+        }
+    }
+
+    pub fn remove_cheat(&mut self, index: usize) -> Result<(), String> {
+        match self.cheats.remove(index) {
+            Some(_) => Ok(()),
+            None => Err(format!("no cheat {}", index)),
+        }
+    }
+
+    // The cheats of the loaded program, as described in the `cheats` module.
+    pub fn cheats_json(&self) -> String {
+        self.cheats.to_json()
+    }
+
+    // Load cheats saved for the loaded program.
+    pub fn load_cheats(&mut self, json: &str) -> Result<(), String> {
+        let cheats = CheatList::parse(json).map_err(|e| e.to_string())?;
+        if cheats.rom() != self.rom_crc {
+            return Err(format!(
+                "the cheats are for another ROM (CRC-32 {:08X}, loaded {:08X})",
+                cheats.rom(),
+                self.rom_crc
+            ));
+        }
+        self.cheats = cheats;
+        Ok(())
+    }
+
+    // The memory lives in the wasm memory: frontends wrap it in a typed
+    // array without copying.
+    pub fn memory_ptr(&self) -> *const u8 {
+        self.memory.as_ptr()
+    }
+
+    pub fn memory_len(&self) -> usize {
+        self.memory.len()
     }
 
     pub fn keypad_down(&mut self, key: &str) {
@@ -109,12 +654,286 @@ impl Cpu {
         self.keypad.key_up(key)
     }
 
+    // Hold the keys whose bit is set, key 0 being the lowest bit, for bots,
+    // movies and other drivers without a keyboard.
+    pub fn set_keypad_state(&mut self, state: u16) {
+        self.keypad.set_state(state);
+    }
+
+    pub fn keypad_state(&self) -> u16 {
+        self.keypad.state()
+    }
+
+    // Press the key `idx`, from 0x0 to 0xF, with the indexes of the keypad
+    // module: "1" is 0x0 and "v" is 0xF.
+    pub fn keypad_press(&mut self, idx: usize) {
+        self.keypad.press(idx)
+    }
+
+    pub fn keypad_release(&mut self, idx: usize) {
+        self.keypad.release(idx)
+    }
+
+    // Execute a single instruction. Like `run_frame`, the dirty state of the
+    // display is reset first, so `frame_changed` tells whether this
+    // instruction drew anything. Frontends stepping by cycles call
+    // `end_frame` once per frame.
     pub fn execute_cycle(&mut self) -> ExecutionResult {
+        self.display.clear_dirty();
+        self.step();
+        ExecutionResult::new(self.display.is_dirty(), self.st > 0)
+    }
+
+    // Close a frame run with `execute_cycle`: the persistence stage fades,
+    // the GIF recorder captures the display and the profiler moves to the
+    // next frame. `run_frame` does it on its own.
+    pub fn end_frame(&mut self) {
+        self.display.end_frame();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+    }
+
+    // Execute a whole frame worth of instructions. The dirty state of the
+    // display is reset first, so `frame_changed` tells whether this frame
+    // needs to be redrawn.
+    pub fn run_frame(&mut self, cycles: u32) -> ExecutionResult {
+        self.display.clear_dirty();
+        self.sound_gates.clear();
+        self.cheats.apply(&mut self.memory, &mut self.v);
+        for _ in 0..cycles {
+            self.step();
+            if self.audio.is_some() {
+                self.sound_gates.push(self.st > 0);
+            }
+        }
+        self.end_frame();
+        if let Some(audio) = &mut self.audio {
+            audio.render_frame(&self.sound_gates);
+        }
+        ExecutionResult::new(self.frame_changed(), self.st > 0)
+    }
+}
+
+impl Cpu {
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Select the interpreter behaviours, kept across resets.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // The pixels shown, one byte per pixel in rows, 1 when the pixel is on.
+    pub fn vram(&self) -> &[u8] {
+        self.display.vram()
+    }
+
+    // PCM samples of the last frame, empty when the audio is disabled.
+    pub fn audio_samples(&self) -> &[f32] {
+        match &self.audio {
+            Some(audio) => audio.samples(),
+            None => &[],
+        }
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            i: self.i,
+            sp: self.sp,
+            dt: self.dt,
+            st: self.st,
+            v: self.v,
+            stack: self.stack[..self.sp as usize].to_vec(),
+            memory: self.memory.to_vec(),
+            display: self.display.get_vram_copy(),
+        }
+    }
+
+    // The execution statistics, when the profiler is enabled.
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn break_reason(&self) -> Option<BreakReason> {
+        self.break_reason
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolMap> {
+        self.symbols.as_ref()
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = Some(symbols);
+    }
+
+    // The value of a byte of memory or of a register.
+    pub fn read_location(&self, location: Location) -> u8 {
+        location.read(&self.memory, &self.v)
+    }
+
+    pub fn cheats(&self) -> &CheatList {
+        &self.cheats
+    }
+
+    // Replace the cheats, whatever the program they were made for.
+    pub fn set_cheats(&mut self, cheats: CheatList) {
+        self.cheats = cheats;
+    }
+
+    pub fn search(&self) -> Option<&RamSearch> {
+        self.search.as_ref()
+    }
+
+    // The address of a location typed by a user.
+    pub fn resolve(&self, location: &str) -> Result<u16, String> {
+        let resolved = match &self.symbols {
+            Some(symbols) => symbols.resolve(location),
+            None => SymbolMap::new().resolve(location),
+        };
+        resolved.ok_or_else(|| format!("unknown location {}", location))
+    }
+
+    // Remove and return the logged writes into code, oldest first.
+    pub fn take_self_modifying_writes(&mut self) -> Vec<SelfModifyingWrite> {
+        match &mut self.smc {
+            Some(detector) => detector.drain(),
+            None => Vec::new(),
+        }
+    }
+
+    // Remove and return the recorded instructions, oldest first.
+    pub fn take_trace(&mut self) -> Vec<TraceEntry> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.drain(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn trace_format(&self) -> Option<TraceFormat> {
+        self.tracer.as_ref().map(|tracer| tracer.format())
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+    }
+
+    fn increment_i_after_load_store(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+    }
+
+    fn read_memory(&self, addr: u16) -> u8 {
+        self.memory[(addr & ADDRESS_MASK) as usize]
+    }
+
+    fn write_memory(&mut self, addr: u16, value: u8) {
+        self.memory[(addr & ADDRESS_MASK) as usize] = value;
+    }
+
+    fn step(&mut self) {
+        if self.fault.is_some() || self.break_reason.is_some() {
+            return;
+        }
+        if !self.skip_breakpoint && self.breakpoints.contains(&self.pc) {
+            self.break_reason = Some(BreakReason::Breakpoint(self.pc));
+            return;
+        }
+        self.skip_breakpoint = false;
         // read the opcode from the memory
-        let opcode = (self.memory[self.pc as usize] as u16) << 8
-            | (self.memory[(self.pc + 1) as usize] as u16);
+        let opcode =
+            (self.read_memory(self.pc) as u16) << 8 | (self.read_memory(self.pc + 1) as u16);
+        let (pc, i) = (self.pc, self.i);
+        let write = match self.smc {
+            Some(_) => Instruction::decode(opcode).and_then(|ins| ins.memory_write(i)),
+            None => None,
+        };
+        if self.tracer.is_some() {
+            self.traced_process_opcode(opcode);
+        } else {
+            self.process_opcode(opcode);
+        }
+        // a faulting opcode never ran, it is left out of the statistics
+        if self.fault.is_none() {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(pc, opcode, self.pc == pc, self.v[0xF] == 1);
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record(pc, opcode, i, self.pc);
+            }
+        }
+        if let Some(detector) = &mut self.smc {
+            detector.executed(pc);
+            if let Some((start, len)) = write {
+                for offset in 0..len {
+                    let addr = start.wrapping_add(offset) & ADDRESS_MASK;
+                    let value = self.memory[addr as usize];
+                    if let Some(write) = detector.written(pc, addr, value) {
+                        if detector.action() == SmcAction::Break && self.break_reason.is_none() {
+                            self.break_reason = Some(BreakReason::SelfModifyingWrite(write));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn traced_process_opcode(&mut self, opcode: u16) {
+        let mut entry = TraceEntry {
+            pc: self.pc,
+            opcode,
+            v: self.v,
+            i: self.i,
+            sp: self.sp,
+            changes: Vec::new(),
+        };
+        let (dt, st) = (self.dt, self.st);
+        let write = Instruction::decode(opcode).and_then(|ins| ins.memory_write(self.i));
+
         self.process_opcode(opcode);
-        ExecutionResult::new(self.display.get_vram_copy(), self.st > 0)
+
+        for (x, value) in self.v.iter().enumerate() {
+            if *value != entry.v[x] {
+                entry.changes.push(Change::Register(x, *value));
+            }
+        }
+        if self.i != entry.i {
+            entry.changes.push(Change::I(self.i));
+        }
+        if self.sp != entry.sp {
+            entry.changes.push(Change::Sp(self.sp));
+        }
+        if self.dt != dt {
+            entry.changes.push(Change::Dt(self.dt));
+        }
+        if self.st != st {
+            entry.changes.push(Change::St(self.st));
+        }
+        if let Some((start, len)) = write {
+            for offset in 0..len {
+                let addr = (start.wrapping_add(offset) & ADDRESS_MASK) as usize;
+                entry
+                    .changes
+                    .push(Change::Memory(addr as u16, self.memory[addr]));
+            }
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.push(entry);
+        }
     }
 
     fn update_timers(&mut self) {
@@ -143,6 +962,7 @@ impl Cpu {
         let op_4 = opcode & 0x000F;
 
         // increment the program counter
+        let pc = self.pc;
         self.pc += 2;
 
         // update timers
@@ -154,13 +974,30 @@ impl Cpu {
             // Clear the display.
             (0, 0, 0xE, 0) => self.display.cls(),
 
+            // 00FE - LOW (SUPER-CHIP)
+            // Switch to the 64x32 resolution.
+            (0, 0, 0xF, 0xE) => self.display.set_hires(false),
+
+            // 00FF - HIGH (SUPER-CHIP)
+            // Switch to the 128x64 resolution.
+            (0, 0, 0xF, 0xF) => self.display.set_hires(true),
+
             // 00EE - RET
             // Return from a subroutine.
             (0, 0, 0xE, 0xE) => {
-                self.sp = self.sp - 1;
-                self.pc = self.stack[self.sp as usize];
+                if self.sp == 0 {
+                    self.fault = Some(Fault::StackUnderflow { pc });
+                    self.pc = pc;
+                } else {
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize];
+                }
             }
 
+            // 0nnn - SYS addr
+            // Jump to a machine code routine at nnn: ignored, like modern interpreters do.
+            (0, _, _, _) => (),
+
             // 1nnn - JP addr
             // Jump to location nnn.
             (0x1, _, _, _) => self.pc = nnn,
@@ -170,9 +1007,14 @@ impl Cpu {
             (0x2, _, _, _) => {
                 // the pc is already beign incremented to the next instruction
                 // so we save the current value
-                self.stack[self.sp as usize] = self.pc;
-                self.sp += 1;
-                self.pc = nnn;
+                if self.sp as usize == self.stack.len() {
+                    self.fault = Some(Fault::StackOverflow { pc });
+                    self.pc = pc;
+                } else {
+                    self.stack[self.sp as usize] = self.pc;
+                    self.sp += 1;
+                    self.pc = nnn;
+                }
             }
 
             // 3xkk - SE Vx, byte
@@ -193,7 +1035,7 @@ impl Cpu {
 
             // 7xkk - ADD Vx, byte
             // Set Vx = Vx + kk
-            (0x7, _, _, _) => self.v[x] = vx + kk,
+            (0x7, _, _, _) => self.v[x] = vx.wrapping_add(kk),
 
             // 8xy0 - LD Vx, Vy
             // Set Vx = Vy.
@@ -201,50 +1043,62 @@ impl Cpu {
 
             // 8xy1 - OR Vx, Vy
             // Set Vx = Vx OR Vy.
-            (0x8, _, _, 0x1) => self.v[x] = vx | vy,
+            (0x8, _, _, 0x1) => {
+                self.v[x] = vx | vy;
+                self.reset_vf_after_logic();
+            }
 
             // 8xy2 - AND Vx, Vy
             // Set Vx = Vx AND Vy
-            (0x8, _, _, 0x2) => self.v[x] = vx & vy,
+            (0x8, _, _, 0x2) => {
+                self.v[x] = vx & vy;
+                self.reset_vf_after_logic();
+            }
 
             // 8xy3 - XOR Vx, Vy
             // Set Vx = Vx XOR Vy.
-            (0x8, _, _, 0x3) => self.v[x] = vx ^ vy,
+            (0x8, _, _, 0x3) => {
+                self.v[x] = vx ^ vy;
+                self.reset_vf_after_logic();
+            }
 
             // 8xy4 - ADD Vx, Vy
             // Set Vx = Vx + Vy, set VF = carry.
+            // The flags are set last, so that they win when x is F.
             (0x8, _, _, 0x4) => {
                 let total = self.v[x] as u16 + self.v[y] as u16;
-                self.v[0xF] = if total > 0xFF { 1 } else { 0 };
                 self.v[x] = total as u8;
+                self.v[0xF] = if total > 0xFF { 1 } else { 0 };
             }
 
             // 8xy5 - SUB Vx, Vy
             // Set Vx = Vx - Vy, set VF = NOT borrow.
             (0x8, _, _, 0x5) => {
-                self.v[0xF] = if vx > vy { 1 } else { 0 };
-                self.v[x] = vx - vy;
+                self.v[x] = vx.wrapping_sub(vy);
+                self.v[0xF] = if vx >= vy { 1 } else { 0 };
             }
 
             // 8xy6 - SHR Vx {, Vy}
-            // Set Vx = Vx SHR 1.
+            // Set Vx = Vx SHR 1, or Vy SHR 1 with the shift_vy quirk.
             (0x8, _, _, 0x6) => {
-                self.v[0xF] = vx & 0x1;
-                self.v[x] >>= 1;
+                let source = if self.quirks.shift_vy { vy } else { vx };
+                self.v[x] = source >> 1;
+                self.v[0xF] = source & 0x1;
             }
 
             // 8xy7 - SUBN Vx, Vy
             // Set Vx = Vy - Vx, set VF = NOT borrow.
             (0x8, _, _, 0x7) => {
-                self.v[0xF] = if vy > vx { 1 } else { 0 };
-                self.v[x] = vy - vx;
+                self.v[x] = vy.wrapping_sub(vx);
+                self.v[0xF] = if vy >= vx { 1 } else { 0 };
             }
 
             // 8xyE - SHL Vx {, Vy}
-            // Set Vx = Vx SHL 1.
+            // Set Vx = Vx SHL 1, or Vy SHL 1 with the shift_vy quirk.
             (0x8, _, _, 0xE) => {
-                self.v[0xF] = vx & 0x8;
-                self.v[x] <<= 1;
+                let source = if self.quirks.shift_vy { vy } else { vx };
+                self.v[x] = source << 1;
+                self.v[0xF] = source >> 7;
             }
 
             // 9xy0 - SNE Vx, Vy
@@ -256,8 +1110,11 @@ impl Cpu {
             (0xA, _, _, _) => self.i = nnn,
 
             // Bnnn - JP V0, addr
-            // Jump to location nnn + V0
-            (0xB, _, _, _) => self.pc = nnn + self.v[0] as u16,
+            // Jump to location nnn + V0, or xnn + Vx with the jump_vx quirk
+            (0xB, _, _, _) => {
+                let offset = if self.quirks.jump_vx { vx } else { self.v[0] };
+                self.pc = nnn + offset as u16;
+            }
 
             // Cxkk - RND Vx, byte
             // Set Vx = random byte AND kk
@@ -265,22 +1122,29 @@ impl Cpu {
 
             // Dxyn - DRW Vx, Vy, nibble
             // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision
+            // In the SUPER-CHIP high resolution, Dxy0 draws a 16x16 sprite of 32 bytes.
             (0xD, _, _, _) => {
-                let collision = self.display.draw(
-                    vx as usize,
-                    vy as usize,
-                    &self.memory[self.i as usize..(self.i + n as u16) as usize],
-                );
+                let wide = n == 0 && self.display.is_hires();
+                let len = if wide { 32 } else { n as usize };
+                let mut sprite = [0u8; 32];
+                for (row, byte) in sprite.iter_mut().enumerate().take(len) {
+                    *byte = self.read_memory(self.i.wrapping_add(row as u16));
+                }
+                let collision = if wide {
+                    self.display.draw_wide(vx as usize, vy as usize, &sprite)
+                } else {
+                    self.display.draw(vx as usize, vy as usize, &sprite[..len])
+                };
                 self.v[0xF] = if collision { 1 } else { 0 };
             }
 
             // Ex9E - SKP Vx
             // Skip next instruction if key with the value of Vx is pressed
-            (0xE, _, 0x9, 0xE) => self.pc += if self.keypad.is_key_idx_pressed(vx as usize) { 2 } else { 0 },
+            (0xE, _, 0x9, 0xE) => self.pc += if self.keypad.is_key_idx_pressed((vx & 0xF) as usize) { 2 } else { 0 },
 
             // ExA1 - SKNP Vx
             // Skip next instruction if key with the value of Vx is not pressed
-            (0xE, _, 0xA, 0x1) => self.pc += if self.keypad.is_key_idx_pressed(vx as usize) { 0 } else { 2 },
+            (0xE, _, 0xA, 0x1) => self.pc += if self.keypad.is_key_idx_pressed((vx & 0xF) as usize) { 0 } else { 2 },
 
             // Fx07 - LD Vx, DT
             // Set Vx = delay timer value
@@ -288,15 +1152,11 @@ impl Cpu {
 
             // Fx0A - LD Vx, K
             // Wait for a key press, store the value of the key in Vx
-            (0xF, _, 0x0, 0xA) => {
-                match self.keypad.get_first_pressed_key_idx() {
-                    Some(idx) => {
-                        self.v[x] = idx as u8;
-                        self.pc += 2;
-                    }
-                    None => ()
-                }
-            }
+            (0xF, _, 0x0, 0xA) => match self.keypad.get_first_pressed_key_idx() {
+                Some(idx) => self.v[x] = idx as u8,
+                // execute this instruction again until a key is pressed
+                None => self.pc -= 2,
+            },
 
             // Fx15 - LD DT, Vx
             // Set delay timer = Vx
@@ -308,7 +1168,7 @@ impl Cpu {
 
             // Fx1E - ADD I, Vx
             // Set I = I + Vx
-            (0xF, _, 1, 0xE) => self.i = self.i + vx as u16,
+            (0xF, _, 1, 0xE) => self.i = self.i.wrapping_add(vx as u16),
 
             // Fx29 - LD F, Vx
             // Set I = location of sprite for digit Vx
@@ -317,23 +1177,43 @@ impl Cpu {
             // Fx33 - LD B, Vx
             // Store BCD representation of Vx in memory locations I, I+1, and I+2
             (0xF, _, 0x3, 0x3) => {
-                self.memory[self.i as usize] = vx / 100;
-                self.memory[self.i as usize + 1] = (vx / 10) % 10;
-                self.memory[self.i as usize + 2] = (vx % 100) % 10;
+                self.write_memory(self.i, vx / 100);
+                self.write_memory(self.i.wrapping_add(1), (vx / 10) % 10);
+                self.write_memory(self.i.wrapping_add(2), (vx % 100) % 10);
             }
 
             // Fx55 - LD [I], Vx
             // Store registers V0 through Vx in memory starting at location I
-            (0xF, _, 0x5, 0x5) => self.memory[(self.i as usize)..(self.i + x as u16 + 1) as usize]
-                .copy_from_slice(&self.v[0..(x as usize + 1)]),
+            (0xF, _, 0x5, 0x5) => {
+                for r in 0..=x {
+                    self.write_memory(self.i.wrapping_add(r as u16), self.v[r]);
+                }
+                self.increment_i_after_load_store(x);
+            }
 
             // Fx65 - LD Vx, [I]
             // Read registers V0 through Vx from memory starting at location I
-            (0xF, _, 0x6, 0x5) => self.v[0..(x as usize + 1)]
-                .copy_from_slice(&self.memory[(self.i as usize)..(self.i + x as u16 + 1) as usize]),
+            (0xF, _, 0x6, 0x5) => {
+                for r in 0..=x {
+                    self.v[r] = self.read_memory(self.i.wrapping_add(r as u16));
+                }
+                self.increment_i_after_load_store(x);
+            }
 
-            (_, _, _, _) => unimplemented!("opcode {:04x}", opcode),
+            (_, _, _, _) => {
+                self.fault = Some(Fault::UnknownOpcode { pc, opcode });
+                self.pc = pc;
+            }
         }
+
+        // jumps and skips can go past the end of the memory: wrap around
+        self.pc &= ADDRESS_MASK;
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
     }
 }
 
@@ -511,6 +1391,147 @@ mod tests {
         assert_eq!(cpu.v[0xF], 1, "overflow occured");
     }
 
+    #[test]
+    fn opcode_add_vx_byte_wraps_around() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 0xFF;
+        cpu.v[0xF] = 7;
+        cpu.load_cartridge(Cartridge::new(&[0x71, 0x02]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 1, "Vx wrapped around");
+        assert_eq!(cpu.v[0xF], 7, "VF is not affected");
+    }
+
+    #[test]
+    fn opcode_sub_vx_vy() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 1;
+        cpu.v[2] = 3;
+        cpu.load_cartridge(Cartridge::new(&[0x81, 0x25]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 0xFE, "Vx was loaded with vx - vy");
+        assert_eq!(cpu.v[0xF], 0, "borrow occured");
+    }
+
+    #[test]
+    fn opcode_subn_vx_vy() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 3;
+        cpu.v[2] = 1;
+        cpu.load_cartridge(Cartridge::new(&[0x81, 0x27]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 0xFE, "Vx was loaded with vy - vx");
+        assert_eq!(cpu.v[0xF], 0, "borrow occured");
+    }
+
+    #[test]
+    fn opcode_shl_vx() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 0b1000_0001;
+        cpu.load_cartridge(Cartridge::new(&[0x81, 0x0E]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 0b0000_0010, "Vx was shifted left");
+        assert_eq!(cpu.v[0xF], 1, "VF is the most significant bit");
+    }
+
+    #[test]
+    fn quirk_shift_vy() {
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(Quirks {
+            shift_vy: true,
+            ..Quirks::default()
+        });
+        cpu.v[2] = 0b1000_0001;
+        cpu.load_cartridge(Cartridge::new(&[0x81, 0x2E, 0x83, 0x26]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 0b0000_0010, "Vy was shifted left into Vx");
+        assert_eq!(cpu.v[0xF], 1, "VF is the most significant bit of Vy");
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[3], 0b0100_0000, "Vy was shifted right into Vx");
+        assert_eq!(cpu.v[2], 0b1000_0001, "Vy is unchanged");
+    }
+
+    #[test]
+    fn quirk_load_store_increments_i() {
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(Quirks {
+            load_store_increments_i: true,
+            ..Quirks::default()
+        });
+        // LD I, 0x300 - LD [I], V2 - LD V1, [I]
+        cpu.load_cartridge(Cartridge::new(&[0xA3, 0x00, 0xF2, 0x55, 0xF1, 0x65]));
+        cpu.run_frame(2);
+        assert_eq!(cpu.i, 0x303, "I is after V2");
+        cpu.execute_cycle();
+        assert_eq!(cpu.i, 0x305, "I is after V1");
+    }
+
+    #[test]
+    fn quirk_logic_resets_vf() {
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(Quirks {
+            logic_resets_vf: true,
+            ..Quirks::default()
+        });
+        cpu.v[0xF] = 1;
+        cpu.v[2] = 0x0F;
+        cpu.load_cartridge(Cartridge::new(&[0x81, 0x21]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 0x0F);
+        assert_eq!(cpu.v[0xF], 0, "VF was reset");
+    }
+
+    #[test]
+    fn quirk_jump_vx() {
+        let mut cpu = Cpu::new();
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        cpu.load_cartridge(Cartridge::new(&[0xB3, 0x00]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x310, "nnn + V0 by default");
+
+        cpu.set_quirks(Quirks {
+            jump_vx: true,
+            ..Quirks::default()
+        });
+        cpu.reset();
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        cpu.load_cartridge(Cartridge::new(&[0xB3, 0x00]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x320, "xnn + Vx, the quirks are kept by reset");
+    }
+
+    #[test]
+    fn opcode_ld_vx_k_waits_for_a_key() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0xF3, 0x0A]));
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x200, "the program counter does not move");
+
+        cpu.keypad_down("w");
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[3], 5, "the key is stored in Vx");
+        assert_eq!(cpu.pc, 0x202, "the program counter is advanced two bytes");
+    }
+
+    #[test]
+    fn keys_are_driven_by_index() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0xF3, 0x0A, 0xF4, 0x0A]));
+        cpu.keypad_press(0xB);
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[3], 0xB);
+
+        cpu.set_keypad_state(1 << 0xC | 1 << 0x7);
+        assert_eq!(cpu.keypad_state(), 0x1080);
+        cpu.keypad_release(0x7);
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[4], 0xC);
+        assert_eq!(cpu.keypad_state(), 0x1000);
+    }
+
     #[test]
     fn opcode_ld_i_vx() {
         let mut cpu = Cpu::new();
@@ -617,4 +1638,494 @@ mod tests {
         assert_eq!(cpu.i, 0x0FAF, "the 'i' register is updated");
         assert_eq!(cpu.pc, 0x202, "the program counter is advanced two bytes");
     }
+
+    #[test]
+    fn render_rgba_exposes_the_framebuffer() {
+        let mut cpu = Cpu::new();
+        cpu.set_palette(Palette::new(0x000000, 0xFFFFFF));
+        // draw the "0" glyph at (0, 0)
+        cpu.load_cartridge(Cartridge::new(&[0xD0, 0x05]));
+        cpu.execute_cycle();
+
+        let ptr = cpu.render_rgba();
+        let rgba = unsafe { std::slice::from_raw_parts(ptr, cpu.rgba_len()) };
+        assert_eq!(&rgba[0..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&rgba[16..20], &[0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn run_frame_reports_display_changes() {
+        let mut cpu = Cpu::new();
+        // draw the "0" glyph, then loop forever on the jump at 0x202
+        cpu.load_cartridge(Cartridge::new(&[0xD0, 0x05, 0x12, 0x02]));
+
+        let result = cpu.run_frame(10);
+        assert!(result.get_frame_changed(), "the sprite was drawn");
+        assert!(cpu.frame_changed());
+        assert_eq!(
+            cpu.dirty_rect(),
+            Some(DirtyRect {
+                x: 0,
+                y: 0,
+                width: 8,
+                height: 5
+            })
+        );
+
+        let result = cpu.run_frame(10);
+        assert!(!result.get_frame_changed(), "nothing was drawn");
+        assert!(!cpu.frame_changed());
+        assert_eq!(cpu.dirty_rect(), None);
+    }
+
+    #[test]
+    fn opcodes_switch_to_the_high_resolution() {
+        let mut cpu = Cpu::new();
+        // HIGH - LD I, 0x000 - DRW V0, V0, 0 - LOW - DRW V0, V0, 0
+        cpu.load_cartridge(Cartridge::new(&[
+            0x00, 0xFF, 0xA0, 0x00, 0xD0, 0x00, 0x00, 0xFE, 0xD0, 0x00,
+        ]));
+        cpu.execute_cycle();
+        assert_eq!((cpu.display_width(), cpu.display_height()), (128, 64));
+        assert_eq!(cpu.display_len(), 128 * 64);
+        assert_eq!(cpu.rgba_len(), 128 * 64 * 4);
+
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+        // the 16x16 sprite is the 32 bytes at I, two bytes per row
+        let vram = cpu.display.vram();
+        for y in 0..16 {
+            let word = u16::from_be_bytes([FONT_SET[2 * y], FONT_SET[2 * y + 1]]);
+            let row: Vec<u8> = (0..16).map(|x| (word >> (15 - x) & 1) as u8).collect();
+            assert_eq!(&vram[y * 128..y * 128 + 16], &row[..]);
+        }
+
+        cpu.execute_cycle();
+        assert_eq!(cpu.display_width(), 64);
+        // Dxy0 draws nothing in the low resolution
+        cpu.execute_cycle();
+        assert!(cpu.display.vram().iter().all(|&pixel| pixel == 0));
+
+        cpu.display.set_hires(true);
+        cpu.reset();
+        assert_eq!(cpu.display_width(), 64);
+    }
+
+    #[test]
+    fn execute_cycle_reports_display_changes() {
+        let mut cpu = Cpu::new();
+        // draw the "0" glyph, then loop forever on the jump at 0x202
+        cpu.load_cartridge(Cartridge::new(&[0xD0, 0x05, 0x12, 0x02]));
+
+        let result = cpu.execute_cycle();
+        assert!(result.get_frame_changed(), "the sprite was drawn");
+        assert!(cpu.frame_changed());
+
+        let result = cpu.execute_cycle();
+        assert!(!result.get_frame_changed(), "nothing was drawn");
+        assert!(!cpu.frame_changed());
+        assert_eq!(cpu.dirty_rect(), None);
+    }
+
+    #[test]
+    fn display_ptr_points_to_the_vram() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0xD0, 0x05]));
+        cpu.execute_cycle();
+
+        let vram = unsafe { std::slice::from_raw_parts(cpu.display_ptr(), cpu.display_len()) };
+        assert_eq!(vram.len(), 64 * 32);
+        assert_eq!(&vram[0..8], &[1, 1, 1, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn persistence_does_not_affect_emulation() {
+        let rom = [0xD0, 0x05, 0x12, 0x00];
+        let mut plain = Cpu::new();
+        plain.load_cartridge(Cartridge::new(&rom));
+        let mut phosphor = Cpu::new();
+        phosphor.load_cartridge(Cartridge::new(&rom));
+        phosphor.set_persistence(Persistence::fade(0.8));
+
+        for _ in 0..5 {
+            plain.run_frame(3);
+            phosphor.run_frame(3);
+            phosphor.render_rgba();
+            assert_eq!(
+                plain.display.get_vram_copy(),
+                phosphor.display.get_vram_copy()
+            );
+            assert_eq!(plain.pc, phosphor.pc);
+            assert_eq!(plain.v, phosphor.v);
+        }
+    }
+
+    #[test]
+    fn persistence_advances_once_per_frame() {
+        let mut cpu = Cpu::new();
+        cpu.set_palette(Palette::new(0x000000, 0xFFFFFF));
+        // draw the top-left pixel of the "0" glyph, erase it, then loop
+        cpu.load_cartridge(Cartridge::new(&[0xD0, 0x01, 0xD0, 0x01, 0x12, 0x04]));
+        cpu.set_persistence(Persistence::fade(0.5));
+
+        cpu.execute_cycle();
+        cpu.end_frame();
+        assert_eq!(&cpu.display.render_rgba()[0..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+        for _ in 0..10 {
+            cpu.execute_cycle();
+        }
+        assert_eq!(
+            &cpu.display.render_rgba()[0..4],
+            &[0xFF, 0xFF, 0xFF, 0xFF],
+            "the cycles alone do not fade the pixel"
+        );
+        cpu.end_frame();
+        assert!(cpu.frame_changed(), "the pixel is fading out");
+        assert_eq!(&cpu.display.render_rgba()[0..4], &[0x80, 0x80, 0x80, 0xFF]);
+    }
+
+    #[test]
+    fn run_frame_generates_audio_from_the_sound_timer() {
+        let mut cpu = Cpu::new();
+        assert_eq!(cpu.audio_len(), 0);
+
+        cpu.enable_audio(48000);
+        // V0 = 4, ST = V0, then loop: the timer runs out after 4 instructions
+        cpu.load_cartridge(Cartridge::new(&[0x60, 0x04, 0xF0, 0x18, 0x12, 0x04]));
+        cpu.run_frame(20);
+
+        let samples = cpu.audio_samples();
+        assert_eq!(samples.len(), 800);
+        assert!(samples[..200].iter().any(|&s| s != 0.0));
+        assert!(samples[400..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn tracer_records_executed_instructions() {
+        let mut cpu = Cpu::new();
+        cpu.enable_trace(10, TraceFormat::Full);
+        cpu.i = 0x300;
+        cpu.load_cartridge(Cartridge::new(&[0x6A, 0x02, 0xFA, 0x33]));
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+
+        let log = cpu.trace_log();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("PC:0200 OP:6A02 V0:00"));
+        assert!(lines[0].ends_with("; LD VA, 0x02 ; VA=02"));
+        assert!(lines[1].starts_with("PC:0202 OP:FA33"));
+        assert!(lines[1].ends_with("; LD B, VA ; [300]=00 [301]=00 [302]=02"));
+
+        assert_eq!(cpu.take_trace().len(), 2);
+        assert_eq!(cpu.trace_log(), "");
+    }
+
+    #[test]
+    fn tracer_is_disabled_by_default() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0x6A, 0x02]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.trace_log(), "");
+        assert_eq!(cpu.trace_format(), None);
+    }
+
+    // Regressions for the crashes found by the fuzz targets.
+
+    #[test]
+    fn oversized_cartridge_is_truncated() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0xAB; MEMORY_SIZE]));
+        assert_eq!(cpu.memory[MEMORY_SIZE - 1], 0xAB);
+        assert_eq!(cpu.memory[0x1FF], 0);
+    }
+
+    #[test]
+    fn program_counter_wraps_around_memory() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0xFFE] = 0x60;
+        cpu.memory[0xFFF] = 0x01;
+        cpu.pc = 0xFFE;
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x000);
+
+        // an opcode straddling the end of memory
+        cpu.pc = 0xFFF;
+        cpu.memory[0xFFF] = 0x3A;
+        cpu.memory[0x000] = 0x00;
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x003, "SE VA, 0x00 skips past the end of memory");
+
+        cpu.load_cartridge(Cartridge::new(&[0x60, 0xFF, 0xBF, 0xFF]));
+        cpu.pc = 0x200;
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x0FE, "JP V0, 0xFFF wraps around");
+    }
+
+    #[test]
+    fn memory_accesses_wrap_around_memory() {
+        let mut cpu = Cpu::new();
+        cpu.v[0] = 123;
+        cpu.v[1] = 0xFF;
+        cpu.i = 0xFFE;
+        // LD B, V0 - LD [I], V1 - DRW V0, V0, 15 - LD VF, [I]
+        cpu.load_cartridge(Cartridge::new(&[
+            0xF0, 0x33, 0xF1, 0x55, 0xD0, 0x0F, 0xFF, 0x65,
+        ]));
+        cpu.execute_cycle();
+        assert_eq!(&cpu.memory[0xFFE..], &[1, 2]);
+        assert_eq!(cpu.memory[0x000], 3);
+        cpu.execute_cycle();
+        assert_eq!(&cpu.memory[0xFFE..], &[123, 0xFF]);
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[0], 123);
+        assert_eq!(cpu.v[1], 0xFF);
+
+        // ADD I, Vx past 0xFFFF
+        let mut cpu = Cpu::new();
+        cpu.i = 0xFFFF;
+        cpu.v[0] = 2;
+        cpu.load_cartridge(Cartridge::new(&[0xF0, 0x1E, 0xF0, 0x55]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.i, 0x0001);
+        cpu.execute_cycle();
+        assert_eq!(cpu.memory[0x001], 2);
+    }
+
+    #[test]
+    fn key_skips_use_the_low_nibble_of_vx() {
+        let mut cpu = Cpu::new();
+        cpu.v[0] = 0xF5;
+        cpu.keypad_down("w");
+        cpu.load_cartridge(Cartridge::new(&[0xE0, 0x9E]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn sys_is_ignored() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0x01, 0x23]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.fault(), None);
+    }
+
+    #[test]
+    fn subtraction_flags_win_over_the_result() {
+        let mut cpu = Cpu::new();
+        // LD VF, 0x05 - SUB VF, V0 - LD V1, 0x05 - SUBN V1, V1
+        cpu.load_cartridge(Cartridge::new(&[
+            0x6F, 0x05, 0x8F, 0x05, 0x61, 0x05, 0x81, 0x17,
+        ]));
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[0xF], 1, "no borrow from 5 - 0");
+        cpu.execute_cycle();
+        cpu.execute_cycle();
+        assert_eq!(cpu.v[1], 0);
+        assert_eq!(cpu.v[0xF], 1, "no borrow from 5 - 5");
+    }
+
+    #[test]
+    fn faults_halt_the_cpu() {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0x00, 0xEE]));
+        cpu.execute_cycle();
+        assert_eq!(cpu.fault(), Some(Fault::StackUnderflow { pc: 0x200 }));
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x200, "a faulted cpu does not execute");
+
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0x22, 0x00]));
+        cpu.run_frame(17);
+        assert_eq!(cpu.fault(), Some(Fault::StackOverflow { pc: 0x200 }));
+        assert_eq!(cpu.sp, 16);
+
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[0xFF, 0xFF]));
+        cpu.execute_cycle();
+        assert_eq!(
+            cpu.fault_message(),
+            Some("unknown opcode FFFF at 200".to_string())
+        );
+        cpu.reset();
+        assert_eq!(cpu.fault(), None);
+    }
+
+    #[test]
+    fn profiler_counts_executed_instructions() {
+        let mut cpu = Cpu::new();
+        // LD V0, 0x0F - LD F, V0 - DRW V1, V1, 5 - JP 0x204
+        cpu.load_cartridge(Cartridge::new(&[
+            0x60, 0x0F, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x04,
+        ]));
+        assert_eq!(cpu.profile_json(), None);
+        cpu.enable_profiler();
+        cpu.run_frame(10);
+        cpu.run_frame(10);
+
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.cycles(), 20);
+        assert_eq!(profiler.executions(0x200), 1);
+        assert_eq!(profiler.executions(0x204), 9);
+        assert_eq!(profiler.frames(), 2);
+        assert_eq!(profiler.max_frame_draws(), 5);
+        assert_eq!(
+            profiler.draws_per_frame(),
+            &std::collections::BTreeMap::from([(4, 1), (5, 1)])
+        );
+        assert_eq!(profiler.collisions(), 4);
+        assert_eq!(cpu.profile_heat_map().unwrap()[0x204], 255);
+
+        cpu.disable_profiler();
+        assert_eq!(cpu.profile_report(10), None);
+    }
+
+    #[test]
+    fn coverage_marks_the_code_that_ran() {
+        let mut cpu = Cpu::new();
+        // SKP V0 - JP 0x200 - JP 0x206
+        cpu.load_cartridge(Cartridge::new(&[0xE0, 0x9E, 0x12, 0x00, 0x12, 0x06]));
+        assert_eq!(cpu.coverage_listing(), None);
+        cpu.enable_coverage();
+        cpu.run_frame(10);
+
+        let coverage = cpu.coverage().unwrap();
+        assert!(coverage.is_executed(0x202));
+        assert!(!coverage.is_executed(0x204));
+        let listing = cpu.coverage_listing().unwrap();
+        assert!(listing.contains("~ 0200  E09E  SKP V0 ; never skipped\n"));
+        assert!(listing.contains("! 0204  1206  JP 0x206\n"));
+
+        cpu.keypad_down("1");
+        cpu.run_frame(10);
+        let listing = cpu.coverage_listing().unwrap();
+        assert!(listing.contains("  0200  E09E  SKP V0\n"));
+        assert!(listing.starts_with("; code: 3 of 3 instructions executed (100.0%)\n"));
+    }
+
+    #[test]
+    fn faulting_opcodes_are_not_counted_as_executed() {
+        let mut cpu = Cpu::new();
+        // LD V0, 0x01 - unknown opcode
+        cpu.load_cartridge(Cartridge::new(&[0x60, 0x01, 0xFF, 0xFF]));
+        cpu.enable_profiler();
+        cpu.enable_coverage();
+        cpu.run_frame(10);
+
+        assert!(cpu.fault().is_some());
+        assert_eq!(cpu.profiler().unwrap().cycles(), 1);
+        assert_eq!(cpu.profiler().unwrap().executions(0x202), 0);
+        assert!(cpu.coverage().unwrap().is_executed(0x200));
+        assert!(!cpu.coverage().unwrap().is_executed(0x202));
+    }
+
+    #[test]
+    fn self_modifying_code_is_detected() {
+        // LD I, 0x206 - LD [I], V1 - JP 0x200 - 0x206: CLS
+        let program = [0xA2, 0x06, 0xF1, 0x55, 0x12, 0x00, 0x00, 0xE0];
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&program));
+        cpu.detect_self_modifying_code(SmcAction::Count);
+        cpu.run_frame(6);
+        assert_eq!(cpu.self_modifying_writes(), 0, "0x206 is not code yet");
+
+        // the same program jumping to the patched bytes
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[
+            0xA2, 0x06, 0xF1, 0x55, 0x12, 0x06, 0x12, 0x00,
+        ]));
+        cpu.detect_self_modifying_code(SmcAction::Log);
+        cpu.run_frame(6);
+        assert_eq!(cpu.self_modifying_writes(), 2);
+        assert_eq!(
+            cpu.self_modifying_log(),
+            "self-modifying write of 0x00 at 206 by the instruction at 202\n\
+             self-modifying write of 0x00 at 207 by the instruction at 202\n"
+        );
+        assert_eq!(cpu.self_modifying_log(), "");
+        assert_eq!(cpu.break_reason(), None);
+    }
+
+    #[test]
+    fn self_modifying_code_can_break() {
+        // LD V0, 0x12 - LD I, 0x200 - LD [I], V0 - ...
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&[
+            0x60, 0x12, 0xA2, 0x00, 0xF0, 0x55, 0x12, 0x00,
+        ]));
+        cpu.detect_self_modifying_code(SmcAction::Break);
+        cpu.run_frame(10);
+        assert_eq!(
+            cpu.break_reason(),
+            Some(BreakReason::SelfModifyingWrite(SelfModifyingWrite {
+                pc: 0x204,
+                addr: 0x200,
+                value: 0x12
+            }))
+        );
+        assert_eq!(cpu.pc, 0x206, "the cpu paused after the write");
+
+        cpu.resume();
+        cpu.execute_cycle();
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.break_reason(), None);
+    }
+
+    #[test]
+    fn cheats_are_applied_before_every_frame() {
+        // V3 := 5, then V3 -= 1 in a loop, like a lives counter going down
+        let program = [0x63, 0x05, 0x73, 0xFF, 0x12, 0x02];
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(&program));
+        cpu.run_frame(1);
+        cpu.search_start();
+        cpu.run_frame(2);
+        assert_eq!(cpu.search_filter(SearchFilter::Decreased, 0), Ok(1));
+        assert_eq!(cpu.search_results(10), "V3: 4\n");
+
+        cpu.add_freeze("lives", "V3", 9).unwrap();
+        cpu.run_frame(2);
+        assert_eq!(cpu.v[3], 8);
+        cpu.run_frame(2);
+        assert_eq!(cpu.v[3], 8);
+
+        // the patched program adds 0 instead
+        cpu.add_patch("no deaths", "0x203", 0).unwrap();
+        cpu.run_frame(2);
+        assert_eq!(cpu.v[3], 9);
+
+        // the cheats are kept across resets of the same program only
+        let json = cpu.cheats_json();
+        cpu.reset();
+        cpu.load_cartridge(Cartridge::new(&program));
+        assert_eq!(cpu.cheats().entries().len(), 2);
+        cpu.load_cartridge(Cartridge::new(&[0x12, 0x00]));
+        assert!(cpu.cheats().entries().is_empty());
+        assert_eq!(cpu.search_count(), 0);
+        assert!(cpu
+            .load_cheats(&json)
+            .unwrap_err()
+            .starts_with("the cheats are for another ROM"));
+    }
+
+    #[test]
+    fn seed_is_kept_across_resets() {
+        // RND V0, 0xFF
+        let random = |cpu: &mut Cpu| {
+            cpu.reset();
+            cpu.load_cartridge(Cartridge::new(&[0xC0, 0xFF]));
+            cpu.execute_cycle();
+            cpu.v[0]
+        };
+        let mut cpu = Cpu::new();
+        let first = random(&mut cpu);
+        cpu.set_seed(1234);
+        let seeded = random(&mut cpu);
+        assert_eq!(random(&mut cpu), seeded);
+        assert_ne!(seeded, first);
+    }
 }
@@ -0,0 +1,282 @@
+use std::fmt::Write;
+
+use crate::opcode::{disassemble, Instruction};
+use crate::symbols::SymbolMap;
+use crate::MEMORY_SIZE;
+
+// first byte of an executed instruction
+const EXECUTED: u8 = 0x01;
+// read as data by DRW or LD Vx, [I]
+const READ: u8 = 0x04;
+// written by LD B, Vx or LD [I], Vx
+const WRITTEN: u8 = 0x08;
+// outcomes of the conditional skips
+const SKIP_TAKEN: u8 = 0x10;
+const SKIP_NOT_TAKEN: u8 = 0x20;
+
+// Which bytes of the memory were executed, read or written, and which
+// conditional skips went both ways.
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            flags: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    fn mark(&mut self, start: u16, len: u16, flag: u8) {
+        for offset in 0..len {
+            self.flags[start.wrapping_add(offset) as usize % MEMORY_SIZE] |= flag;
+        }
+    }
+
+    // Record the instruction executed at `pc`, given the value of I before
+    // it ran and the address of the next instruction.
+    pub(crate) fn record(&mut self, pc: u16, opcode: u16, i: u16, next_pc: u16) {
+        self.mark(pc, 1, EXECUTED);
+        let instruction = match Instruction::decode(opcode) {
+            Some(instruction) => instruction,
+            None => return,
+        };
+        if let Some((start, len)) = instruction.memory_read(i) {
+            self.mark(start, len, READ);
+        }
+        if let Some((start, len)) = instruction.memory_write(i) {
+            self.mark(start, len, WRITTEN);
+        }
+        if instruction.is_skip() {
+            let skipped = next_pc == pc.wrapping_add(4) % MEMORY_SIZE as u16;
+            self.mark(pc, 1, if skipped { SKIP_TAKEN } else { SKIP_NOT_TAKEN });
+        }
+    }
+
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.flags[addr as usize % MEMORY_SIZE] & EXECUTED != 0
+    }
+
+    pub fn is_read(&self, addr: u16) -> bool {
+        self.flags[addr as usize % MEMORY_SIZE] & READ != 0
+    }
+
+    pub fn is_written(&self, addr: u16) -> bool {
+        self.flags[addr as usize % MEMORY_SIZE] & WRITTEN != 0
+    }
+
+    // Disassembly of the program in `memory`, from 0x200 to its last non
+    // zero byte. Executed instructions are listed as they ran, bytes only
+    // used as data as DB, runs of untouched zeros are collapsed, and
+    // everything else is disassembled as code marked with `!`. Skips that
+    // only went one way are marked with `~`. With symbols, labels head the
+    // lines they point to and name the addresses in the operands.
+    pub fn listing(&self, memory: &[u8], symbols: Option<&SymbolMap>) -> String {
+        let end = memory
+            .iter()
+            .rposition(|&byte| byte != 0)
+            .map_or(0x200, |last| last + 1)
+            .max(0x200);
+
+        let mut lines = String::new();
+        let (mut instructions, mut executed) = (0, 0);
+        let (mut skips, mut full_skips) = (0, 0);
+        let mut addr = 0x200;
+        let label = |addr: usize| symbols.and_then(|symbols| symbols.label(addr as u16));
+        while addr < end {
+            if let Some(label) = label(addr) {
+                writeln!(lines, "{}:", label).unwrap();
+            }
+            let flags = self.flags[addr];
+            // untouched padding is not worth disassembling
+            let untouched = |addr: usize| self.flags[addr] == 0 && memory[addr] == 0;
+            if addr + 1 < end && untouched(addr) && untouched(addr + 1) && label(addr + 1).is_none()
+            {
+                let len = 1
+                    + (addr + 1..end)
+                        .take_while(|&addr| untouched(addr) && label(addr).is_none())
+                        .count();
+                writeln!(lines, "  {:04X}  00    ; {} zero bytes", addr, len).unwrap();
+                addr += len;
+                continue;
+            }
+            let data = flags & (READ | WRITTEN) != 0;
+            let next_is_code = addr + 1 < MEMORY_SIZE && self.flags[addr + 1] & EXECUTED != 0;
+            if flags & EXECUTED == 0 && (data || next_is_code || addr + 1 == end) {
+                let comment = match (flags & READ != 0, flags & WRITTEN != 0) {
+                    (true, true) => " ; read, written",
+                    (true, false) => " ; read",
+                    (false, true) => " ; written",
+                    (false, false) => "",
+                };
+                writeln!(
+                    lines,
+                    "  {:04X}  {:02X}    DB 0x{:02X}{}",
+                    addr, memory[addr], memory[addr], comment
+                )
+                .unwrap();
+                addr += 1;
+                continue;
+            }
+
+            let opcode = (memory[addr] as u16) << 8 | memory[(addr + 1) % MEMORY_SIZE] as u16;
+            instructions += 1;
+            let (marker, comment) = if flags & EXECUTED == 0 {
+                ('!', "")
+            } else {
+                executed += 1;
+                match Instruction::decode(opcode) {
+                    Some(instruction) if instruction.is_skip() => {
+                        skips += 1;
+                        match (flags & SKIP_TAKEN != 0, flags & SKIP_NOT_TAKEN != 0) {
+                            (true, true) => {
+                                full_skips += 1;
+                                (' ', "")
+                            }
+                            (true, false) => ('~', " ; always skipped"),
+                            _ => ('~', " ; never skipped"),
+                        }
+                    }
+                    _ => (' ', ""),
+                }
+            };
+            writeln!(
+                lines,
+                "{} {:04X}  {:04X}  {}{}",
+                marker,
+                addr,
+                opcode,
+                match symbols {
+                    Some(symbols) => symbols.disassemble(opcode),
+                    None => disassemble(opcode),
+                },
+                comment
+            )
+            .unwrap();
+            addr += 2;
+        }
+
+        let percent = |count: usize, total: usize| {
+            if total == 0 {
+                100.0
+            } else {
+                count as f64 * 100.0 / total as f64
+            }
+        };
+        let mut listing = String::new();
+        writeln!(
+            listing,
+            "; code: {} of {} instructions executed ({:.1}%)",
+            executed,
+            instructions,
+            percent(executed, instructions)
+        )
+        .unwrap();
+        writeln!(
+            listing,
+            "; skips: {} of {} went both ways ({:.1}%)",
+            full_skips,
+            skips,
+            percent(full_skips, skips)
+        )
+        .unwrap();
+        writeln!(
+            listing,
+            "; data: {} bytes read, {} bytes written",
+            self.flags
+                .iter()
+                .filter(|&&flags| flags & READ != 0)
+                .count(),
+            self.flags
+                .iter()
+                .filter(|&&flags| flags & WRITTEN != 0)
+                .count()
+        )
+        .unwrap();
+        listing.push_str(&lines);
+        listing
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Coverage;
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn it_lists_covered_and_uncovered_code() {
+        let mut memory = vec![0; 0x1000];
+        memory[0x200..0x20B].copy_from_slice(&[
+            0xA2, 0x0A, // LD I, 0x20A
+            0x30, 0x00, // SE V0, 0x00
+            0x12, 0x08, // JP 0x208
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x12, 0x08, // JP 0x208
+            0xFF, // sprite
+        ]);
+        let mut coverage = Coverage::new();
+        coverage.record(0x200, 0xA20A, 0x000, 0x202);
+        coverage.record(0x202, 0x3000, 0x20A, 0x206);
+        coverage.record(0x206, 0xD001, 0x20A, 0x208);
+        coverage.record(0x208, 0x1208, 0x20A, 0x208);
+
+        assert!(coverage.is_executed(0x202));
+        assert!(!coverage.is_executed(0x204));
+        assert!(coverage.is_read(0x20A));
+        assert!(!coverage.is_written(0x20A));
+        assert_eq!(
+            coverage.listing(&memory, None),
+            "; code: 4 of 5 instructions executed (80.0%)
+; skips: 0 of 1 went both ways (0.0%)
+; data: 1 bytes read, 0 bytes written
+  0200  A20A  LD I, 0x20A
+~ 0202  3000  SE V0, 0x00 ; always skipped
+! 0204  1208  JP 0x208
+  0206  D001  DRW V0, V0, 1
+  0208  1208  JP 0x208
+  020A  FF    DB 0xFF ; read
+"
+        );
+    }
+
+    #[test]
+    fn it_lists_labels() {
+        let mut memory = vec![0; 0x1000];
+        // CALL 0x206 - JP 0x202 - 0x0000 - RET
+        memory[0x200..0x208].copy_from_slice(&[0x22, 0x06, 0x12, 0x02, 0, 0, 0x00, 0xEE]);
+        let mut symbols = SymbolMap::new();
+        symbols.insert_symbol("main", 0x200);
+        symbols.insert_symbol("loop", 0x202);
+        symbols.insert_symbol("update", 0x206);
+
+        let listing = Coverage::new().listing(&memory, Some(&symbols));
+        assert!(listing.ends_with(
+            "main:
+! 0200  2206  CALL update
+loop:
+! 0202  1202  JP loop
+  0204  00    ; 2 zero bytes
+update:
+! 0206  00EE  RET
+"
+        ));
+    }
+
+    #[test]
+    fn it_tracks_writes() {
+        let mut coverage = Coverage::new();
+        // LD [I], V2 at I = 0xFFF wraps around
+        coverage.record(0x200, 0xF255, 0xFFF, 0x202);
+        assert!(coverage.is_written(0xFFF));
+        assert!(coverage.is_written(0x000));
+        assert!(coverage.is_written(0x001));
+        assert!(!coverage.is_written(0x002));
+    }
+}
@@ -0,0 +1,126 @@
+use super::{Cpu, ADDRESS_MASK};
+
+// A register of the cpu, as seen by debuggers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Cpu {
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::V(x) => self.v[x & 0xF] as u16,
+            Register::I => self.i,
+            Register::Pc => self.pc,
+            Register::Sp => self.sp as u16,
+            Register::Dt => self.dt as u16,
+            Register::St => self.st as u16,
+        }
+    }
+
+    // Values are truncated to the size of the register, the program counter
+    // to the memory and the stack pointer to the stack.
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::V(x) => self.v[x & 0xF] = value as u8,
+            Register::I => self.i = value,
+            Register::Pc => self.pc = value & ADDRESS_MASK,
+            Register::Sp => self.sp = value.min(self.stack.len() as u16) as u8,
+            Register::Dt => self.dt = value as u8,
+            Register::St => self.st = value as u8,
+        }
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read_memory(addr)
+    }
+
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.write_memory(addr, value)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Register;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::{BreakReason, Cpu, TraceFormat};
+
+    #[test]
+    fn registers_can_be_read_and_written() {
+        let mut cpu = Cpu::new();
+        cpu.set_register(Register::V(3), 0x1FF);
+        cpu.set_register(Register::Pc, 0x1234);
+        cpu.set_register(Register::Sp, 40);
+        assert_eq!(cpu.register(Register::V(3)), 0xFF);
+        assert_eq!(cpu.register(Register::Pc), 0x234);
+        assert_eq!(cpu.register(Register::Sp), 16);
+
+        cpu.poke(0x1300, 0xAB);
+        assert_eq!(cpu.peek(0x300), 0xAB);
+    }
+
+    #[test]
+    fn breakpoints_pause_before_the_instruction() {
+        let mut cpu = Cpu::new();
+        // ADD V0, 1 - JP 0x200
+        cpu.load_cartridge(Cartridge::new(&[0x70, 0x01, 0x12, 0x00]));
+        cpu.add_breakpoint(0x202);
+        cpu.run_frame(10);
+        assert_eq!(cpu.break_reason(), Some(BreakReason::Breakpoint(0x202)));
+        assert_eq!(cpu.register(Register::Pc), 0x202);
+        assert_eq!(cpu.register(Register::V(0)), 1);
+
+        // resuming executes the instruction under the breakpoint
+        cpu.resume();
+        cpu.run_frame(10);
+        assert_eq!(cpu.break_reason(), Some(BreakReason::Breakpoint(0x202)));
+        assert_eq!(cpu.register(Register::V(0)), 2);
+
+        cpu.remove_breakpoint(0x202);
+        assert_eq!(cpu.breakpoints().count(), 0);
+        cpu.resume();
+        cpu.run_frame(10);
+        assert_eq!(cpu.break_reason(), None);
+        assert_eq!(cpu.register(Register::V(0)), 7);
+    }
+
+    #[test]
+    fn breakpoints_can_be_set_by_label() {
+        let mut cpu = Cpu::new();
+        // ADD V0, 1 - JP 0x200
+        cpu.load_cartridge(Cartridge::new(&[0x70, 0x01, 0x12, 0x00]));
+        assert!(cpu.add_breakpoint_at("main").is_err());
+        cpu.load_symbols(
+            r#"{"version": 1, "symbols": {"main": 512},
+                "lines": [{"address": 514, "file": "loop.8o", "line": 3}]}"#,
+        )
+        .unwrap();
+        cpu.add_breakpoint_at("main+0x2").unwrap();
+        assert_eq!(cpu.breakpoints().collect::<Vec<_>>(), vec![0x202]);
+
+        cpu.enable_trace(10, TraceFormat::Full);
+        cpu.run_frame(10);
+        assert_eq!(
+            cpu.break_message().unwrap(),
+            "breakpoint at main+0x2 (loop.8o:3)"
+        );
+        assert!(cpu.trace_log().ends_with("; main: ADD V0, 0x01 ; V0=01\n"));
+        assert_eq!(cpu.symbolize(0x202), "main+0x2");
+
+        cpu.clear_symbols();
+        assert_eq!(cpu.break_message().unwrap(), "breakpoint at 0x202");
+        assert_eq!(cpu.symbolize(0x202), "0x202");
+        cpu.remove_breakpoint_at("0x202").unwrap();
+        assert_eq!(cpu.breakpoints().count(), 0);
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::json::Value;
+use crate::opcode::{disassemble, Instruction};
+use crate::MEMORY_SIZE;
+
+// Reads of the delay timer from the same address at most this many
+// instructions apart are a polling loop, like `LD V0, DT; SE V0, 0; JP loop`.
+const TIMER_POLL_WINDOW: u64 = 4;
+
+// Where a ROM spends its cycles: executions per address and per kind of
+// instruction, cycles spent waiting for a key or for the delay timer, and
+// the sprites drawn.
+#[derive(Clone, Debug)]
+pub struct Profiler {
+    executions: Vec<u64>,
+    // last opcode executed at each address, for the report
+    opcodes: Vec<u16>,
+    kinds: BTreeMap<&'static str, u64>,
+    cycles: u64,
+    key_wait_cycles: u64,
+    timer_wait_cycles: u64,
+    // address and cycle of the last delay timer read
+    last_timer_read: Option<(u16, u64)>,
+    sprite_draws: u64,
+    collisions: u64,
+    frame_draws: u32,
+    frames: u64,
+    // number of frames by number of sprites drawn in the frame
+    draws_per_frame: BTreeMap<u32, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            executions: vec![0; MEMORY_SIZE],
+            opcodes: vec![0; MEMORY_SIZE],
+            kinds: BTreeMap::new(),
+            cycles: 0,
+            key_wait_cycles: 0,
+            timer_wait_cycles: 0,
+            last_timer_read: None,
+            sprite_draws: 0,
+            collisions: 0,
+            frame_draws: 0,
+            frames: 0,
+            draws_per_frame: BTreeMap::new(),
+        }
+    }
+
+    // Record the instruction executed at `pc`. `stalled` is set when the
+    // program counter did not move, `collided` when VF was set afterwards.
+    pub(crate) fn record(&mut self, pc: u16, opcode: u16, stalled: bool, collided: bool) {
+        let instruction = Instruction::decode(opcode);
+        self.cycles += 1;
+        self.executions[pc as usize] += 1;
+        self.opcodes[pc as usize] = opcode;
+        let kind = instruction.map_or("unknown", |instruction| instruction.kind());
+        *self.kinds.entry(kind).or_insert(0) += 1;
+
+        match instruction {
+            Some(Instruction::LdVxK(_)) if stalled => self.key_wait_cycles += 1,
+            Some(Instruction::LdVxDt(_)) => {
+                if let Some((last_pc, last_cycle)) = self.last_timer_read {
+                    let elapsed = self.cycles - last_cycle;
+                    if last_pc == pc && elapsed <= TIMER_POLL_WINDOW {
+                        self.timer_wait_cycles += elapsed;
+                    }
+                }
+                self.last_timer_read = Some((pc, self.cycles));
+            }
+            Some(Instruction::Drw(_, _, _)) => {
+                self.sprite_draws += 1;
+                self.frame_draws += 1;
+                if collided {
+                    self.collisions += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        self.frames += 1;
+        *self.draws_per_frame.entry(self.frame_draws).or_insert(0) += 1;
+        self.frame_draws = 0;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn executions(&self, addr: u16) -> u64 {
+        self.executions[addr as usize % MEMORY_SIZE]
+    }
+
+    // Executions per kind of instruction, see `Instruction::kind`.
+    pub fn kinds(&self) -> &BTreeMap<&'static str, u64> {
+        &self.kinds
+    }
+
+    // Cycles spent in `Fx0A` without a key pressed.
+    pub fn key_wait_cycles(&self) -> u64 {
+        self.key_wait_cycles
+    }
+
+    // Cycles spent in loops polling the delay timer.
+    pub fn timer_wait_cycles(&self) -> u64 {
+        self.timer_wait_cycles
+    }
+
+    pub fn sprite_draws(&self) -> u64 {
+        self.sprite_draws
+    }
+
+    pub fn collisions(&self) -> u64 {
+        self.collisions
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Histogram of the sprites drawn per frame: the number of frames that
+    // drew each number of sprites.
+    pub fn draws_per_frame(&self) -> &BTreeMap<u32, u64> {
+        &self.draws_per_frame
+    }
+
+    pub fn max_frame_draws(&self) -> u32 {
+        self.draws_per_frame
+            .keys()
+            .next_back()
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    // Executed addresses, the most executed first.
+    fn hot_spots(&self) -> Vec<(u16, u64)> {
+        let mut spots: Vec<(u16, u64)> = self
+            .executions
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(addr, &count)| (addr as u16, count))
+            .collect();
+        spots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    // Executions of every address scaled to 0-255, on a logarithmic scale
+    // so that rarely executed code stays visible next to the hot loops.
+    pub fn heat_map(&self) -> Vec<u8> {
+        let max = self.executions.iter().cloned().max().unwrap_or(0);
+        if max == 0 {
+            return vec![0; MEMORY_SIZE];
+        }
+        let scale = 255.0 / (max as f64).ln_1p();
+        self.executions
+            .iter()
+            .map(|&count| ((count as f64).ln_1p() * scale).round() as u8)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let kinds = self
+            .kinds
+            .iter()
+            .fold(Value::object(), |kinds, (kind, &count)| {
+                kinds.with(kind, count)
+            });
+        let addresses: Vec<Value> = self
+            .hot_spots()
+            .into_iter()
+            .map(|(addr, count)| {
+                Value::object()
+                    .with("address", addr)
+                    .with("opcode", format!("{:04X}", self.opcodes[addr as usize]))
+                    .with("count", count)
+            })
+            .collect();
+        let draws_per_frame = self
+            .draws_per_frame
+            .iter()
+            .fold(Value::object(), |draws, (count, &frames)| {
+                draws.with(&count.to_string(), frames)
+            });
+        Value::object()
+            .with("cycles", self.cycles)
+            .with("frames", self.frames)
+            .with("key_wait_cycles", self.key_wait_cycles)
+            .with("timer_wait_cycles", self.timer_wait_cycles)
+            .with("sprite_draws", self.sprite_draws)
+            .with("collisions", self.collisions)
+            .with("draws_per_frame", draws_per_frame)
+            .with("instructions", kinds)
+            .with("addresses", addresses)
+            .to_string()
+    }
+
+    // Human readable summary, listing the `limit` most executed addresses.
+    pub fn report(&self, limit: usize) -> String {
+        let percent = |count: u64| {
+            if self.cycles == 0 {
+                0.0
+            } else {
+                count as f64 * 100.0 / self.cycles as f64
+            }
+        };
+        let frames = self.frames;
+        let max_draws = self.max_frame_draws();
+        let average_draws = if frames == 0 {
+            0.0
+        } else {
+            self.sprite_draws as f64 / frames as f64
+        };
+
+        let mut report = String::new();
+        writeln!(report, "cycles: {} in {} frames", self.cycles, frames).unwrap();
+        writeln!(
+            report,
+            "waiting for a key: {} cycles ({:.1}%)",
+            self.key_wait_cycles,
+            percent(self.key_wait_cycles)
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "polling the delay timer: {} cycles ({:.1}%)",
+            self.timer_wait_cycles,
+            percent(self.timer_wait_cycles)
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "sprite draws: {} ({:.1} per frame, at most {}), collisions: {}",
+            self.sprite_draws, average_draws, max_draws, self.collisions
+        )
+        .unwrap();
+
+        writeln!(report).unwrap();
+        writeln!(report, "ADDR  OPCODE      COUNT       %  INSTRUCTION").unwrap();
+        for (addr, count) in self.hot_spots().into_iter().take(limit) {
+            let opcode = self.opcodes[addr as usize];
+            writeln!(
+                report,
+                "{:04X}  {:04X}   {:>10}  {:>5.1}%  {}",
+                addr,
+                opcode,
+                count,
+                percent(count),
+                disassemble(opcode)
+            )
+            .unwrap();
+        }
+
+        let mut kinds: Vec<(&str, u64)> = self.kinds.iter().map(|(k, &c)| (*k, c)).collect();
+        kinds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        writeln!(report).unwrap();
+        writeln!(report, "INSTRUCTION               COUNT       %").unwrap();
+        for (kind, count) in kinds {
+            writeln!(
+                report,
+                "{:<20}  {:>10}  {:>5.1}%",
+                kind,
+                count,
+                percent(count)
+            )
+            .unwrap();
+        }
+        report
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::Profiler;
+
+    #[test]
+    fn it_counts_executions_and_waits() {
+        let mut profiler = Profiler::new();
+        // LD V0, DT - SE V0, 0 - JP 0x200, twice around the loop
+        for _ in 0..2 {
+            profiler.record(0x200, 0xF007, false, false);
+            profiler.record(0x202, 0x3000, false, false);
+            profiler.record(0x204, 0x1200, false, false);
+        }
+        profiler.record(0x200, 0xF007, false, false);
+        profiler.record(0x206, 0xF10A, true, false);
+        profiler.record(0x206, 0xF10A, true, false);
+        profiler.record(0x206, 0xD015, false, true);
+        profiler.end_frame();
+
+        assert_eq!(profiler.cycles(), 10);
+        assert_eq!(profiler.executions(0x200), 3);
+        assert_eq!(profiler.timer_wait_cycles(), 6);
+        assert_eq!(profiler.key_wait_cycles(), 2);
+        assert_eq!(profiler.sprite_draws(), 1);
+        assert_eq!(profiler.collisions(), 1);
+        assert_eq!(profiler.frames(), 1);
+        assert_eq!(profiler.draws_per_frame(), &BTreeMap::from([(1, 1)]));
+        assert_eq!(profiler.kinds()["LD Vx, DT"], 3);
+
+        let heat_map = profiler.heat_map();
+        assert_eq!(heat_map[0x200], 255);
+        assert_eq!(heat_map[0x208], 0);
+        assert!(heat_map[0x202] > 0 && heat_map[0x202] < 255);
+    }
+
+    #[test]
+    fn it_reports_as_json_and_table() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x200, 0x6A02, false, false);
+        profiler.record(0x202, 0x1202, false, false);
+        profiler.record(0x202, 0x1202, true, false);
+        profiler.end_frame();
+        profiler.end_frame();
+
+        assert_eq!(
+            profiler.to_json(),
+            concat!(
+                r#"{"cycles":3,"frames":2,"key_wait_cycles":0,"timer_wait_cycles":0,"#,
+                r#""sprite_draws":0,"collisions":0,"draws_per_frame":{"0":2},"#,
+                r#""instructions":{"JP addr":2,"LD Vx, byte":1},"#,
+                r#""addresses":[{"address":514,"opcode":"1202","count":2},"#,
+                r#"{"address":512,"opcode":"6A02","count":1}]}"#
+            )
+        );
+        let report = profiler.report(10);
+        assert!(report.starts_with("cycles: 3 in 2 frames\n"));
+        assert!(report.contains("0202  1202            2   66.7%  JP 0x202\n"));
+        assert!(report.contains("LD Vx, byte                    1   33.3%\n"));
+    }
+}
@@ -0,0 +1,54 @@
+// Behaviours on which the CHIP-8 interpreters disagree. ROMs are written
+// against one of them, so the cpu can be configured per program. The
+// default is what the emulator always did: the SUPER-CHIP shifts and
+// loads, with the original Bnnn jump.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_vy: bool,
+    // Fx55/Fx65 leave I after the last register accessed
+    pub load_store_increments_i: bool,
+    // 8xy1/8xy2/8xy3 reset VF
+    pub logic_resets_vf: bool,
+    // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_vx: bool,
+}
+
+impl Quirks {
+    // The behaviour of the original COSMAC VIP interpreter.
+    pub fn cosmac_vip() -> Self {
+        Quirks {
+            shift_vy: true,
+            load_store_increments_i: true,
+            logic_resets_vf: true,
+            jump_vx: false,
+        }
+    }
+
+    // Every combination of the quirks, for sweeps over the interpreters.
+    pub fn all() -> impl Iterator<Item = Quirks> {
+        (0..16u8).filter_map(Quirks::from_bits)
+    }
+
+    // One bit per quirk, in the order of the fields, for the save states and
+    // the netplay handshake.
+    pub(crate) fn to_bits(self) -> u8 {
+        self.shift_vy as u8
+            | (self.load_store_increments_i as u8) << 1
+            | (self.logic_resets_vf as u8) << 2
+            | (self.jump_vx as u8) << 3
+    }
+
+    // None when a bit of an unknown quirk is set.
+    pub(crate) fn from_bits(bits: u8) -> Option<Quirks> {
+        if bits >> 4 != 0 {
+            return None;
+        }
+        Some(Quirks {
+            shift_vy: bits & 1 != 0,
+            load_store_increments_i: bits & 2 != 0,
+            logic_resets_vf: bits & 4 != 0,
+            jump_vx: bits & 8 != 0,
+        })
+    }
+}
@@ -0,0 +1,332 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use super::{Cpu, Quirks, ADDRESS_MASK};
+use crate::rand::{ComplementaryMultiplyWithCarryGen, CMWC_CYCLE};
+use crate::{DISPLAY_PIXEL_HEIGHT, HIRES_PIXEL_HEIGHT, MEMORY_SIZE};
+
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u8 = 3;
+
+// magic and version
+const HEADER_SIZE: usize = 4 + 1;
+// pc, i, sp, dt, st, v and stack
+const REGISTERS_SIZE: usize = 2 + 2 + 3 + 16 + 16 * 2;
+// the resolution, then the packed display rows of both resolutions
+const DISPLAY_SIZE: usize = 1 + DISPLAY_PIXEL_HEIGHT * 8 + HIRES_PIXEL_HEIGHT * 16;
+// q, c and i of the random generator
+const RAND_SIZE: usize = CMWC_CYCLE * 4 + 4 + 2;
+// the quirks the program runs with, one bit each
+const QUIRKS_SIZE: usize = 1;
+const STATE_SIZE: usize =
+    HEADER_SIZE + REGISTERS_SIZE + MEMORY_SIZE + DISPLAY_SIZE + RAND_SIZE + QUIRKS_SIZE;
+
+// Reason a save state could not be loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLength(usize),
+    // a register holds a value the cpu can never reach
+    BadRegister(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::BadLength(len) => write!(
+                f,
+                "save state is {} bytes long, expected {}",
+                len, STATE_SIZE
+            ),
+            StateError::BadRegister(name) => write!(f, "invalid {} in save state", name),
+        }
+    }
+}
+
+// Cursor over the bytes of a save state, the length being checked upfront.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        head
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take(8).try_into().unwrap())
+    }
+
+    fn u128(&mut self) -> u128 {
+        u128::from_le_bytes(self.take(16).try_into().unwrap())
+    }
+}
+
+impl Cpu {
+    // Serialize the whole machine but the keypad, which belongs to the host.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(STATE_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.extend_from_slice(&self.i.to_le_bytes());
+        bytes.extend_from_slice(&[self.sp, self.dt, self.st]);
+        bytes.extend_from_slice(&self.v);
+        for addr in self.stack.iter() {
+            bytes.extend_from_slice(&addr.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.memory);
+        let (rows, hires_rows) = self.display.rows();
+        bytes.push(self.display.is_hires() as u8);
+        for row in rows.iter() {
+            bytes.extend_from_slice(&row.to_le_bytes());
+        }
+        for row in hires_rows.iter() {
+            bytes.extend_from_slice(&row.to_le_bytes());
+        }
+        for word in self.rand.q.iter() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.rand.c.to_le_bytes());
+        bytes.extend_from_slice(&(self.rand.i as u16).to_le_bytes());
+        bytes.push(self.quirks.to_bits());
+        bytes
+    }
+
+    // Restore a state written by save_state. The cpu is left untouched when
+    // the state is invalid.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(StateError::UnsupportedVersion(bytes[MAGIC.len()]));
+        }
+        if bytes.len() != STATE_SIZE {
+            return Err(StateError::BadLength(bytes.len()));
+        }
+
+        let mut reader = Reader {
+            bytes: &bytes[HEADER_SIZE..],
+        };
+        let pc = reader.u16();
+        let i = reader.u16();
+        let sp = reader.u8();
+        let dt = reader.u8();
+        let st = reader.u8();
+        if pc > ADDRESS_MASK {
+            return Err(StateError::BadRegister("PC"));
+        }
+        if sp as usize > self.stack.len() {
+            return Err(StateError::BadRegister("SP"));
+        }
+        let mut v = [0; 16];
+        v.copy_from_slice(reader.take(16));
+        let mut stack = [0; 16];
+        for addr in stack.iter_mut() {
+            *addr = reader.u16();
+        }
+        if stack.iter().any(|&addr| addr > ADDRESS_MASK) {
+            return Err(StateError::BadRegister("stack"));
+        }
+        let memory = reader.take(MEMORY_SIZE);
+        let hires = match reader.u8() {
+            0 => false,
+            1 => true,
+            _ => return Err(StateError::BadRegister("resolution")),
+        };
+        let mut rows = [0; DISPLAY_PIXEL_HEIGHT];
+        for row in rows.iter_mut() {
+            *row = reader.u64();
+        }
+        let mut hires_rows = [0; HIRES_PIXEL_HEIGHT];
+        for row in hires_rows.iter_mut() {
+            *row = reader.u128();
+        }
+        let mut q = [0; CMWC_CYCLE];
+        for word in q.iter_mut() {
+            *word = reader.u32();
+        }
+        let c = reader.u32();
+        let rand_i = reader.u16() as usize;
+        if rand_i >= CMWC_CYCLE {
+            return Err(StateError::BadRegister("random generator index"));
+        }
+        let quirks = Quirks::from_bits(reader.u8()).ok_or(StateError::BadRegister("quirks"))?;
+
+        self.pc = pc;
+        self.i = i;
+        self.sp = sp;
+        self.dt = dt;
+        self.st = st;
+        self.v = v;
+        self.stack = stack;
+        self.memory.copy_from_slice(memory);
+        self.display.set_rows(hires, rows, hires_rows);
+        self.rand = ComplementaryMultiplyWithCarryGen { q, c, i: rand_i };
+        self.quirks = quirks;
+        // the pause belonged to the replaced execution
+        self.fault = None;
+        self.break_reason = None;
+        self.skip_breakpoint = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StateError, STATE_SIZE};
+    use crate::cartridge::Cartridge;
+    use crate::cpu::{Cpu, Quirks, TraceFormat};
+
+    fn running_cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(include_bytes!("../../web/roms/UFO.ch8")));
+        cpu.run_frame(500);
+        cpu
+    }
+
+    #[test]
+    fn restoring_a_state_resumes_the_same_execution() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        assert_eq!(state.len(), STATE_SIZE);
+        cpu.run_frame(300);
+        let expected = cpu.state();
+
+        let mut restored = Cpu::new();
+        restored.load_state(&state).unwrap();
+        restored.run_frame(300);
+        assert_eq!(restored.state(), expected);
+    }
+
+    #[test]
+    fn the_high_resolution_is_restored() {
+        let mut cpu = Cpu::new();
+        // HIGH - LD I, 0x000 - DRW V0, V0, 0
+        cpu.load_cartridge(Cartridge::new(&[0x00, 0xFF, 0xA0, 0x00, 0xD0, 0x00]));
+        cpu.run_frame(3);
+        let state = cpu.save_state();
+
+        let mut restored = Cpu::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.display_width(), 128);
+        assert_eq!(restored.state(), cpu.state());
+    }
+
+    #[test]
+    fn the_quirks_are_restored() {
+        let mut cpu = running_cpu();
+        cpu.set_quirks(Quirks::cosmac_vip());
+        let mut state = cpu.save_state();
+
+        let mut restored = Cpu::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.quirks(), Quirks::cosmac_vip());
+
+        *state.last_mut().unwrap() = 0x10;
+        assert_eq!(
+            restored.load_state(&state),
+            Err(StateError::BadRegister("quirks"))
+        );
+    }
+
+    // Run `frames` frames of PONG2 with the keys changing every few frames.
+    fn play(cpu: &mut Cpu, from: u32, frames: u32) {
+        for frame in from..from + frames {
+            cpu.set_keypad_state(if frame % 12 < 5 {
+                1 << 0x1 | 1 << 0xD
+            } else {
+                0
+            });
+            cpu.run_frame(10);
+        }
+    }
+
+    // Netplay runs the frames again from a state when the keys of the peer
+    // arrive late, and expects exactly the same run.
+    #[test]
+    fn replaying_the_same_keys_is_deterministic() {
+        let mut cpu = Cpu::new();
+        cpu.set_seed(9);
+        cpu.load_cartridge(Cartridge::new(include_bytes!("../../web/roms/PONG2.ch8")));
+        play(&mut cpu, 0, 30);
+        let state = cpu.save_state();
+        play(&mut cpu, 30, 90);
+        let expected = cpu.save_state();
+
+        // again on the same cpu, then on another one with all the host
+        // features on, which must not change the run
+        cpu.load_state(&state).unwrap();
+        play(&mut cpu, 30, 90);
+        assert_eq!(cpu.save_state(), expected);
+        let mut other = Cpu::new();
+        other.enable_audio(44100);
+        other.enable_trace(16, TraceFormat::Compact);
+        other.enable_profiler();
+        other.enable_coverage();
+        other.load_state(&state).unwrap();
+        play(&mut other, 30, 90);
+        assert_eq!(other.save_state(), expected);
+    }
+
+    #[test]
+    fn invalid_states_are_rejected() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        let before = cpu.state();
+
+        assert_eq!(cpu.load_state(b"nope"), Err(StateError::BadMagic));
+        let mut bad = state.clone();
+        bad[4] = 1;
+        assert_eq!(cpu.load_state(&bad), Err(StateError::UnsupportedVersion(1)));
+        assert_eq!(
+            cpu.load_state(&state[..100]),
+            Err(StateError::BadLength(100))
+        );
+        let mut bad = state.clone();
+        bad[5..7].copy_from_slice(&0x1000u16.to_le_bytes());
+        assert_eq!(cpu.load_state(&bad), Err(StateError::BadRegister("PC")));
+        let mut bad = state;
+        bad[9] = 17;
+        assert_eq!(cpu.load_state(&bad), Err(StateError::BadRegister("SP")));
+
+        assert_eq!(cpu.state(), before);
+    }
+
+    #[test]
+    fn loading_a_state_clears_the_break() {
+        let mut cpu = Cpu::new();
+        // LD V0, 1 - LD V1, 2 - JP 0x204
+        cpu.load_cartridge(Cartridge::new(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x04]));
+        let state = cpu.save_state();
+        cpu.add_breakpoint(0x202);
+        cpu.run_frame(10);
+        assert!(cpu.break_reason().is_some());
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.break_reason(), None);
+        cpu.remove_breakpoint(0x202);
+        cpu.run_frame(10);
+        assert_eq!(cpu.state().v[1], 2, "the cpu runs the loaded state");
+    }
+}
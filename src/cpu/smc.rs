@@ -0,0 +1,122 @@
+use std::fmt;
+
+use wasm_bindgen::prelude::*;
+
+use crate::analysis::Analysis;
+use crate::MEMORY_SIZE;
+
+// What to do when an instruction writes into code.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmcAction {
+    // keep a message for the frontend to show
+    Log,
+    // pause the cpu after the write
+    Break,
+    // only count the writes
+    Count,
+}
+
+// A byte written by the instruction at `pc` over code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfModifyingWrite {
+    pub pc: u16,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for SelfModifyingWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "self-modifying write of 0x{:02X} at {:03X} by the instruction at {:03X}",
+            self.value, self.addr, self.pc
+        )
+    }
+}
+
+// Tracks the bytes known to be code, and the writes landing on them.
+#[derive(Clone, Debug)]
+pub struct SmcDetector {
+    action: SmcAction,
+    code: Vec<bool>,
+    count: u32,
+    log: Vec<SelfModifyingWrite>,
+}
+
+impl SmcDetector {
+    // Start from the code reachable from 0x200 in `memory`, as decoded by
+    // the static analysis. Executed instructions are added as they run.
+    pub fn new(action: SmcAction, memory: &[u8]) -> Self {
+        let analysis = Analysis::new(&memory[0x200..]);
+        SmcDetector {
+            action,
+            code: (0..MEMORY_SIZE as u16)
+                .map(|addr| analysis.is_code(addr))
+                .collect(),
+            count: 0,
+            log: Vec::new(),
+        }
+    }
+
+    pub fn action(&self) -> SmcAction {
+        self.action
+    }
+
+    pub(crate) fn executed(&mut self, pc: u16) {
+        self.code[pc as usize % MEMORY_SIZE] = true;
+        self.code[(pc as usize + 1) % MEMORY_SIZE] = true;
+    }
+
+    // Check a write, returning it when it lands on code.
+    pub(crate) fn written(&mut self, pc: u16, addr: u16, value: u8) -> Option<SelfModifyingWrite> {
+        if !self.code[addr as usize % MEMORY_SIZE] {
+            return None;
+        }
+        let write = SelfModifyingWrite { pc, addr, value };
+        self.count = self.count.saturating_add(1);
+        if self.action == SmcAction::Log {
+            self.log.push(write);
+        }
+        Some(write)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    // Remove and return the logged writes, oldest first.
+    pub fn drain(&mut self) -> Vec<SelfModifyingWrite> {
+        self.log.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SelfModifyingWrite, SmcAction, SmcDetector};
+
+    #[test]
+    fn it_detects_writes_into_code() {
+        let mut memory = vec![0; 0x1000];
+        // JP 0x200
+        memory[0x200..0x202].copy_from_slice(&[0x12, 0x00]);
+        let mut detector = SmcDetector::new(SmcAction::Log, &memory);
+
+        assert_eq!(detector.written(0x300, 0x202, 1), None);
+        detector.executed(0x202);
+        let write = SelfModifyingWrite {
+            pc: 0x300,
+            addr: 0x203,
+            value: 7,
+        };
+        assert_eq!(detector.written(0x300, 0x203, 7), Some(write));
+        assert!(detector.written(0x300, 0x201, 0).is_some());
+        assert_eq!(detector.count(), 2);
+        assert_eq!(detector.drain()[0], write);
+        assert!(detector.drain().is_empty());
+        assert_eq!(
+            write.to_string(),
+            "self-modifying write of 0x07 at 203 by the instruction at 300"
+        );
+    }
+}
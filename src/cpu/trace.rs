@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use wasm_bindgen::prelude::*;
+
+use crate::opcode::disassemble;
+use crate::symbols::SymbolMap;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    // PC, opcode, V0-VF, I and SP: one line per instruction, easy to diff
+    // with the traces of other emulators
+    Compact,
+    // the compact format followed by the mnemonic and the changed state
+    Full,
+}
+
+// A piece of state changed by an instruction, with its new value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Register(usize, u8),
+    I(u16),
+    Sp(u8),
+    Dt(u8),
+    St(u8),
+    Memory(u16, u8),
+}
+
+// State of the cpu before executing an instruction, and what the
+// instruction changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub changes: Vec<Change>,
+}
+
+impl TraceEntry {
+    pub fn format(&self, format: TraceFormat) -> String {
+        self.format_with_symbols(format, None)
+    }
+
+    // With symbols, the full format shows the instruction address as
+    // `label+offset` and the addresses it refers to by their labels.
+    pub fn format_with_symbols(&self, format: TraceFormat, symbols: Option<&SymbolMap>) -> String {
+        let mut line = format!("PC:{:04X} OP:{:04X}", self.pc, self.opcode);
+        for (x, value) in self.v.iter().enumerate() {
+            write!(line, " V{:X}:{:02X}", x, value).unwrap();
+        }
+        write!(line, " I:{:04X} SP:{:02X}", self.i, self.sp).unwrap();
+
+        if format == TraceFormat::Full {
+            match symbols {
+                Some(symbols) => write!(
+                    line,
+                    " ; {}: {}",
+                    symbols.format_address(self.pc),
+                    symbols.disassemble(self.opcode)
+                ),
+                None => write!(line, " ; {}", disassemble(self.opcode)),
+            }
+            .unwrap();
+            if !self.changes.is_empty() {
+                line.push_str(" ;");
+            }
+            for change in self.changes.iter() {
+                match change {
+                    Change::Register(x, value) => write!(line, " V{:X}={:02X}", x, value),
+                    Change::I(value) => write!(line, " I={:04X}", value),
+                    Change::Sp(value) => write!(line, " SP={:02X}", value),
+                    Change::Dt(value) => write!(line, " DT={:02X}", value),
+                    Change::St(value) => write!(line, " ST={:02X}", value),
+                    Change::Memory(addr, value) => write!(line, " [{:03X}]={:02X}", addr, value),
+                }
+                .unwrap();
+            }
+        }
+        line
+    }
+}
+
+// Ring buffer of the last executed instructions.
+pub struct Tracer {
+    capacity: usize,
+    format: TraceFormat,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl Tracer {
+    pub fn new(capacity: usize, format: TraceFormat) -> Tracer {
+        Tracer {
+            capacity: capacity.max(1),
+            format,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+
+    // Remove and return all the entries, oldest first.
+    pub fn drain(&mut self) -> Vec<TraceEntry> {
+        self.entries.drain(..).collect()
+    }
+
+    // All the entries, one line each, oldest first.
+    pub fn log(&self, symbols: Option<&SymbolMap>) -> String {
+        let mut log = String::new();
+        for entry in self.entries.iter() {
+            log.push_str(&entry.format_with_symbols(self.format, symbols));
+            log.push('\n');
+        }
+        log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pc: u16) -> TraceEntry {
+        TraceEntry {
+            pc,
+            opcode: 0x6A02,
+            v: [0; 16],
+            i: 0x0300,
+            sp: 1,
+            changes: vec![Change::Register(0xA, 0x02)],
+        }
+    }
+
+    #[test]
+    fn compact_format_has_the_registers() {
+        assert_eq!(
+            entry(0x200).format(TraceFormat::Compact),
+            "PC:0200 OP:6A02 V0:00 V1:00 V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 \
+             V8:00 V9:00 VA:00 VB:00 VC:00 VD:00 VE:00 VF:00 I:0300 SP:01"
+        );
+    }
+
+    #[test]
+    fn full_format_adds_mnemonic_and_changes() {
+        let line = entry(0x200).format(TraceFormat::Full);
+        assert!(line.ends_with("I:0300 SP:01 ; LD VA, 0x02 ; VA=02"));
+    }
+
+    #[test]
+    fn full_format_uses_symbols() {
+        let mut symbols = SymbolMap::new();
+        symbols.insert_symbol("draw_player", 0x1FC);
+        symbols.insert_symbol("sprite", 0x300);
+        let mut entry = entry(0x200);
+        entry.opcode = 0xA300;
+        entry.changes = vec![Change::I(0x300)];
+
+        let line = entry.format_with_symbols(TraceFormat::Full, Some(&symbols));
+        assert!(line.ends_with("SP:01 ; draw_player+0x4: LD I, sprite ; I=0300"));
+        let line = entry.format_with_symbols(TraceFormat::Compact, Some(&symbols));
+        assert!(line.ends_with("SP:01"));
+    }
+
+    #[test]
+    fn it_keeps_the_last_entries() {
+        let mut tracer = Tracer::new(2, TraceFormat::Compact);
+        tracer.push(entry(0x200));
+        tracer.push(entry(0x202));
+        tracer.push(entry(0x204));
+
+        let pcs: Vec<u16> = tracer.entries().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, vec![0x202, 0x204]);
+        assert_eq!(tracer.log(None).lines().count(), 2);
+        assert_eq!(tracer.drain().len(), 2);
+        assert_eq!(tracer.entries().count(), 0);
+    }
+}
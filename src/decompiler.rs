@@ -0,0 +1,596 @@
+// Decompiler from CHIP-8 bytecode to Octo source, for studying programs.
+//
+// The static analysis tells code from data. On top of the instructions,
+// backward jumps become `loop ... again`, a skip over a jump out of a loop
+// becomes `while`, a skip over a forward jump becomes `if ... begin`,
+// `else` and `end`, and every other skip `if ... then`. Call targets are
+// named `sub_XXX`, the data drawn by `sprite` `sprite_XXX` and is listed in
+// binary. Structures are only recovered when they nest, so that the source
+// reassembles to the same bytes with the `octo` module, for the SUPER-CHIP
+// target when it switches the resolution.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::analysis::Analysis;
+use crate::opcode::Instruction::{self, *};
+use crate::MEMORY_SIZE;
+
+const START: u16 = 0x200;
+// bytes per line of data other than sprites
+const DATA_COLUMNS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Structure {
+    // `loop` at `start`, `again` at the backward jump
+    Loop { start: u16, again: u16 },
+    // skip and jump out of the innermost loop at `skip`
+    While { skip: u16 },
+    // skip and forward jump at `skip`, the jump going to `end`
+    If { skip: u16, end: u16 },
+    // the same, with the jump at the end of the first branch as `else`
+    IfElse { skip: u16, jump: u16, end: u16 },
+}
+
+impl Structure {
+    // The range covered by the structure, and the ranges in it where other
+    // structures may lie.
+    fn ranges(self) -> ((u16, u16), Vec<(u16, u16)>) {
+        match self {
+            Structure::Loop { start, again } => ((start, again + 2), vec![(start, again)]),
+            Structure::While { skip } => ((skip, skip + 4), Vec::new()),
+            Structure::If { skip, end } => ((skip, end), vec![(skip + 4, end)]),
+            Structure::IfElse { skip, jump, end } => {
+                ((skip, end), vec![(skip + 4, jump), (jump + 2, end)])
+            }
+        }
+    }
+
+    // Whether the two structures can be written one inside the other, or
+    // one after the other.
+    fn nests_with(self, other: Structure) -> bool {
+        let (outer, inner) = self.ranges();
+        let (other_outer, other_inner) = other.ranges();
+        let within =
+            |range: (u16, u16), region: &(u16, u16)| region.0 <= range.0 && range.1 <= region.1;
+        outer.1 <= other_outer.0
+            || other_outer.1 <= outer.0
+            || other_inner.iter().any(|region| within(outer, region))
+            || inner.iter().any(|region| within(other_outer, region))
+    }
+}
+
+// How an instruction is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Plain,
+    // the skip and the jump of an `if ... begin` or a `while`
+    IfBegin,
+    While,
+    Again,
+    Else,
+    // the jump following an `IfBegin` or a `While`
+    Consumed,
+}
+
+fn register(x: usize) -> String {
+    format!("v{:x}", x)
+}
+
+// The condition under which a skip skips, and its opposite.
+fn conditions(instruction: Instruction) -> Option<(String, String)> {
+    let compare = |x: usize, y: String, equal: bool| {
+        let (yes, no) = if equal { ("==", "!=") } else { ("!=", "==") };
+        (
+            format!("{} {} {}", register(x), yes, y),
+            format!("{} {} {}", register(x), no, y),
+        )
+    };
+    match instruction {
+        SeByte(x, kk) => Some(compare(x, kk.to_string(), true)),
+        SneByte(x, kk) => Some(compare(x, kk.to_string(), false)),
+        SeReg(x, y) => Some(compare(x, register(y), true)),
+        SneReg(x, y) => Some(compare(x, register(y), false)),
+        Skp(x) => Some((
+            format!("{} key", register(x)),
+            format!("{} -key", register(x)),
+        )),
+        Sknp(x) => Some((
+            format!("{} -key", register(x)),
+            format!("{} key", register(x)),
+        )),
+        _ => None,
+    }
+}
+
+struct Decompiler {
+    memory: Vec<u8>,
+    // address following the program
+    end: u16,
+    // the instructions listed as such, by address; everything else is data
+    instructions: BTreeMap<u16, Instruction>,
+    labels: BTreeMap<u16, String>,
+    sprites: BTreeSet<u16>,
+    structures: Vec<Structure>,
+    roles: BTreeMap<u16, Role>,
+}
+
+// Decompile a program loaded at 0x200.
+pub fn decompile(program: &[u8]) -> String {
+    let len = program.len().min(MEMORY_SIZE - START as usize);
+    // one more byte, for the second half of an opcode at 0xFFF
+    let mut memory = vec![0; MEMORY_SIZE + 1];
+    memory[START as usize..START as usize + len].copy_from_slice(&program[..len]);
+    let mut decompiler = Decompiler {
+        memory,
+        end: START + len as u16,
+        instructions: BTreeMap::new(),
+        labels: BTreeMap::new(),
+        sprites: BTreeSet::new(),
+        structures: Vec::new(),
+        roles: BTreeMap::new(),
+    };
+    let analysis = Analysis::new(&program[..len]);
+    decompiler.find_instructions(&analysis);
+    decompiler.find_sprites(&analysis);
+    decompiler.name_labels();
+    decompiler.find_structures();
+    // the structures make some of the labels unnecessary
+    decompiler.name_labels();
+    decompiler.write()
+}
+
+impl Decompiler {
+    fn instruction_at(&self, addr: u16) -> Option<Instruction> {
+        self.instructions.get(&addr).cloned()
+    }
+
+    fn in_program(&self, addr: u16) -> bool {
+        START <= addr && addr < self.end
+    }
+
+    // Whether a structure can open or close at `addr`: not in the middle
+    // of an instruction.
+    fn is_boundary(&self, addr: u16) -> bool {
+        addr == self.end || (self.in_program(addr) && !self.instructions.contains_key(&(addr - 1)))
+    }
+
+    fn is_code(&self, addr: u16) -> bool {
+        self.instructions.contains_key(&addr) || self.instructions.contains_key(&(addr - 1))
+    }
+
+    // Reachable instructions, in order. When two of them overlap, the first
+    // one wins and the bytes of the other are data.
+    fn find_instructions(&mut self, analysis: &Analysis) {
+        let starts: BTreeSet<u16> = analysis
+            .blocks()
+            .flat_map(|block| block.instructions())
+            .collect();
+        let mut addr = START;
+        while addr < self.end {
+            let opcode =
+                (self.memory[addr as usize] as u16) << 8 | self.memory[addr as usize + 1] as u16;
+            match Instruction::decode(opcode) {
+                Some(instruction) if starts.contains(&addr) && addr + 2 <= self.end => {
+                    self.instructions.insert(addr, instruction);
+                    addr += 2;
+                }
+                _ => addr += 1,
+            }
+        }
+    }
+
+    // The bytes drawn by `sprite`, with I set by `i := addr` in the same
+    // block.
+    fn find_sprites(&mut self, analysis: &Analysis) {
+        for block in analysis.blocks() {
+            let mut i = None;
+            for addr in block.instructions() {
+                let opcode = (self.memory[addr as usize] as u16) << 8
+                    | self.memory[addr as usize + 1] as u16;
+                match Instruction::decode(opcode) {
+                    Some(LdI(nnn)) => i = Some(nnn),
+                    Some(AddI(_)) | Some(LdF(_)) => i = None,
+                    Some(Drw(_, _, n)) => {
+                        if let Some(i) = i {
+                            let bytes: Vec<u16> = (i..i + n as u16)
+                                .filter(|&addr| self.in_program(addr) && !self.is_code(addr))
+                                .collect();
+                            self.sprites.extend(bytes);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Name the addresses referenced by instructions, except by the jumps
+    // written as structures.
+    fn name_labels(&mut self) {
+        // the stronger names win: subroutines over sprites over data
+        let mut names: BTreeMap<u16, (u8, &str)> = BTreeMap::new();
+        self.labels.clear();
+        for (addr, instruction) in self.instructions.iter() {
+            if self.roles.contains_key(addr) {
+                continue;
+            }
+            let (addr, rank, prefix) = match *instruction {
+                Call(nnn) => (nnn, 3, "sub"),
+                LdI(nnn) if self.sprites.contains(&nnn) => (nnn, 2, "sprite"),
+                LdI(nnn) => (nnn, 1, "data"),
+                Jp(nnn) | JpV0(nnn) => (nnn, 0, "label"),
+                _ => continue,
+            };
+            if !self.in_program(addr) {
+                continue;
+            }
+            let name = names.entry(addr).or_insert((rank, prefix));
+            if rank > name.0 {
+                *name = (rank, prefix);
+            }
+        }
+        for (addr, (_, prefix)) in names {
+            self.labels.insert(addr, format!("{}_{:03X}", prefix, addr));
+        }
+        self.labels.insert(START, "main".to_string());
+    }
+
+    fn accept(&mut self, structure: Structure) -> bool {
+        if self
+            .structures
+            .iter()
+            .all(|&other| structure.nests_with(other))
+        {
+            self.structures.push(structure);
+            true
+        } else {
+            false
+        }
+    }
+
+    // A skip followed by a forward jump, with no label between them: the
+    // address of the skip and the target of the jump.
+    fn skip_and_jump(&self, skip: u16) -> Option<u16> {
+        let is_skip = self.instruction_at(skip)?.is_skip();
+        let labelled =
+            self.labels.contains_key(&(skip + 2)) || self.labels.contains_key(&(skip + 3));
+        match self.instruction_at(skip + 2)? {
+            Jp(target)
+                if is_skip && !labelled && target >= skip + 4 && self.is_boundary(target) =>
+            {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
+    fn find_structures(&mut self) {
+        // backward jumps, the outer loops first
+        let mut loops: Vec<(u16, u16)> = self
+            .instructions
+            .iter()
+            .filter_map(|(&addr, instruction)| match *instruction {
+                Jp(start) if start <= addr && self.instructions.contains_key(&start) => {
+                    Some((start, addr))
+                }
+                _ => None,
+            })
+            .collect();
+        loops.sort_by_key(|&(start, again)| (start, std::cmp::Reverse(again)));
+        for (start, again) in loops {
+            if self.accept(Structure::Loop { start, again }) {
+                self.roles.insert(again, Role::Again);
+            }
+        }
+
+        let skips: Vec<u16> = self
+            .instructions
+            .iter()
+            .filter(|(_, instruction)| instruction.is_skip())
+            .map(|(&addr, _)| addr)
+            .collect();
+
+        // jumps out of the innermost loop
+        for &skip in skips.iter() {
+            let target = match self.skip_and_jump(skip) {
+                Some(target) => target,
+                None => continue,
+            };
+            let innermost = self
+                .structures
+                .iter()
+                .filter_map(|structure| match *structure {
+                    Structure::Loop { start, again } if start <= skip && skip + 4 <= again => {
+                        Some(again)
+                    }
+                    _ => None,
+                })
+                .min();
+            if innermost.map(|again| again + 2) == Some(target)
+                && self.accept(Structure::While { skip })
+            {
+                self.roles.insert(skip, Role::While);
+                self.roles.insert(skip + 2, Role::Consumed);
+            }
+        }
+
+        // forward jumps over a branch, with an `else` when the branch ends
+        // with a forward jump itself
+        for &skip in skips.iter() {
+            if self.roles.contains_key(&skip) || self.roles.contains_key(&(skip + 2)) {
+                continue;
+            }
+            let end = match self.skip_and_jump(skip) {
+                Some(end) => end,
+                None => continue,
+            };
+            let jump = end - 2;
+            let accepted = match self.instruction_at(jump) {
+                Some(Jp(target))
+                    if jump >= skip + 4
+                        && target > end
+                        && self.is_boundary(target)
+                        && !self.roles.contains_key(&jump)
+                        && self.accept(Structure::IfElse {
+                            skip,
+                            jump,
+                            end: target,
+                        }) =>
+                {
+                    self.roles.insert(jump, Role::Else);
+                    true
+                }
+                _ => self.accept(Structure::If { skip, end }),
+            };
+            if accepted {
+                self.roles.insert(skip, Role::IfBegin);
+                self.roles.insert(skip + 2, Role::Consumed);
+            }
+        }
+    }
+
+    fn address(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    fn statement(&self, instruction: Instruction) -> String {
+        let (vx, vy) = (register, register);
+        match instruction {
+            Sys(nnn) => format!("native 0x{:03X}", nnn),
+            Cls => "clear".to_string(),
+            Ret => "return".to_string(),
+            Low => "lores".to_string(),
+            High => "hires".to_string(),
+            Jp(nnn) => format!("jump {}", self.address(nnn)),
+            Call(nnn) if self.labels.contains_key(&nnn) => self.labels[&nnn].clone(),
+            Call(nnn) => format!(":call 0x{:03X}", nnn),
+            SeByte(..) | SneByte(..) | SeReg(..) | SneReg(..) | Skp(_) | Sknp(_) => {
+                let (_, otherwise) = conditions(instruction).unwrap_or_default();
+                format!("if {} then", otherwise)
+            }
+            LdByte(x, kk) => format!("{} := {}", vx(x), kk),
+            AddByte(x, kk) => format!("{} += {}", vx(x), kk),
+            LdReg(x, y) => format!("{} := {}", vx(x), vy(y)),
+            Or(x, y) => format!("{} |= {}", vx(x), vy(y)),
+            And(x, y) => format!("{} &= {}", vx(x), vy(y)),
+            Xor(x, y) => format!("{} ^= {}", vx(x), vy(y)),
+            AddReg(x, y) => format!("{} += {}", vx(x), vy(y)),
+            Sub(x, y) => format!("{} -= {}", vx(x), vy(y)),
+            Shr(x, y) => format!("{} >>= {}", vx(x), vy(y)),
+            Subn(x, y) => format!("{} =- {}", vx(x), vy(y)),
+            Shl(x, y) => format!("{} <<= {}", vx(x), vy(y)),
+            LdI(nnn) => format!("i := {}", self.address(nnn)),
+            JpV0(nnn) => format!("jump0 {}", self.address(nnn)),
+            Rnd(x, kk) => format!("{} := random {}", vx(x), kk),
+            // a 16x16 sprite on SUPER-CHIP, which Octo only assembles
+            // for that target
+            Drw(x, y, 0) => format!("0x{:02X} 0x{:02X}", 0xD0 | x, y << 4),
+            Drw(x, y, n) => format!("sprite {} {} {}", vx(x), vy(y), n),
+            LdVxDt(x) => format!("{} := delay", vx(x)),
+            LdVxK(x) => format!("{} := key", vx(x)),
+            LdDtVx(x) => format!("delay := {}", vx(x)),
+            LdStVx(x) => format!("buzzer := {}", vx(x)),
+            AddI(x) => format!("i += {}", vx(x)),
+            LdF(x) => format!("i := hex {}", vx(x)),
+            LdB(x) => format!("bcd {}", vx(x)),
+            LdIVx(x) => format!("save {}", vx(x)),
+            LdVxI(x) => format!("load {}", vx(x)),
+        }
+    }
+
+    fn write(&self) -> String {
+        // the `end`s closing at each address, the innermost first
+        let mut ends: BTreeMap<u16, Vec<u16>> = BTreeMap::new();
+        let mut loops: BTreeMap<u16, usize> = BTreeMap::new();
+        for structure in self.structures.iter() {
+            match *structure {
+                Structure::If { skip, end } | Structure::IfElse { skip, end, .. } => {
+                    ends.entry(end).or_default().push(skip)
+                }
+                Structure::Loop { start, .. } => *loops.entry(start).or_default() += 1,
+                Structure::While { .. } => (),
+            }
+        }
+        for starts in ends.values_mut() {
+            starts.sort_by(|a, b| b.cmp(a));
+        }
+
+        let mut source = String::new();
+        writeln!(source, "# decompiled from {} bytes", self.end - START).unwrap();
+        let mut depth = 0;
+        let mut data = String::new();
+        let mut data_len = 0;
+        let mut addr = START;
+        loop {
+            let indent = |depth: usize| "  ".repeat(depth + 1);
+            let boundary = ends.contains_key(&addr)
+                || self.labels.contains_key(&addr)
+                || loops.contains_key(&addr)
+                || self.instructions.contains_key(&addr)
+                || addr == self.end;
+            let sprite_edge = self.sprites.contains(&addr) || self.sprites.contains(&(addr - 1));
+            if !data.is_empty() && (boundary || sprite_edge || data_len == DATA_COLUMNS) {
+                writeln!(source, "{}{}", indent(depth), data.trim_end()).unwrap();
+                data.clear();
+                data_len = 0;
+            }
+            for _ in ends.get(&addr).into_iter().flatten() {
+                depth -= 1;
+                writeln!(source, "{}end", indent(depth)).unwrap();
+            }
+            if let Some(label) = self.labels.get(&addr) {
+                writeln!(source, ": {}", label).unwrap();
+            }
+            if addr == self.end {
+                break;
+            }
+            for _ in 0..loops.get(&addr).cloned().unwrap_or(0) {
+                writeln!(source, "{}loop", indent(depth)).unwrap();
+                depth += 1;
+            }
+
+            let instruction = match self.instruction_at(addr) {
+                Some(instruction) => instruction,
+                None => {
+                    let byte = self.memory[addr as usize];
+                    if self.sprites.contains(&addr) {
+                        write!(data, "0b{:08b} ", byte).unwrap();
+                    } else {
+                        write!(data, "0x{:02X} ", byte).unwrap();
+                    }
+                    data_len += 1;
+                    addr += 1;
+                    continue;
+                }
+            };
+            if let Some(label) = self.labels.get(&(addr + 1)) {
+                writeln!(source, ":next {}", label).unwrap();
+            }
+            let role = self.roles.get(&addr).cloned().unwrap_or(Role::Plain);
+            let condition = || conditions(instruction).unwrap_or_default().0;
+            match role {
+                Role::Plain => {
+                    writeln!(source, "{}{}", indent(depth), self.statement(instruction)).unwrap()
+                }
+                Role::IfBegin => {
+                    writeln!(source, "{}if {} begin", indent(depth), condition()).unwrap();
+                    depth += 1;
+                }
+                Role::While => writeln!(source, "{}while {}", indent(depth), condition()).unwrap(),
+                Role::Again => {
+                    depth -= 1;
+                    writeln!(source, "{}again", indent(depth)).unwrap();
+                }
+                Role::Else => writeln!(source, "{}else", indent(depth - 1)).unwrap(),
+                Role::Consumed => (),
+            }
+            addr += 2;
+        }
+        source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompile;
+    use crate::octo::{compile, Target};
+
+    fn recompile(program: &[u8]) -> Vec<u8> {
+        let source = decompile(program);
+        match compile("program.8o", &source, Target::Chip8) {
+            Ok(compiled) => compiled.bytes,
+            Err(error) => panic!("{}\n{}", error, source),
+        }
+    }
+
+    #[test]
+    fn it_reassembles_the_roms() {
+        let roms: &[&[u8]] = &[
+            include_bytes!("../web/roms/IBM.ch8"),
+            include_bytes!("../web/roms/INVADERS.ch8"),
+            include_bytes!("../web/roms/PONG2.ch8"),
+            include_bytes!("../web/roms/TETRIS.ch8"),
+            include_bytes!("../web/roms/TIMEBOMB.ch8"),
+            include_bytes!("../web/roms/UFO.ch8"),
+            include_bytes!("../web/roms/WIPEOFF.ch8"),
+        ];
+        for rom in roms {
+            assert_eq!(&recompile(rom)[..], &rom[..]);
+        }
+    }
+
+    #[test]
+    fn it_recovers_structures() {
+        let source = "
+: main
+  loop
+    v0 := key
+    if v0 == 5 begin
+      v1 += 1
+    else
+      v1 -= v2
+    end
+    while v1 != 9
+    if v2 key then face
+  again
+: face
+  i := glyph
+  sprite v0 v1 2
+  return
+: glyph
+  0b11000011 0b00111100
+";
+        let program = compile("program.8o", source, Target::Chip8).unwrap().bytes;
+        let decompiled = decompile(&program);
+        let lines: Vec<&str> = decompiled.lines().map(str::trim).collect();
+        for line in &[
+            ": main",
+            "loop",
+            "v0 := key",
+            "if v0 == 5 begin",
+            "v1 += 1",
+            "else",
+            "v1 -= v2",
+            "end",
+            "while v1 != 9",
+            "if v2 key then",
+            "again",
+            ": sub_216",
+            "i := sprite_21C",
+            "sprite v0 v1 2",
+            ": sprite_21C",
+            "0b11000011",
+            "0b00111100",
+        ] {
+            assert!(
+                lines.contains(line),
+                "{} missing from\n{}",
+                line,
+                decompiled
+            );
+        }
+        assert_eq!(recompile(&program), program);
+    }
+
+    #[test]
+    fn it_keeps_data_and_overlapping_code() {
+        // a jump into the middle of an instruction, and an invalid opcode
+        let program = [0x12, 0x03, 0x60, 0x00, 0xE0, 0x12, 0x04, 0xFF, 0xFF];
+        assert_eq!(recompile(&program), program);
+        assert!(decompile(&[]).contains(": main"));
+    }
+
+    #[test]
+    fn it_decompiles_the_resolution_switches() {
+        // HIGH - LOW - JP 0x204
+        let program = [0x00, 0xFF, 0x00, 0xFE, 0x12, 0x04];
+        let source = decompile(&program);
+        assert!(source.contains("hires\n  lores\n"), "{}", source);
+        let compiled = compile("program.8o", &source, Target::SuperChip).unwrap();
+        assert_eq!(compiled.bytes, program);
+    }
+}
@@ -1,69 +1,444 @@
+use std::convert::TryFrom;
+use std::ops::{BitAnd, BitXorAssign};
+
+use wasm_bindgen::prelude::*;
+
+use super::png::{self, PngError};
 use super::DISPLAY_PIXEL_HEIGHT;
 use super::DISPLAY_PIXEL_WIDTH;
+use super::{HIRES_PIXEL_HEIGHT, HIRES_PIXEL_WIDTH};
+
+pub mod palette;
+pub mod persistence;
+pub mod recorder;
+
+pub use self::palette::{Palette, PalettePreset};
+pub use self::persistence::Persistence;
+pub use self::recorder::Recorder;
+
+pub const VRAM_SIZE: usize = DISPLAY_PIXEL_WIDTH * DISPLAY_PIXEL_HEIGHT;
+pub const RGBA_SIZE: usize = VRAM_SIZE * 4;
+
+// Bounding box of the pixels touched since the dirty state was last cleared.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
 
-const VRAM_SIZE: usize = DISPLAY_PIXEL_WIDTH * DISPLAY_PIXEL_HEIGHT;
+impl DirtyRect {
+    fn union(self, other: DirtyRect) -> DirtyRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        DirtyRect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+// Return the (start, length) span covered by `len` pixels drawn from `start`
+// on an axis of `size` pixels: a sprite wrapping around the edge dirties the
+// whole axis.
+fn dirty_span(start: usize, len: usize, size: usize) -> (usize, usize) {
+    if start + len > size {
+        (0, size)
+    } else {
+        (start, len)
+    }
+}
+
+// A display row packed in a single word, the leftmost pixel being the most
+// significant bit: u64 for the 64 pixels of a CHIP-8 row, u128 for the 128
+// pixels of a SUPER-CHIP high resolution row.
+trait Row: Copy + Eq + BitAnd<Output = Self> + BitXorAssign {
+    const BITS: usize;
+    const ZERO: Self;
+
+    // The `width` bits of a sprite row, drawn at column x: rotating wraps the
+    // pixels past the right edge around.
+    fn sprite(bits: u16, width: usize, x: usize) -> Self;
+
+    // Write the row to `line`, one byte per pixel.
+    fn unpack(self, line: &mut [u8]);
+}
+
+macro_rules! impl_row {
+    ($row:ty, $bits:expr) => {
+        impl Row for $row {
+            const BITS: usize = $bits;
+            const ZERO: Self = 0;
+
+            fn sprite(bits: u16, width: usize, x: usize) -> Self {
+                ((bits as $row) << ($bits - width)).rotate_right(x as u32)
+            }
+
+            fn unpack(self, line: &mut [u8]) {
+                for (i, pixel) in line.iter_mut().enumerate() {
+                    *pixel = (self >> ($bits - 1 - i) & 1) as u8;
+                }
+            }
+        }
+    };
+}
+
+impl_row!(u64, 64);
+impl_row!(u128, 128);
+
+// XOR the sprite rows, `width` pixels wide, onto the packed rows at (x, y),
+// then unpack the rows changed into the one byte per pixel copy. Return
+// whether a pixel was turned off.
+fn blit<R: Row>(
+    rows: &mut [R],
+    pixels: &mut [u8],
+    x: usize,
+    y: usize,
+    sprite: impl Iterator<Item = u16>,
+    width: usize,
+) -> bool {
+    let mut collision = false;
+    for (j, bits) in sprite.enumerate() {
+        let y = (y + j) % rows.len();
+        let sprite_row = R::sprite(bits, width, x);
+        // a pixel on both the screen and the sprite is a collision
+        collision |= rows[y] & sprite_row != R::ZERO;
+        // draw the new value with XOR
+        if sprite_row != R::ZERO {
+            rows[y] ^= sprite_row;
+            rows[y].unpack(&mut pixels[y * R::BITS..(y + 1) * R::BITS]);
+        }
+    }
+    collision
+}
 
 pub struct Display {
-    vram: [u8; VRAM_SIZE],
+    // CHIP-8 rows, shown in low resolution
+    vram: [u64; DISPLAY_PIXEL_HEIGHT],
+    // SUPER-CHIP rows, shown instead of `vram` in high resolution
+    hires_vram: [u128; HIRES_PIXEL_HEIGHT],
+    hires: bool,
+    // the shown rows unpacked to one byte per pixel, updated along with them
+    // so that frontends can read the display directly from memory
+    pixels: Vec<u8>,
+    palette: Palette,
+    // RGBA framebuffer, allocated only once a frontend asks for it
+    rgba: Vec<u8>,
+    // whether the RGBA framebuffer is out of date with the vram or the palette
+    rgba_stale: bool,
+    // area drawn since the last call to `clear_dirty`
+    dirty_rect: Option<DirtyRect>,
+    // optional post-processing applied to the RGBA output
+    persistence: Option<Persistence>,
+    // GIF recording in progress, if any
+    recorder: Option<Recorder>,
 }
 
 impl Display {
     pub fn new() -> Self {
-        Display { vram: [0; 2048] }
+        Display {
+            vram: [0; DISPLAY_PIXEL_HEIGHT],
+            hires_vram: [0; HIRES_PIXEL_HEIGHT],
+            hires: false,
+            pixels: vec![0; VRAM_SIZE],
+            palette: Palette::default(),
+            rgba: Vec::new(),
+            rgba_stale: true,
+            dirty_rect: None,
+            persistence: None,
+            recorder: None,
+        }
     }
 
+    #[cfg(test)]
     fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
-        let scaled = y * DISPLAY_PIXEL_WIDTH;
-        self.vram[x + scaled] = on as u8;
+        if self.is_pixel_on(x, y) != on {
+            let pixel = std::iter::once(1);
+            if self.hires {
+                blit(&mut self.hires_vram, &mut self.pixels, x, y, pixel, 1);
+            } else {
+                blit(&mut self.vram, &mut self.pixels, x, y, pixel, 1);
+            }
+            self.rgba_stale = true;
+        }
     }
 
-    fn is_pixel_on(&mut self, x: usize, y: usize) -> bool {
-        let scaled = y * DISPLAY_PIXEL_WIDTH;
-        self.vram[x + scaled] == 1
+    #[cfg(test)]
+    fn is_pixel_on(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * self.width() + x] == 1
     }
 
-    pub fn cls(&mut self) {
-        for x in 0..DISPLAY_PIXEL_WIDTH {
-            for y in 0..DISPLAY_PIXEL_HEIGHT {
-                self.set_pixel(x, y, false);
+    fn mark_dirty(&mut self, rect: DirtyRect) {
+        self.rgba_stale = true;
+        self.dirty_rect = Some(match self.dirty_rect {
+            Some(dirty_rect) => dirty_rect.union(rect),
+            None => rect,
+        });
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.mark_dirty(DirtyRect {
+            x: 0,
+            y: 0,
+            width: self.width(),
+            height: self.height(),
+        });
+    }
+
+    // Width in pixels of the current resolution.
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_PIXEL_WIDTH
+        } else {
+            DISPLAY_PIXEL_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_PIXEL_HEIGHT
+        } else {
+            DISPLAY_PIXEL_HEIGHT
+        }
+    }
+
+    // Whether the SUPER-CHIP 128x64 resolution is on.
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Switch between the 64x32 and the 128x64 resolutions. Like in Octo, the
+    // display is cleared.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        let width = self.width();
+        self.pixels = vec![0; width * self.height()];
+        if let Some(persistence) = &mut self.persistence {
+            persistence.resize(width, self.pixels.len());
+        }
+        self.cls();
+    }
+
+    // The packed rows of both resolutions, used by save states.
+    pub(crate) fn rows(&self) -> (&[u64; DISPLAY_PIXEL_HEIGHT], &[u128; HIRES_PIXEL_HEIGHT]) {
+        (&self.vram, &self.hires_vram)
+    }
+
+    pub(crate) fn set_rows(
+        &mut self,
+        hires: bool,
+        rows: [u64; DISPLAY_PIXEL_HEIGHT],
+        hires_rows: [u128; HIRES_PIXEL_HEIGHT],
+    ) {
+        self.set_hires(hires);
+        self.vram = rows;
+        self.hires_vram = hires_rows;
+        let width = self.width();
+        for (y, line) in self.pixels.chunks_exact_mut(width).enumerate() {
+            if hires {
+                hires_rows[y].unpack(line);
+            } else {
+                rows[y].unpack(line);
             }
         }
     }
 
+    pub fn cls(&mut self) {
+        self.vram = [0; DISPLAY_PIXEL_HEIGHT];
+        self.hires_vram = [0; HIRES_PIXEL_HEIGHT];
+        self.pixels.iter_mut().for_each(|pixel| *pixel = 0);
+        self.mark_all_dirty();
+    }
+
+    // Draw a sprite 8 pixels wide, one byte per row.
     pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
-        let rows = sprite.len();
-        let mut collision = false;
-        for j in 0..rows {
-            let row = sprite[j];
-            for i in 0..8 {
-                // check every single bit, starting from the most significant bit
-                let value = row >> (7 - i) & 0x01;
-                if value == 1 {
-                    // calculate the indexes in the memory
-                    let xi = (x + i) % DISPLAY_PIXEL_WIDTH;
-                    let yj = (y + j) % DISPLAY_PIXEL_HEIGHT;
-                    // get the value on the screen in order to detect collisions
-                    let value_screen_on = self.is_pixel_on(xi, yj);
-                    if value_screen_on {
-                        collision = true;
-                    }
-                    // draw the new value with XOR
-                    let is_on = (value == 1) ^ value_screen_on;
-                    self.set_pixel(xi, yj, is_on);
-                }
-            }
+        let rows = sprite.iter().map(|&byte| byte as u16);
+        self.draw_rows(x, y, rows, sprite.len(), 8)
+    }
+
+    // Draw a SUPER-CHIP sprite 16 pixels wide, two bytes per row.
+    pub fn draw_wide(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        let rows = sprite
+            .chunks_exact(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+        self.draw_rows(x, y, rows, sprite.len() / 2, 16)
+    }
+
+    fn draw_rows(
+        &mut self,
+        x: usize,
+        y: usize,
+        sprite: impl Iterator<Item = u16>,
+        len: usize,
+        width: usize,
+    ) -> bool {
+        let x = x % self.width();
+        let y = y % self.height();
+        let collision = if self.hires {
+            blit(&mut self.hires_vram, &mut self.pixels, x, y, sprite, width)
+        } else {
+            blit(&mut self.vram, &mut self.pixels, x, y, sprite, width)
+        };
+        if len > 0 {
+            let (x, width) = dirty_span(x, width, self.width());
+            let (y, height) = dirty_span(y, len.min(self.height()), self.height());
+            self.mark_dirty(DirtyRect {
+                x,
+                y,
+                width,
+                height,
+            });
         }
-        return collision;
+        collision
     }
 
     pub fn get_vram_copy(&self) -> Vec<u8> {
-        self.vram.to_vec()
+        self.pixels.clone()
+    }
+
+    // One byte per pixel, row major, 1 when the pixel is on.
+    pub fn vram(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // Whether anything was drawn since the last call to `clear_dirty`.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_rect.is_some()
+    }
+
+    pub fn dirty_rect(&self) -> Option<DirtyRect> {
+        self.dirty_rect
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty_rect = None;
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.rgba_stale = true;
+    }
+
+    // Encode the display as a PNG image with the active palette, every
+    // pixel being scaled to a `scale` x `scale` square.
+    pub fn to_png(&self, scale: usize) -> Result<Vec<u8>, PngError> {
+        let scale = scale.max(1);
+        let too_large = PngError::TooLarge {
+            width: self.width() as u64 * scale as u64,
+            height: self.height() as u64 * scale as u64,
+        };
+        let (width, height) = match (
+            self.width().checked_mul(scale),
+            self.height().checked_mul(scale),
+        ) {
+            (Some(width), Some(height)) if width.checked_mul(height).is_some() => (width, height),
+            _ => return Err(too_large),
+        };
+        let mut pixels = Vec::with_capacity(width * height);
+        for line in self.pixels.chunks_exact(self.width()) {
+            let line: Vec<u8> = (0..width).map(|x| line[x / scale]).collect();
+            for _ in 0..scale {
+                pixels.extend_from_slice(&line);
+            }
+        }
+        png::encode_indexed(
+            u32::try_from(width).map_err(|_| too_large)?,
+            u32::try_from(height).map_err(|_| too_large)?,
+            self.palette.colors(),
+            &pixels,
+        )
+    }
+
+    // Enable or disable the post-processing stage. It starts from the pixels
+    // shown, not from a dark screen.
+    pub fn set_persistence(&mut self, persistence: Option<Persistence>) {
+        self.persistence = persistence;
+        let width = self.width();
+        if let Some(persistence) = &mut self.persistence {
+            persistence.seed(width, &self.pixels);
+        }
+        self.rgba_stale = true;
+    }
+
+    // Signal the end of an emulated frame to the post-processing stage
+    // and to the recorder. This is the only place the persistence advances.
+    pub fn end_frame(&mut self) {
+        let width = self.width();
+        if let Some(persistence) = &mut self.persistence {
+            persistence.push_frame(width, &self.pixels);
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.capture(width, &self.pixels);
+        }
+    }
+
+    // Start recording the frames to an animated GIF; a recording already in
+    // progress is discarded.
+    pub fn start_recording(&mut self, scale: usize) {
+        self.recorder = Some(Recorder::new(scale));
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Stop the recording and encode it with the active palette.
+    pub fn stop_recording(&mut self) -> Option<Vec<u8>> {
+        self.recorder
+            .take()
+            .map(|recorder| recorder.encode(&self.palette))
+    }
+
+    // Whether the post-processing output keeps changing while nothing is drawn.
+    pub fn is_fading(&self) -> bool {
+        match &self.persistence {
+            Some(persistence) => persistence.changed(),
+            None => false,
+        }
+    }
+
+    // Convert the vram into RGBA pixels using the active palette.
+    // The returned slice is owned by the display and reused across frames,
+    // so in wasm it can be wrapped by an `ImageData` without copying.
+    // The conversion is skipped when nothing changed since the last call.
+    pub fn render_rgba(&mut self) -> &[u8] {
+        if let Some(persistence) = &mut self.persistence {
+            return persistence.render_rgba(&self.palette);
+        }
+        if self.rgba.len() != self.pixels.len() * 4 {
+            self.rgba = vec![0; self.pixels.len() * 4];
+            self.rgba_stale = true;
+        }
+        if self.rgba_stale {
+            for (pixel, rgba) in self.pixels.iter().zip(self.rgba.chunks_exact_mut(4)) {
+                rgba.copy_from_slice(&self.palette.color(*pixel));
+            }
+            self.rgba_stale = false;
+        }
+        &self.rgba
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
-    use super::Display;
+    use super::{DirtyRect, Display, Palette, Persistence};
 
     #[test]
     fn set_pixel() {
@@ -121,4 +496,211 @@ mod tests {
         collision = display.draw(0, 0, &sprite);
         assert_eq!(true, collision);
     }
+
+    #[test]
+    fn render_rgba_uses_palette() {
+        let mut display = Display::new();
+        display.set_palette(Palette::new(0x000000, 0xFF8000));
+        display.set_pixel(1, 0, true);
+
+        let rgba = display.render_rgba();
+        assert_eq!(rgba.len(), 64 * 32 * 4);
+        assert_eq!(&rgba[0..4], &[0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(&rgba[4..8], &[0xFF, 0x80, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn render_rgba_follows_palette_changes() {
+        let mut display = Display::new();
+        display.set_pixel(0, 0, true);
+        display.render_rgba();
+
+        display.set_palette(Palette::new(0x112233, 0x445566));
+        assert_eq!(&display.render_rgba()[0..4], &[0x44, 0x55, 0x66, 0xFF]);
+    }
+
+    #[test]
+    fn display_starts_clean() {
+        let display = Display::new();
+        assert!(!display.is_dirty());
+        assert_eq!(display.dirty_rect(), None);
+    }
+
+    #[test]
+    fn draw_marks_the_sprite_area_dirty() {
+        let mut display = Display::new();
+        display.draw(10, 4, &[0xFF, 0xFF, 0xFF]);
+        display.draw(20, 2, &[0x80]);
+
+        assert!(display.is_dirty());
+        assert_eq!(
+            display.dirty_rect(),
+            Some(DirtyRect {
+                x: 10,
+                y: 2,
+                width: 18,
+                height: 5
+            })
+        );
+
+        display.clear_dirty();
+        assert!(!display.is_dirty());
+    }
+
+    #[test]
+    fn draw_wrapping_around_dirties_the_whole_axis() {
+        let mut display = Display::new();
+        display.draw(60, 0, &[0xFF]);
+
+        assert_eq!(
+            display.dirty_rect(),
+            Some(DirtyRect {
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 1
+            })
+        );
+    }
+
+    #[test]
+    fn cls_dirties_the_whole_screen() {
+        let mut display = Display::new();
+        display.cls();
+
+        assert_eq!(
+            display.dirty_rect(),
+            Some(DirtyRect {
+                x: 0,
+                y: 0,
+                width: 64,
+                height: 32
+            })
+        );
+    }
+
+    #[test]
+    fn draw_wraps_around_the_edges() {
+        let mut display = Display::new();
+        let collision = display.draw(62, 31, &[0b11110000, 0b10000001]);
+        assert!(!collision);
+
+        assert!(display.is_pixel_on(62, 31));
+        assert!(display.is_pixel_on(63, 31));
+        assert!(display.is_pixel_on(0, 31));
+        assert!(display.is_pixel_on(1, 31));
+        assert!(!display.is_pixel_on(2, 31));
+
+        assert!(display.is_pixel_on(62, 0));
+        assert!(!display.is_pixel_on(63, 0));
+        assert!(display.is_pixel_on(5, 0));
+    }
+
+    #[test]
+    fn draw_same_sprite_twice_erases_it() {
+        let mut display = Display::new();
+        let sprite = [0xF0, 0x90, 0x90, 0x90, 0xF0];
+        assert!(!display.draw(3, 7, &sprite));
+        assert!(display.draw(3, 7, &sprite));
+        assert_eq!(display.get_vram_copy(), vec![0; 64 * 32]);
+    }
+
+    #[test]
+    fn vram_unpacks_one_byte_per_pixel() {
+        let mut display = Display::new();
+        display.draw(0, 1, &[0b10100000]);
+
+        let vram = display.vram();
+        assert_eq!(vram.len(), 64 * 32);
+        assert_eq!(&vram[64..68], &[1, 0, 1, 0]);
+        assert_eq!(vram.iter().filter(|&&pixel| pixel == 1).count(), 2);
+    }
+
+    #[test]
+    fn high_resolution_draws_wide_sprites_on_128_bit_rows() {
+        let mut display = Display::new();
+        display.set_hires(true);
+        assert_eq!((display.width(), display.height()), (128, 64));
+        assert_eq!(display.vram().len(), 128 * 64);
+
+        let sprite = [0x80, 0x01, 0xFF, 0xFF];
+        assert!(!display.draw_wide(120, 63, &sprite));
+        assert!(display.is_pixel_on(120, 63));
+        assert!(display.is_pixel_on(7, 63));
+        assert!(!display.is_pixel_on(8, 63));
+        assert!(display.is_pixel_on(0, 0));
+        assert_eq!(
+            display.vram().iter().filter(|&&pixel| pixel == 1).count(),
+            18
+        );
+        assert_eq!(display.rows().1[63], 1 << 7 | 1 << 120);
+
+        assert!(display.draw(127, 0, &[0x80]));
+        assert!(!display.is_pixel_on(127, 0));
+    }
+
+    #[test]
+    fn switching_the_resolution_clears_the_display() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xFF]);
+        display.set_hires(true);
+        assert!(display.vram().iter().all(|&pixel| pixel == 0));
+        display.draw(0, 0, &[0xFF]);
+        display.set_hires(false);
+        assert_eq!(display.vram(), &[0; 64 * 32][..]);
+        assert_eq!(display.rows().1, &[0; 64]);
+    }
+
+    #[test]
+    fn render_rgba_applies_persistence() {
+        let mut display = Display::new();
+        display.set_palette(Palette::new(0x000000, 0xFFFFFF));
+        display.set_persistence(Some(Persistence::fade(0.5)));
+
+        display.draw(0, 0, &[0x80]);
+        display.end_frame();
+        display.draw(0, 0, &[0x80]);
+        display.end_frame();
+
+        assert!(!display.is_pixel_on(0, 0));
+        assert!(display.is_fading());
+        assert_eq!(&display.render_rgba()[0..4], &[0x80, 0x80, 0x80, 0xFF]);
+    }
+
+    #[test]
+    fn persistence_starts_from_the_pixels_shown() {
+        let mut display = Display::new();
+        display.set_palette(Palette::new(0x000000, 0xFFFFFF));
+        display.draw(0, 0, &[0x80]);
+        display.set_persistence(Some(Persistence::fade(0.5)));
+
+        assert_eq!(&display.render_rgba()[0..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn to_png_scales_the_display() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0x80]);
+        let png = display.to_png(4).unwrap();
+
+        assert_eq!(&png[1..4], b"PNG");
+        // width and height in the IHDR chunk
+        assert_eq!(&png[16..24], &[0, 0, 1, 0, 0, 0, 0, 128]);
+    }
+
+    #[test]
+    fn recording_captures_frames_until_stopped() {
+        let mut display = Display::new();
+        assert_eq!(display.stop_recording(), None);
+
+        display.start_recording(1);
+        assert!(display.is_recording());
+        display.end_frame();
+        display.draw(0, 0, &[0xFF]);
+        display.end_frame();
+
+        let gif = display.stop_recording().unwrap();
+        assert_eq!(&gif[0..6], b"GIF89a");
+        assert!(!display.is_recording());
+    }
 }
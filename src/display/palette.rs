@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+use wasm_bindgen::prelude::*;
+
+// Colors are stored as RGBA quadruplets so a pixel can be copied straight
+// into an `ImageData` buffer.
+type Rgba = [u8; 4];
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PalettePreset {
+    // green phosphor on black, the original look of the web frontend
+    Classic,
+    // amber monochrome monitor
+    Amber,
+    // greenish reflective LCD, like the HP-48 calculators
+    Lcd,
+    // pure white on pure black
+    HighContrast,
+}
+
+impl FromStr for PalettePreset {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_ref() {
+            "classic" => Ok(PalettePreset::Classic),
+            "amber" => Ok(PalettePreset::Amber),
+            "lcd" => Ok(PalettePreset::Lcd),
+            "high-contrast" => Ok(PalettePreset::HighContrast),
+            _ => Err(format!("unknown palette: {}", name)),
+        }
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    // index 0 is the background, index 1 the foreground.
+    // XO-CHIP uses all the four entries: one per combination of the two planes.
+    colors: [Rgba; 4],
+}
+
+fn rgb_to_rgba(rgb: u32) -> Rgba {
+    [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 0xFF]
+}
+
+#[wasm_bindgen]
+impl Palette {
+    // Build a two colors palette from 0xRRGGBB values.
+    // The XO-CHIP entries default to the foreground color.
+    pub fn new(background: u32, foreground: u32) -> Palette {
+        Palette::xo_chip(background, foreground, foreground, foreground)
+    }
+
+    // Build a four colors XO-CHIP palette from 0xRRGGBB values.
+    pub fn xo_chip(background: u32, plane_1: u32, plane_2: u32, both_planes: u32) -> Palette {
+        Palette {
+            colors: [
+                rgb_to_rgba(background),
+                rgb_to_rgba(plane_1),
+                rgb_to_rgba(plane_2),
+                rgb_to_rgba(both_planes),
+            ],
+        }
+    }
+
+    pub fn preset(preset: PalettePreset) -> Palette {
+        match preset {
+            PalettePreset::Classic => Palette::xo_chip(0x000000, 0x33FF66, 0x1A8033, 0xCCFFD9),
+            PalettePreset::Amber => Palette::xo_chip(0x1A0F00, 0xFFB000, 0x805800, 0xFFE0A0),
+            PalettePreset::Lcd => Palette::xo_chip(0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F),
+            PalettePreset::HighContrast => Palette::xo_chip(0x000000, 0xFFFFFF, 0xFF0000, 0xFFFF00),
+        }
+    }
+}
+
+impl Palette {
+    // Return the RGBA color of a pixel given its planes bitmask.
+    pub fn color(&self, planes: u8) -> Rgba {
+        self.colors[(planes & 0x3) as usize]
+    }
+
+    pub fn colors(&self) -> &[Rgba; 4] {
+        &self.colors
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::preset(PalettePreset::Classic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_converts_rgb_to_rgba() {
+        let palette = Palette::new(0x102030, 0x405060);
+        assert_eq!(palette.color(0), [0x10, 0x20, 0x30, 0xFF]);
+        assert_eq!(palette.color(1), [0x40, 0x50, 0x60, 0xFF]);
+    }
+
+    #[test]
+    fn two_colors_palette_uses_foreground_for_all_planes() {
+        let palette = Palette::new(0x000000, 0xABCDEF);
+        assert_eq!(palette.color(2), palette.color(1));
+        assert_eq!(palette.color(3), palette.color(1));
+    }
+
+    #[test]
+    fn xo_chip_palette_has_four_colors() {
+        let palette = Palette::xo_chip(0x000000, 0x111111, 0x222222, 0x333333);
+        assert_eq!(palette.color(2), [0x22, 0x22, 0x22, 0xFF]);
+        assert_eq!(palette.color(3), [0x33, 0x33, 0x33, 0xFF]);
+    }
+
+    #[test]
+    fn it_parses_preset_names() {
+        assert_eq!("amber".parse(), Ok(PalettePreset::Amber));
+        assert_eq!("High-Contrast".parse(), Ok(PalettePreset::HighContrast));
+        assert!("sepia".parse::<PalettePreset>().is_err());
+    }
+
+    #[test]
+    fn default_palette_is_classic() {
+        assert_eq!(Palette::default(), Palette::preset(PalettePreset::Classic));
+        assert_eq!(Palette::default().color(1), [0x33, 0xFF, 0x66, 0xFF]);
+    }
+}
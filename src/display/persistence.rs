@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+use super::{Palette, DISPLAY_PIXEL_WIDTH, VRAM_SIZE};
+
+// intensities below this value are not visible once quantized to 8 bits
+const MIN_INTENSITY: f32 = 1.0 / 255.0;
+
+enum Mode {
+    // every pixel lights up at full intensity and fades out by `decay`
+    // (the fraction of intensity kept) at each frame
+    Fade { decay: f32 },
+    // a pixel is on if it was on in any of the last `frames` frames
+    Blend { frames: usize },
+}
+
+// Post-processing stage that reduces the flickering of sprites erased and
+// redrawn with XOR. It only reads the frames produced by the display, so it
+// never affects the emulation.
+#[wasm_bindgen]
+pub struct Persistence {
+    mode: Mode,
+    // width of the frames, which changes with the resolution
+    width: usize,
+    // intensity of every pixel, from 0.0 (off) to 1.0 (fully lit)
+    intensity: Vec<f32>,
+    // last frames, one byte per pixel, used by the blend mode
+    history: VecDeque<Vec<u8>>,
+    // whether the last frame changed the intensity of any pixel
+    changed: bool,
+    rgba: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Persistence {
+    // Phosphor fade: `decay` is the fraction of intensity a pixel keeps at
+    // every frame after being turned off, between 0.0 (no persistence)
+    // and 1.0 (pixels never fade).
+    pub fn fade(decay: f32) -> Persistence {
+        Persistence::with_mode(Mode::Fade {
+            decay: decay.clamp(0.0, 1.0),
+        })
+    }
+
+    // Show the union of the last `frames` frames.
+    pub fn blend(frames: usize) -> Persistence {
+        Persistence::with_mode(Mode::Blend {
+            frames: frames.max(1),
+        })
+    }
+}
+
+impl Persistence {
+    fn with_mode(mode: Mode) -> Persistence {
+        Persistence {
+            mode,
+            width: DISPLAY_PIXEL_WIDTH,
+            intensity: vec![0.0; VRAM_SIZE],
+            history: VecDeque::new(),
+            changed: true,
+            rgba: Vec::new(),
+        }
+    }
+
+    // Feed the frame displayed at the end of an emulated frame, one byte per
+    // pixel and `width` pixels per row. A change of resolution starts over
+    // from a dark screen.
+    pub fn push_frame(&mut self, width: usize, pixels: &[u8]) {
+        self.resize(width, pixels.len());
+        if let Mode::Blend { frames } = self.mode {
+            self.history.push_front(pixels.to_vec());
+            self.history.truncate(frames);
+        }
+
+        let mut changed = false;
+        for (idx, (&pixel, intensity)) in pixels.iter().zip(self.intensity.iter_mut()).enumerate() {
+            let next = match self.mode {
+                _ if pixel == 1 => 1.0,
+                Mode::Fade { decay } if *intensity * decay >= MIN_INTENSITY => *intensity * decay,
+                Mode::Fade { .. } => 0.0,
+                // union of the frames kept in the history
+                Mode::Blend { .. } => {
+                    let blended = self.history.iter().any(|frame| frame[idx] == 1);
+                    blended as u8 as f32
+                }
+            };
+            changed |= next != *intensity;
+            *intensity = next;
+        }
+        self.changed = changed;
+    }
+
+    // Start from the frame shown, one byte per pixel, as if it had been on
+    // for a while: enabling the stage mid-game keeps the display lit.
+    pub(crate) fn seed(&mut self, width: usize, pixels: &[u8]) {
+        self.width = width;
+        self.intensity = pixels.iter().map(|&pixel| pixel as f32).collect();
+        self.history.clear();
+        if let Mode::Blend { .. } = self.mode {
+            self.history.push_front(pixels.to_vec());
+        }
+        self.changed = true;
+    }
+
+    // Start over from a dark screen of `len` pixels when the resolution
+    // changes.
+    pub(crate) fn resize(&mut self, width: usize, len: usize) {
+        if width != self.width || len != self.intensity.len() {
+            self.width = width;
+            self.intensity = vec![0.0; len];
+            self.history.clear();
+            self.changed = true;
+        }
+    }
+
+    // Whether the last frame changed the output, even if nothing was drawn.
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn intensity(&self, x: usize, y: usize) -> f32 {
+        self.intensity[y * self.width + x]
+    }
+
+    // One byte per pixel, from 0 (off) to 255 (fully lit).
+    pub fn grayscale(&self) -> Vec<u8> {
+        self.intensity
+            .iter()
+            .map(|intensity| (intensity * 255.0).round() as u8)
+            .collect()
+    }
+
+    // Blend the background and the foreground colors of the palette by the
+    // intensity of every pixel.
+    pub fn render_rgba(&mut self, palette: &Palette) -> &[u8] {
+        if self.rgba.len() != self.intensity.len() * 4 {
+            self.rgba = vec![0; self.intensity.len() * 4];
+        }
+        let background = palette.color(0);
+        let foreground = palette.color(1);
+        for (intensity, rgba) in self.intensity.iter().zip(self.rgba.chunks_exact_mut(4)) {
+            for c in 0..4 {
+                let from = background[c] as f32;
+                let to = foreground[c] as f32;
+                rgba[c] = (from + (to - from) * intensity).round() as u8;
+            }
+        }
+        &self.rgba
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_with_pixel(x: usize, y: usize) -> Vec<u8> {
+        let mut pixels = vec![0; VRAM_SIZE];
+        pixels[y * DISPLAY_PIXEL_WIDTH + x] = 1;
+        pixels
+    }
+
+    #[test]
+    fn fade_decays_intensity_over_frames() {
+        let mut persistence = Persistence::fade(0.5);
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &frame_with_pixel(3, 2));
+        assert_eq!(persistence.intensity(3, 2), 1.0);
+
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        assert_eq!(persistence.intensity(3, 2), 0.5);
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        assert_eq!(persistence.intensity(3, 2), 0.25);
+        assert!(persistence.changed());
+    }
+
+    #[test]
+    fn fade_settles_to_zero() {
+        let mut persistence = Persistence::fade(0.5);
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &frame_with_pixel(0, 0));
+        for _ in 0..10 {
+            persistence.push_frame(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        }
+        assert_eq!(persistence.intensity(0, 0), 0.0);
+        assert!(!persistence.changed());
+    }
+
+    #[test]
+    fn blend_ors_the_last_frames() {
+        let mut persistence = Persistence::blend(2);
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &frame_with_pixel(1, 1));
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &frame_with_pixel(2, 1));
+        assert_eq!(persistence.intensity(1, 1), 1.0);
+        assert_eq!(persistence.intensity(2, 1), 1.0);
+
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        assert_eq!(persistence.intensity(1, 1), 0.0);
+        assert_eq!(persistence.intensity(2, 1), 1.0);
+    }
+
+    #[test]
+    fn seeding_lights_the_pixels_shown() {
+        let mut persistence = Persistence::fade(0.5);
+        persistence.seed(DISPLAY_PIXEL_WIDTH, &frame_with_pixel(4, 1));
+        assert_eq!(persistence.intensity(4, 1), 1.0);
+        assert_eq!(persistence.intensity(0, 0), 0.0);
+
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        assert_eq!(persistence.intensity(4, 1), 0.5);
+    }
+
+    #[test]
+    fn render_rgba_blends_palette_colors() {
+        let mut persistence = Persistence::fade(0.5);
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &frame_with_pixel(0, 0));
+        persistence.push_frame(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+
+        let palette = Palette::new(0x000000, 0xFF8040);
+        let rgba = persistence.render_rgba(&palette);
+        assert_eq!(&rgba[0..4], &[0x80, 0x40, 0x20, 0xFF]);
+        assert_eq!(&rgba[4..8], &[0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(persistence.grayscale()[0], 128);
+    }
+}
@@ -0,0 +1,151 @@
+use super::{Palette, DISPLAY_PIXEL_HEIGHT, DISPLAY_PIXEL_WIDTH};
+use crate::gif::GifEncoder;
+
+// The emulator runs at 60 frames per second, GIF delays are expressed in
+// hundredths of a second.
+const FRAMES_PER_SECOND: u64 = 60;
+const GIF_TICKS_PER_SECOND: u64 = 100;
+// browsers replace shorter delays with a slow default
+const MIN_DELAY: u64 = 2;
+// GIF dimensions are 16 bits: the largest scale of a 128 pixel wide frame
+const MAX_SCALE: usize = u16::MAX as usize / 128;
+
+// Collects the frames shown by the display to encode them as an animated GIF.
+// Identical consecutive frames are stored once, with their width and the
+// number of emulated frames they lasted.
+pub struct Recorder {
+    scale: usize,
+    frames: Vec<(usize, Vec<u8>, u64)>,
+}
+
+// Time in GIF ticks at which the frame number `frame` starts. Rounding the
+// absolute time instead of every single delay keeps the animation in sync.
+fn gif_ticks(frame: u64) -> u64 {
+    (frame * GIF_TICKS_PER_SECOND + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND
+}
+
+impl Recorder {
+    pub fn new(scale: usize) -> Recorder {
+        Recorder {
+            scale: scale.clamp(1, MAX_SCALE),
+            frames: Vec::new(),
+        }
+    }
+
+    // Add a frame of one byte per pixel, `width` pixels per row.
+    pub fn capture(&mut self, width: usize, pixels: &[u8]) {
+        match self.frames.last_mut() {
+            Some((last_width, last, duration)) if *last_width == width && last == pixels => {
+                *duration += 1
+            }
+            _ => self.frames.push((width, pixels.to_vec(), 1)),
+        }
+    }
+
+    // Number of distinct frames recorded so far.
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    // The GIF is as wide as the widest frame: frames recorded in low
+    // resolution are scaled up to match the high resolution ones. A frame
+    // ending less than MIN_DELAY after the previous GIF frame is merged into
+    // the next one, so that the delays still add up to the elapsed time.
+    pub fn encode(&self, palette: &Palette) -> Vec<u8> {
+        let widest = self
+            .frames
+            .iter()
+            .map(|(width, _, _)| *width)
+            .max()
+            .unwrap_or(DISPLAY_PIXEL_WIDTH);
+        let width = widest * self.scale;
+        let height = widest * DISPLAY_PIXEL_HEIGHT / DISPLAY_PIXEL_WIDTH * self.scale;
+        let mut encoder = GifEncoder::new(width as u16, height as u16, palette.colors());
+
+        let (mut elapsed, mut shown) = (0, 0);
+        let mut pixels = Vec::with_capacity(width * height);
+        for (index, (frame_width, frame, duration)) in self.frames.iter().enumerate() {
+            elapsed += duration;
+            let delay = gif_ticks(elapsed) - shown;
+            if delay < MIN_DELAY && index + 1 < self.frames.len() {
+                continue;
+            }
+            shown += delay;
+            let scale = width / frame_width;
+            pixels.clear();
+            for line in frame.chunks_exact(*frame_width) {
+                for _ in 0..scale {
+                    pixels.extend((0..width).map(|x| line[x / scale]));
+                }
+            }
+            encoder.add_frame(&pixels, delay.min(u16::MAX as u64) as u16);
+        }
+        encoder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::VRAM_SIZE;
+
+    #[test]
+    fn it_deduplicates_consecutive_frames() {
+        let mut recorder = Recorder::new(1);
+        let mut pixels = [0; VRAM_SIZE];
+        recorder.capture(DISPLAY_PIXEL_WIDTH, &pixels);
+        recorder.capture(DISPLAY_PIXEL_WIDTH, &pixels);
+        pixels[0] = 1;
+        recorder.capture(DISPLAY_PIXEL_WIDTH, &pixels);
+        recorder.capture(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+
+        assert_eq!(recorder.frame_count(), 3);
+        assert_eq!(recorder.frames[0].2, 2);
+    }
+
+    #[test]
+    fn delays_add_up_to_the_elapsed_time() {
+        assert_eq!(gif_ticks(60), 100);
+        let mut recorder = Recorder::new(1);
+        let mut pixels = [0; VRAM_SIZE];
+        // six frames of 1/60 s each, then one of 1/2 s
+        for frame in 0..7 {
+            pixels[0] = frame as u8 % 2;
+            for _ in 0..if frame == 6 { 30 } else { 1 } {
+                recorder.capture(DISPLAY_PIXEL_WIDTH, &pixels);
+            }
+        }
+        let gif = recorder.encode(&Palette::default());
+
+        // graphic control extensions: introducer, label, size, flags, delay
+        let delays: Vec<u16> = gif
+            .windows(6)
+            .filter(|w| w[0..3] == [0x21, 0xF9, 0x04])
+            .map(|w| u16::from_le_bytes([w[4], w[5]]))
+            .collect();
+        // the frames shorter than 2 hundredths are merged into the next ones
+        assert_eq!(delays, vec![2, 3, 2, 3, 50]);
+        assert_eq!(delays.iter().sum::<u16>(), 60);
+    }
+
+    #[test]
+    fn it_encodes_a_scaled_gif() {
+        let mut recorder = Recorder::new(2);
+        recorder.capture(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        let gif = recorder.encode(&Palette::default());
+
+        assert_eq!(&gif[0..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[128, 0, 64, 0]);
+    }
+
+    #[test]
+    fn low_resolution_frames_are_scaled_to_the_high_resolution() {
+        let mut recorder = Recorder::new(1);
+        recorder.capture(DISPLAY_PIXEL_WIDTH, &[0; VRAM_SIZE]);
+        recorder.capture(DISPLAY_PIXEL_WIDTH * 2, &[0; VRAM_SIZE * 4]);
+        let gif = recorder.encode(&Palette::default());
+
+        assert_eq!(recorder.frame_count(), 2);
+        assert_eq!(&gif[6..10], &[128, 0, 64, 0]);
+    }
+}
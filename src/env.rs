@@ -0,0 +1,345 @@
+// A gym-style environment around the cpu, for reinforcement learning. An
+// agent picks the keys held for the next frames, and gets back the display,
+// a reward and whether the episode is over.
+//
+// Rewards and episode ends are read from the memory or the registers by
+// extractors, which depend on the ROM: UFO keeps its score in V7 and its
+// missiles left in V8, for instance.
+//
+//     let mut env = Env::new(&rom)
+//         .with_reward(Delta::new(Location::Register(7), 1.0))
+//         .with_termination(Equals::new(Location::Register(8), 0))
+//         .with_frame_skip(4);
+//     let observation = env.reset(42);
+//     let step = env.step(1 << 5);
+
+use crate::cartridge::Cartridge;
+use crate::cheats::Location;
+use crate::cpu::Cpu;
+
+// The reward of the last frames, read from the cpu after them.
+pub trait Reward: Send {
+    // Called after every reset, before the first step.
+    fn reset(&mut self, _cpu: &Cpu) {}
+
+    fn reward(&mut self, cpu: &Cpu) -> f64;
+}
+
+// Whether an episode is over, read from the cpu after every step.
+pub trait Termination: Send {
+    // Called after every reset, before the first step.
+    fn reset(&mut self, _cpu: &Cpu) {}
+
+    fn done(&mut self, cpu: &Cpu) -> bool;
+}
+
+impl<F> Reward for F
+where
+    F: FnMut(&Cpu) -> f64 + Send,
+{
+    fn reward(&mut self, cpu: &Cpu) -> f64 {
+        self(cpu)
+    }
+}
+
+impl<F> Termination for F
+where
+    F: FnMut(&Cpu) -> bool + Send,
+{
+    fn done(&mut self, cpu: &Cpu) -> bool {
+        self(cpu)
+    }
+}
+
+// The change of a byte since the last step, like a score going up, times
+// `scale`. A negative scale turns a lives counter into a penalty.
+pub struct Delta {
+    location: Location,
+    scale: f64,
+    previous: u8,
+}
+
+impl Delta {
+    pub fn new(location: Location, scale: f64) -> Self {
+        Delta {
+            location,
+            scale,
+            previous: 0,
+        }
+    }
+}
+
+impl Reward for Delta {
+    fn reset(&mut self, cpu: &Cpu) {
+        self.previous = cpu.read_location(self.location);
+    }
+
+    fn reward(&mut self, cpu: &Cpu) -> f64 {
+        let current = cpu.read_location(self.location);
+        let delta = current as f64 - self.previous as f64;
+        self.previous = current;
+        delta * self.scale
+    }
+}
+
+// The end of an episode when a byte reaches a value, like no lives left.
+pub struct Equals {
+    location: Location,
+    value: u8,
+}
+
+impl Equals {
+    pub fn new(location: Location, value: u8) -> Self {
+        Equals { location, value }
+    }
+}
+
+impl Termination for Equals {
+    fn done(&mut self, cpu: &Cpu) -> bool {
+        cpu.read_location(self.location) == self.value
+    }
+}
+
+// The end of an episode when a byte goes down, like a life lost.
+pub struct Decreases {
+    location: Location,
+    previous: u8,
+}
+
+impl Decreases {
+    pub fn new(location: Location) -> Self {
+        Decreases {
+            location,
+            previous: 0,
+        }
+    }
+}
+
+impl Termination for Decreases {
+    fn reset(&mut self, cpu: &Cpu) {
+        self.previous = cpu.read_location(self.location);
+    }
+
+    fn done(&mut self, cpu: &Cpu) -> bool {
+        let current = cpu.read_location(self.location);
+        let decreased = current < self.previous;
+        self.previous = current;
+        decreased
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    // one byte per pixel, 1 when the pixel is on, or empty without render
+    pub observation: Vec<u8>,
+    pub reward: f64,
+    pub done: bool,
+}
+
+pub struct Env {
+    cpu: Cpu,
+    program: Vec<u8>,
+    cycles_per_frame: u32,
+    // frames run by every step, holding the same keys
+    frame_skip: u32,
+    // episodes longer than that are cut, when set
+    max_frames: Option<u64>,
+    render: bool,
+    reward: Box<dyn Reward>,
+    termination: Box<dyn Termination>,
+    // frames run since the last reset
+    frame: u64,
+    done: bool,
+}
+
+impl Env {
+    // An environment running `program` for one frame of 10 instructions a
+    // step, with no reward and no end but faults.
+    pub fn new(program: &[u8]) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(program));
+        Env {
+            cpu,
+            program: program.to_vec(),
+            cycles_per_frame: 10,
+            frame_skip: 1,
+            max_frames: None,
+            render: true,
+            reward: Box::new(|_: &Cpu| 0.0),
+            termination: Box::new(|_: &Cpu| false),
+            frame: 0,
+            done: false,
+        }
+    }
+
+    pub fn with_reward(mut self, reward: impl Reward + 'static) -> Self {
+        self.reward = Box::new(reward);
+        self
+    }
+
+    pub fn with_termination(mut self, termination: impl Termination + 'static) -> Self {
+        self.termination = Box::new(termination);
+        self
+    }
+
+    pub fn with_cycles_per_frame(mut self, cycles: u32) -> Self {
+        self.cycles_per_frame = cycles;
+        self
+    }
+
+    // Run `frames` frames per step, at least one.
+    pub fn with_frame_skip(mut self, frames: u32) -> Self {
+        self.frame_skip = frames.max(1);
+        self
+    }
+
+    pub fn with_max_frames(mut self, frames: u64) -> Self {
+        self.max_frames = Some(frames);
+        self
+    }
+
+    // Leave the observations empty, for agents reading the memory only.
+    pub fn without_render(mut self) -> Self {
+        self.render = false;
+        self
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    // Frames run since the last reset.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Restart the program with a new seed, returning the first observation.
+    pub fn reset(&mut self, seed: u32) -> Vec<u8> {
+        self.cpu.reset();
+        self.cpu.set_seed(seed);
+        self.cpu.set_keypad_state(0);
+        self.cpu.load_cartridge(Cartridge::new(&self.program));
+        self.reward.reset(&self.cpu);
+        self.termination.reset(&self.cpu);
+        self.frame = 0;
+        self.done = false;
+        self.observation()
+    }
+
+    // Hold the keys whose bit is set, key 0 being the lowest bit, for the
+    // next frames. Once an episode is over, steps do nothing until a reset.
+    pub fn step(&mut self, action: u16) -> Step {
+        if self.done {
+            return Step {
+                observation: self.observation(),
+                reward: 0.0,
+                done: true,
+            };
+        }
+        self.cpu.set_keypad_state(action);
+        for _ in 0..self.frame_skip {
+            self.cpu.run_frame(self.cycles_per_frame);
+            self.frame += 1;
+        }
+        let reward = self.reward.reward(&self.cpu);
+        let cut = matches!(self.max_frames, Some(max) if self.frame >= max);
+        self.done = self.termination.done(&self.cpu) || cut || self.cpu.fault().is_some();
+        Step {
+            observation: self.observation(),
+            reward,
+            done: self.done,
+        }
+    }
+
+    fn observation(&self) -> Vec<u8> {
+        if self.render {
+            self.cpu.vram().to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DISPLAY_PIXEL_HEIGHT, DISPLAY_PIXEL_WIDTH};
+
+    const UFO: &[u8] = include_bytes!("../web/roms/UFO.ch8");
+
+    fn ufo() -> Env {
+        Env::new(UFO)
+            .with_reward(Delta::new(Location::Register(7), 1.0))
+            .with_termination(Equals::new(Location::Register(8), 0))
+            .with_frame_skip(4)
+    }
+
+    // Fire up every other step until the missiles run out.
+    fn play(env: &mut Env, seed: u32) -> (f64, u64) {
+        env.reset(seed);
+        let mut total = 0.0;
+        for n in 0.. {
+            let step = env.step(if n % 2 == 0 { 1 << 5 } else { 0 });
+            total += step.reward;
+            if step.done {
+                break;
+            }
+        }
+        (total, env.frame())
+    }
+
+    #[test]
+    fn episodes_end_when_the_missiles_run_out() {
+        let mut env = ufo();
+        let observation = env.reset(1);
+        assert_eq!(
+            observation,
+            vec![0; DISPLAY_PIXEL_WIDTH * DISPLAY_PIXEL_HEIGHT]
+        );
+        let observation = env.step(0).observation;
+        assert!(observation.contains(&1));
+        assert_eq!(observation, env.cpu().state().display);
+
+        let (score, frames) = play(&mut env, 1);
+        assert_eq!(score, env.cpu().state().v[7] as f64);
+        assert_eq!(frames % 4, 0);
+        assert!(env.step(1 << 5).done);
+        assert_eq!(env.frame(), frames);
+    }
+
+    #[test]
+    fn episodes_depend_on_the_seed_only() {
+        let mut env = ufo().without_render();
+        let mut run = |seed| {
+            let result = play(&mut env, seed);
+            (result, env.cpu().state())
+        };
+        let first = run(7);
+        assert_eq!(run(7), first);
+        assert_ne!(run(0xDEAD_BEEF), first);
+        assert!(env.step(0).observation.is_empty());
+    }
+
+    #[test]
+    fn episodes_can_be_cut() {
+        let mut best = 0.0;
+        let mut env = Env::new(UFO)
+            .with_reward(move |cpu: &Cpu| {
+                let score = cpu.state().v[7] as f64;
+                let reward = score - best;
+                best = score;
+                reward
+            })
+            .with_termination(Decreases::new(Location::Register(8)))
+            .with_frame_skip(3);
+        env.reset(1);
+        while !env.step(1 << 5).done {}
+        // the first missile is over
+        assert_eq!(env.cpu().state().v[8], 14);
+
+        let mut env = env.with_max_frames(10);
+        env.reset(1);
+        while !env.step(1 << 5).done {}
+        assert_eq!(env.frame(), 12);
+    }
+}
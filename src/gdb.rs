@@ -0,0 +1,479 @@
+// Debug server speaking a subset of the GDB remote serial protocol, so that
+// GDB frontends can drive the cpu over a local TCP socket.
+//
+// The registers are V0-VF (1 byte each), I and PC (2 bytes each) and SP
+// (1 byte), numbered 0 to 18 and sent little endian. Supported packets:
+// `?`, `g`, `G`, `p`, `P`, `m`, `M`, `Z0`/`z0` and `Z1`/`z1` breakpoints,
+// `s`, `c`, `D` and `k`. Ctrl-C interrupts a `c`.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::{Cpu, Register};
+
+// instructions executed between two checks for an interrupt
+const CONTINUE_CHUNK: usize = 1000;
+const REGISTER_COUNT: usize = 19;
+
+fn register(idx: usize) -> Option<(Register, usize)> {
+    match idx {
+        0..=15 => Some((Register::V(idx), 1)),
+        16 => Some((Register::I, 2)),
+        17 => Some((Register::Pc, 2)),
+        18 => Some((Register::Sp, 1)),
+        _ => None,
+    }
+}
+
+fn encode_register(out: &mut String, value: u16, size: usize) {
+    for byte in value.to_le_bytes().iter().take(size) {
+        write!(out, "{:02x}", byte).unwrap();
+    }
+}
+
+// None for invalid digits or an odd number of them.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_register(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u16)
+}
+
+fn parse_number(hex: &str) -> Option<u16> {
+    u16::from_str_radix(hex, 16).ok()
+}
+
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+impl GdbServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(GdbServer {
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Wait for a client and serve it until it detaches or disconnects.
+    pub fn serve(&self, cpu: &mut Cpu) -> io::Result<()> {
+        let (stream, _) = self.listener.accept()?;
+        Session {
+            stream,
+            cpu,
+            pending: VecDeque::new(),
+        }
+        .run()
+    }
+}
+
+struct Session<'a> {
+    stream: TcpStream,
+    cpu: &'a mut Cpu,
+    // bytes read while checking for an interrupt, not handled yet
+    pending: VecDeque<u8>,
+}
+
+enum Reply {
+    Send(String),
+    // send the reply, then end the session
+    Close(String),
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match self.handle(&packet) {
+                Reply::Send(reply) => self.send(&reply)?,
+                Reply::Close(reply) => {
+                    self.send(&reply)?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Read the next packet, acknowledging it. None when the client is gone.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip the acknowledgements and interrupts outside of a `c`
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = self.read_byte()?.ok_or(ErrorKind::UnexpectedEof)?;
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            if expected == Some(sum) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, sum)?;
+        self.stream.flush()
+    }
+
+    // Whether the client sent Ctrl-C, without waiting for it. The other bytes
+    // received are kept for the next packets.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut bytes = [0; 64];
+        let result = match self.stream.read(&mut bytes) {
+            Ok(len) => {
+                let received = &bytes[..len];
+                self.pending
+                    .extend(received.iter().filter(|&&byte| byte != 0x03));
+                Ok(received.contains(&0x03))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.cpu.fault() {
+            // SIGILL
+            Some(_) => "S04".to_string(),
+            // SIGTRAP, for breakpoints, steps and other pauses
+            None => "S05".to_string(),
+        }
+    }
+
+    fn resume(&mut self) -> io::Result<String> {
+        self.cpu.resume();
+        loop {
+            for _ in 0..CONTINUE_CHUNK {
+                self.cpu.execute_cycle();
+                if self.cpu.fault().is_some() || self.cpu.break_reason().is_some() {
+                    return Ok(self.stop_reply());
+                }
+            }
+            if self.interrupted()? {
+                // SIGINT
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(),
+            Some(b'g') => {
+                let mut reply = String::new();
+                for idx in 0..REGISTER_COUNT {
+                    let (register, size) = register(idx).unwrap();
+                    encode_register(&mut reply, self.cpu.register(register), size);
+                }
+                reply
+            }
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16)
+                .ok()
+                .and_then(register)
+            {
+                Some((register, size)) => {
+                    let mut reply = String::new();
+                    encode_register(&mut reply, self.cpu.register(register), size);
+                    reply
+                }
+                None => "E01".to_string(),
+            },
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b's') => {
+                self.cpu.resume();
+                self.cpu.execute_cycle();
+                self.stop_reply()
+            }
+            Some(b'c') => match self.resume() {
+                Ok(reply) => reply,
+                Err(_) => return Reply::Close(String::new()),
+            },
+            Some(b'D') => return Reply::Close("OK".to_string()),
+            Some(b'k') => return Reply::Close(String::new()),
+            Some(b'H') => "OK".to_string(),
+            _ if packet.starts_with("qSupported") => "PacketSize=1000".to_string(),
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet == "qfThreadInfo" => "m1".to_string(),
+            _ if packet == "qsThreadInfo" => "l".to_string(),
+            _ if packet == "qC" => "QC1".to_string(),
+            // anything else is unsupported
+            _ => String::new(),
+        };
+        Reply::Send(reply)
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let bytes = match decode_hex(hex) {
+            Some(bytes) => bytes,
+            None => return "E01".to_string(),
+        };
+        let mut offset = 0;
+        for idx in 0..REGISTER_COUNT {
+            let (register, size) = register(idx).unwrap();
+            match bytes.get(offset..offset + size) {
+                Some(value) => self.cpu.set_register(register, decode_register(value)),
+                None => return "E01".to_string(),
+            }
+            offset += size;
+        }
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let idx = parts
+            .next()
+            .and_then(|idx| usize::from_str_radix(idx, 16).ok());
+        let value = parts.next().and_then(decode_hex);
+        match (idx.and_then(register), value) {
+            (Some((register, size)), Some(value)) if value.len() == size => {
+                self.cpu.set_register(register, decode_register(&value));
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ',');
+        match (
+            parts.next().and_then(parse_number),
+            parts.next().and_then(parse_number),
+        ) {
+            (Some(addr), Some(len)) => {
+                let mut reply = String::new();
+                for offset in 0..len {
+                    let byte = self.cpu.peek(addr.wrapping_add(offset));
+                    write!(reply, "{:02x}", byte).unwrap();
+                }
+                reply
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let mut range = parts.next().unwrap_or("").splitn(2, ',');
+        let addr = range.next().and_then(parse_number);
+        let len = range.next().and_then(parse_number);
+        let bytes = parts.next().and_then(decode_hex);
+        match (addr, len, bytes) {
+            (Some(addr), Some(len), Some(bytes)) if bytes.len() == len as usize => {
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    self.cpu.poke(addr.wrapping_add(offset as u16), byte);
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Software and hardware breakpoints are the same to the cpu.
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let mut parts = packet[1..].split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(parse_number);
+        match (kind, addr) {
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                if packet.starts_with('Z') {
+                    self.cpu.add_breakpoint(addr);
+                } else {
+                    self.cpu.remove_breakpoint(addr);
+                }
+                "OK".to_string()
+            }
+            // watchpoints are not supported
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    use super::GdbServer;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::{Cpu, Register};
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) {
+            let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${}#{:02x}", data, sum).unwrap();
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn it_serves_a_loopback_client() {
+        let server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut cpu = Cpu::new();
+            // LD V0, 0x12 - ADD V0, 1 - JP 0x202
+            cpu.load_cartridge(Cartridge::new(&[0x60, 0x12, 0x70, 0x01, 0x12, 0x02]));
+            server.serve(&mut cpu).unwrap();
+            cpu
+        });
+
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+        };
+        assert_eq!(client.request("qSupported:swbreak+"), "PacketSize=1000");
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(
+            client.request("g"),
+            format!("{}{}", "00".repeat(16), "0000000200")
+        );
+        assert_eq!(client.request("m200,6"), "601270011202");
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "12");
+        assert_eq!(client.request("p11"), "0202");
+
+        assert_eq!(client.request("Z0,204,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p11"), "0402");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p0"), "14");
+        assert_eq!(client.request("z0,204,2"), "OK");
+
+        assert_eq!(client.request("P5=2a"), "OK");
+        assert_eq!(client.request("M300,2:abcd"), "OK");
+        assert_eq!(client.request("m300,2"), "abcd");
+        assert_eq!(client.request("vMustReplyEmpty"), "");
+        assert_eq!(client.request("D"), "OK");
+
+        let cpu = handle.join().unwrap();
+        assert_eq!(cpu.register(Register::V(5)), 0x2A);
+        assert_eq!(cpu.peek(0x301), 0xCD);
+        assert_eq!(cpu.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn continue_can_be_interrupted() {
+        let server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut cpu = Cpu::new();
+            // JP 0x200
+            cpu.load_cartridge(Cartridge::new(&[0x12, 0x00]));
+            server.serve(&mut cpu).unwrap();
+        });
+
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+        };
+        client.send("c");
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "S02");
+        assert_eq!(client.request("k"), "");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn packets_sent_with_an_interrupt_are_kept() {
+        let server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut cpu = Cpu::new();
+            // JP 0x200
+            cpu.load_cartridge(Cartridge::new(&[0x12, 0x00]));
+            server.serve(&mut cpu).unwrap();
+        });
+
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+        };
+        client.send("c");
+        // a `p11` packet, then Ctrl-C
+        client.stream.write_all(b"$p11#d2\x03").unwrap();
+        assert_eq!(client.reply(), "S02");
+        let mut ack = [0];
+        client.stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+');
+        assert_eq!(client.reply(), "0002");
+        assert_eq!(client.request("k"), "");
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+// Minimal animated GIF encoder for indexed color frames.
+
+use std::collections::HashMap;
+
+const MAX_CODE_SIZE: u32 = 12;
+const MAX_CODES: u16 = 1 << MAX_CODE_SIZE;
+
+pub struct GifEncoder {
+    width: u16,
+    height: u16,
+    bytes: Vec<u8>,
+}
+
+impl GifEncoder {
+    // Start an animation looping forever. The palette is padded to a power
+    // of two, with at least four entries.
+    pub fn new(width: u16, height: u16, palette: &[[u8; 4]]) -> GifEncoder {
+        assert!(!palette.is_empty() && palette.len() <= 256);
+        let mut bytes = b"GIF89a".to_vec();
+
+        // logical screen descriptor with a global color table
+        let table_bits = color_table_bits(palette.len()).max(2);
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.push(0x80 | ((table_bits - 1) << 4) as u8 | (table_bits - 1) as u8);
+        // background color index and pixel aspect ratio
+        bytes.extend_from_slice(&[0, 0]);
+        for idx in 0..1 << table_bits {
+            let rgba = palette.get(idx).unwrap_or(&[0, 0, 0, 0]);
+            bytes.extend_from_slice(&rgba[0..3]);
+        }
+
+        // NETSCAPE2.0 application extension: loop forever
+        bytes.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+        bytes.extend_from_slice(b"NETSCAPE2.0");
+        bytes.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+
+        GifEncoder {
+            width,
+            height,
+            bytes,
+        }
+    }
+
+    // Add a frame of `width` x `height` palette indexes, displayed for
+    // `delay` hundredths of a second.
+    pub fn add_frame(&mut self, pixels: &[u8], delay: u16) {
+        assert_eq!(pixels.len(), self.width as usize * self.height as usize);
+
+        // graphic control extension: no transparency, no disposal
+        self.bytes.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+        self.bytes.extend_from_slice(&delay.to_le_bytes());
+        self.bytes.extend_from_slice(&[0x00, 0x00]);
+
+        // image descriptor covering the whole screen, no local color table
+        self.bytes.push(0x2C);
+        self.bytes.extend_from_slice(&[0, 0, 0, 0]);
+        self.bytes.extend_from_slice(&self.width.to_le_bytes());
+        self.bytes.extend_from_slice(&self.height.to_le_bytes());
+        self.bytes.push(0x00);
+
+        let min_code_size = pixels
+            .iter()
+            .max()
+            .map_or(2, |&max| color_table_bits(max as usize + 1).max(2));
+        self.bytes.push(min_code_size as u8);
+        let data = lzw_compress(pixels, min_code_size);
+        for block in data.chunks(255) {
+            self.bytes.push(block.len() as u8);
+            self.bytes.extend_from_slice(block);
+        }
+        // block terminator
+        self.bytes.push(0x00);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        // trailer
+        self.bytes.push(0x3B);
+        self.bytes
+    }
+}
+
+// Number of bits needed to index `colors` colors, at least 1.
+fn color_table_bits(colors: usize) -> u32 {
+    let mut bits = 1;
+    while 1 << bits < colors {
+        bits += 1;
+    }
+    bits
+}
+
+// Pack variable width codes least significant bit first.
+struct CodeWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl CodeWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.buffer |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+fn lzw_compress(pixels: &[u8], min_code_size: u32) -> Vec<u8> {
+    let clear_code: u16 = 1 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let mut writer = CodeWriter {
+        bytes: Vec::new(),
+        buffer: 0,
+        count: 0,
+    };
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = min_code_size + 1;
+
+    writer.write(clear_code, code_size);
+    let mut prefix: Option<u16> = None;
+    for &pixel in pixels {
+        let current = match prefix {
+            None => {
+                prefix = Some(pixel as u16);
+                continue;
+            }
+            Some(current) => current,
+        };
+        if let Some(&code) = dictionary.get(&(current, pixel)) {
+            prefix = Some(code);
+            continue;
+        }
+
+        writer.write(current, code_size);
+        if next_code < MAX_CODES {
+            dictionary.insert((current, pixel), next_code);
+            // the decoder widens the codes once the next one does not fit
+            if next_code == 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+            next_code += 1;
+        } else {
+            // the table is full: start over
+            writer.write(clear_code, code_size);
+            dictionary.clear();
+            next_code = end_code + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = Some(pixel as u16);
+    }
+    if let Some(current) = prefix {
+        writer.write(current, code_size);
+    }
+    writer.write(end_code, code_size);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference LZW decoder, following the GIF89a specification.
+    fn lzw_decompress(data: &[u8], min_code_size: u32) -> Vec<u8> {
+        let clear_code = 1u16 << min_code_size;
+        let end_code = clear_code + 1;
+        let mut bit = 0;
+        let mut read = |size: u32| -> u16 {
+            let mut code = 0;
+            for i in 0..size {
+                code |= (((data[bit / 8] >> (bit % 8)) & 1) as u16) << i;
+                bit += 1;
+            }
+            code
+        };
+
+        let mut out = Vec::new();
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let code = read(code_size);
+            if code == clear_code {
+                table = (0..clear_code).map(|c| vec![c as u8]).collect();
+                table.push(Vec::new());
+                table.push(Vec::new());
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                return out;
+            }
+            let entry = match (&previous, table.get(code as usize)) {
+                (_, Some(entry)) if (code as usize) < table.len() => entry.clone(),
+                (Some(prev), _) => {
+                    let mut entry = prev.clone();
+                    entry.push(prev[0]);
+                    entry
+                }
+                (None, _) => panic!("invalid code"),
+            };
+            if let Some(prev) = previous {
+                let mut new_entry = prev.clone();
+                new_entry.push(entry[0]);
+                table.push(new_entry);
+                if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                    code_size += 1;
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn lzw_round_trips() {
+        let pixels: Vec<u8> = (0..5000).map(|i| ((i / 7) % 4) as u8).collect();
+        assert_eq!(lzw_decompress(&lzw_compress(&pixels, 2), 2), pixels);
+
+        let pixels = vec![1; 20000];
+        assert_eq!(lzw_decompress(&lzw_compress(&pixels, 2), 2), pixels);
+    }
+
+    #[test]
+    fn lzw_round_trips_when_the_table_fills_up() {
+        // pseudo random pixels defeat the dictionary and force clear codes
+        let mut seed: u32 = 1;
+        let pixels: Vec<u8> = (0..50000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8 & 0x3
+            })
+            .collect();
+        assert_eq!(lzw_decompress(&lzw_compress(&pixels, 2), 2), pixels);
+    }
+
+    #[test]
+    fn encoder_writes_gif_structure() {
+        let palette = [[0, 0, 0, 0xFF], [0xFF, 0xFF, 0xFF, 0xFF]];
+        let mut encoder = GifEncoder::new(2, 2, &palette);
+        encoder.add_frame(&[0, 1, 1, 0], 5);
+        let gif = encoder.finish();
+
+        assert_eq!(&gif[0..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[2, 0, 2, 0]);
+        // 4 entries global color table, padded with black
+        assert_eq!(gif[10], 0x91);
+        assert_eq!(&gif[13..25], &[0, 0, 0, 255, 255, 255, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&gif[25..28], &[0x21, 0xFF, 0x0B]);
+        // graphic control extension with the delay
+        assert_eq!(&gif[44..52], &[0x21, 0xF9, 0x04, 0x00, 5, 0, 0, 0]);
+        assert_eq!(gif[gif.len() - 1], 0x3B);
+    }
+}
@@ -0,0 +1,519 @@
+use std::fmt;
+
+// Minimal JSON document, enough for the reports and files of the emulator.
+// Object members keep their insertion order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object() -> Value {
+        Value::Object(Vec::new())
+    }
+
+    // Add a member to an object, builder style.
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Value {
+        if let Value::Object(members) = &mut self {
+            members.push((key.to_string(), value.into()));
+        }
+        self
+    }
+}
+
+impl Value {
+    pub fn parse(text: &str) -> Result<Value, ParseError> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    // Member of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // The number, when it is a non negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(value) if value >= 0.0 && value.fract() == 0.0 && value < 2e19 => {
+                Some(value as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    // byte offset of the error in the text
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+// Why a versioned file of the emulator, like a symbol or a cheat file, could
+// not be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileError {
+    // what the file holds, like "symbol" or "cheat"
+    pub file: &'static str,
+    pub kind: FileErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileErrorKind {
+    Json(ParseError),
+    UnsupportedVersion(u64),
+    // the value at this place of the file is missing or has the wrong type
+    Invalid(&'static str),
+}
+
+impl FileError {
+    pub fn invalid(file: &'static str, what: &'static str) -> FileError {
+        FileError {
+            file,
+            kind: FileErrorKind::Invalid(what),
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            FileErrorKind::Json(e) => write!(f, "invalid {} file: {}", self.file, e),
+            FileErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported {} file version {}", self.file, version)
+            }
+            FileErrorKind::Invalid(what) => write!(f, "invalid {} in {} file", what, self.file),
+        }
+    }
+}
+
+// Parse a file holding `file`, whose "version" member must be `version`.
+pub fn parse_file(json: &str, file: &'static str, version: u64) -> Result<Value, FileError> {
+    let error = |kind| FileError { file, kind };
+    let root = Value::parse(json).map_err(|e| error(FileErrorKind::Json(e)))?;
+    match root.get("version").and_then(Value::as_u64) {
+        Some(found) if found == version => Ok(root),
+        Some(found) => Err(error(FileErrorKind::UnsupportedVersion(found))),
+        None => Err(FileError::invalid(file, "version")),
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            offset: self.pos,
+            message,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, byte: u8, message: &'static str) -> Result<(), ParseError> {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, ParseError> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected character"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            None => Err(self.error("unexpected end")),
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a member name"));
+            }
+            let key = self.string()?;
+            self.expect(b':', "expected ':'")?;
+            members.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.pos += 1;
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+        | Some(b'0'..=b'9') = self.bytes.get(self.pos)
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .map(Value::Number)
+            .ok_or(ParseError {
+                offset: start,
+                message: "invalid number",
+            })
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = match self.bytes.get(self.pos) {
+                Some(&byte) => byte,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.bytes.get(self.pos).cloned();
+                    self.pos += 1;
+                    let c = match escaped {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let mut code = self.hex4()?;
+                            // characters outside of the BMP are surrogate pairs
+                            if (0xD800..0xDC00).contains(&code)
+                                && self.bytes[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            std::char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        // the input is a str and escapes are encoded as UTF-8
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Number(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+// Compact serialization.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(value) => write!(f, "{}", value),
+            // integers are printed without a fraction, and JSON has no NaN
+            Value::Number(value) if !value.is_finite() => f.write_str("null"),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write_string(f, value),
+            Value::Array(values) => {
+                f.write_str("[")?;
+                for (idx, value) in values.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(members) => {
+                f.write_str("{")?;
+                for (idx, (key, value)) in members.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_file, ParseError, Value};
+
+    #[test]
+    fn serializes_compact_json() {
+        let value = Value::object()
+            .with("name", "a \"quoted\"\nline")
+            .with("count", 3u64)
+            .with("ratio", 0.5)
+            .with("list", vec![1u32, 2])
+            .with("empty", Value::Null)
+            .with("on", true);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"quoted\"\nline","count":3,"ratio":0.5,"list":[1,2],"empty":null,"on":true}"#
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let value = Value::parse(
+            r#" { "name": "caf\u00e9 \"\ud83d\ude00\"", "list": [1, -2.5e1, true, null],
+                 "nested": {} } "#,
+        )
+        .unwrap();
+        assert_eq!(
+            value.get("name").unwrap().as_str(),
+            Some("café \"\u{1F600}\"")
+        );
+        let list = value.get("list").unwrap().as_array().unwrap();
+        assert_eq!(list[0].as_u64(), Some(1));
+        assert_eq!(list[1].as_f64(), Some(-25.0));
+        assert_eq!(list[1].as_u64(), None);
+        assert_eq!(list[2].as_bool(), Some(true));
+        assert_eq!(list[3], Value::Null);
+        assert_eq!(value.get("nested").unwrap().as_object(), Some(&[][..]));
+
+        let text = value.to_string();
+        assert_eq!(Value::parse(&text), Ok(value));
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(
+            Value::parse("[1, 2"),
+            Err(ParseError {
+                offset: 5,
+                message: "expected ',' or ']'"
+            })
+        );
+        assert_eq!(
+            Value::parse("{\"a\" 1}").unwrap_err().to_string(),
+            "expected ':' at byte 5"
+        );
+        assert!(Value::parse("[1] x").is_err());
+        assert!(Value::parse("\"open").is_err());
+    }
+
+    #[test]
+    fn checks_the_version_of_files() {
+        let parse = |json: &str| parse_file(json, "test", 1).map_err(|e| e.to_string());
+        assert!(parse(r#"{"version": 1}"#).is_ok());
+        assert_eq!(
+            parse(r#"{"version": 2}"#),
+            Err("unsupported test file version 2".to_string())
+        );
+        assert_eq!(parse("{}"), Err("invalid version in test file".to_string()));
+        assert_eq!(
+            parse("{"),
+            Err("invalid test file: expected a member name at byte 1".to_string())
+        );
+    }
+}
@@ -8,6 +8,12 @@
 // +-+-+-+-+                +-+-+-+-+
 // |A|0|B|F|                |Z|X|C|V|
 // +-+-+-+-+                +-+-+-+-+
+//
+// Keys are stored by index, the value Ex9E, ExA1 and Fx0A use for a key.
+// The host keys map to the indexes in reading order, "1" being 0x0, "4"
+// 0x3, "q" 0x4 and so on up to "v" 0xF, and not to the labels of the
+// keypad above. The index API (press, release and the state bitmask) uses
+// the same indexes.
 
 pub struct Keypad {
     keys: [bool; 16],
@@ -19,7 +25,7 @@ impl Keypad {
     }
 
     fn key_to_idx(&self, key: &str) -> Option<usize> {
-        let idx = match key.to_lowercase().as_ref() {
+        match key.to_lowercase().as_ref() {
             "1" => Some(0),
             "2" => Some(1),
             "3" => Some(2),
@@ -37,38 +43,68 @@ impl Keypad {
             "c" => Some(14),
             "v" => Some(15),
             _ => None,
-        };
-
-        idx
+        }
     }
 
     pub fn key_down(&mut self, key: &str) {
-        match self.key_to_idx(key) {
-            Some(idx) => self.keys[idx] = true,
-            None => (),
+        if let Some(idx) = self.key_to_idx(key) {
+            self.keys[idx] = true;
         }
     }
 
     pub fn key_up(&mut self, key: &str) {
-        match self.key_to_idx(key) {
-            Some(idx) => self.keys[idx] = false,
-            None => (),
+        if let Some(idx) = self.key_to_idx(key) {
+            self.keys[idx] = false;
         }
     }
 
     pub fn is_key_pressed(&self, key: &str) -> bool {
-        let is_pressed = match self.key_to_idx(key) {
+        match self.key_to_idx(key) {
             Some(idx) => self.is_key_idx_pressed(idx),
             None => false,
-        };
-
-        is_pressed
+        }
     }
     pub fn is_key_idx_pressed(&self, idx: usize) -> bool {
-        self.keys[idx] == true
+        self.keys[idx]
     }
     pub fn get_first_pressed_key_idx(&self) -> Option<usize> {
-        self.keys.iter().position(|&idx| idx == true)
+        self.keys.iter().position(|&pressed| pressed)
+    }
+
+    // Press a key by its index, from 0x0 to 0xF: the value Ex9E, ExA1 and
+    // Fx0A use for it. Other indexes are ignored, like unknown host keys.
+    pub fn press(&mut self, idx: usize) {
+        if let Some(key) = self.keys.get_mut(idx) {
+            *key = true;
+        }
+    }
+
+    pub fn release(&mut self, idx: usize) {
+        if let Some(key) = self.keys.get_mut(idx) {
+            *key = false;
+        }
+    }
+
+    // The held keys, one bit per key, key 0 being the lowest bit.
+    pub fn state(&self) -> u16 {
+        self.keys
+            .iter()
+            .enumerate()
+            .filter(|(_, &pressed)| pressed)
+            .fold(0, |state, (idx, _)| state | 1 << idx)
+    }
+
+    // Hold the keys whose bit is set, releasing the others.
+    pub fn set_state(&mut self, state: u16) {
+        for (idx, key) in self.keys.iter_mut().enumerate() {
+            *key = state & (1 << idx) != 0;
+        }
+    }
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Keypad::new()
     }
 }
 
@@ -116,4 +152,30 @@ mod tests {
 
         assert_eq!(keypad.get_first_pressed_key_idx(), None);
     }
+
+    #[test]
+    fn it_presses_keys_by_index() {
+        let mut keypad = Keypad::new();
+
+        keypad.press(0xF);
+        keypad.press(0x2);
+        keypad.press(16);
+        keypad.release(0x2);
+        assert!(keypad.is_key_idx_pressed(0xF));
+        assert!(keypad.is_key_pressed("v"));
+        assert_eq!(keypad.state(), 0x8000);
+    }
+
+    #[test]
+    fn it_sets_the_state_as_a_bitmask() {
+        let mut keypad = Keypad::new();
+
+        keypad.key_down("q");
+        keypad.set_state(0b1001);
+        assert_eq!(keypad.state(), 0b1001);
+        assert!(keypad.is_key_idx_pressed(0));
+        assert!(keypad.is_key_idx_pressed(3));
+        assert!(!keypad.is_key_pressed("q"));
+        assert_eq!(keypad.get_first_pressed_key_idx(), Some(0));
+    }
 }
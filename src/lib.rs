@@ -1,10 +1,31 @@
 const MEMORY_SIZE: usize = 4096;
 const DISPLAY_PIXEL_WIDTH: usize = 64;
 const DISPLAY_PIXEL_HEIGHT: usize = 32;
+// SUPER-CHIP high resolution
+const HIRES_PIXEL_WIDTH: usize = 128;
+const HIRES_PIXEL_HEIGHT: usize = 64;
 
+pub mod analysis;
+pub mod audio;
+pub mod batch;
 pub mod cartridge;
+pub mod cheats;
+pub mod checksum;
 pub mod cpu;
+pub mod decompiler;
 pub mod display;
+pub mod env;
 pub mod font;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gdb;
+pub mod gif;
+pub mod json;
 pub mod keypad;
+pub mod netplay;
+pub mod octo;
+pub mod opcode;
+pub mod png;
 pub mod rand;
+#[cfg(test)]
+mod reference;
+pub mod symbols;
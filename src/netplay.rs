@@ -0,0 +1,583 @@
+// Two players on two machines, each running the same program and
+// exchanging the keys held for every frame, for two-player ROMs like PONG2
+// splitting the keypad between the players.
+//
+// The keys of the peer arrive late, so each frame runs on a prediction of
+// them: the last keys received. A save state is kept for every frame run on
+// a prediction, and when the keys of the peer turn out to be different, the
+// session rolls back to the first wrong frame and runs it again up to the
+// present with the right keys. Both machines go through the same frames
+// with the same keys, and end in the same state, as long as the cpu is
+// deterministic: the same program, seed, speed and keys make the same run.
+//
+//     let transport = TcpTransport::connect("192.168.1.10:8642")?;
+//     let mut session = Session::new(&rom, transport).with_keys(PLAYER_TWO);
+//     // once per frame, with the keys held by the local player
+//     session.advance(keys)?;
+//
+// The messages go through a `Transport`. TCP, on native builds, is the only
+// network transport; other links can implement it with `Message::to_bytes`
+// and `Message::from_bytes`. `Loopback` connects two sessions in memory.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::tcp::TcpTransport;
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+
+use crate::cartridge::Cartridge;
+use crate::cpu::{Cpu, Quirks};
+
+const HELLO: u8 = 0;
+const INPUT: u8 = 1;
+// tag, ROM CRC-32, seed, instructions per frame and quirks
+const HELLO_SIZE: usize = 1 + 4 + 4 + 4 + 1;
+// tag, frame and keys
+const INPUT_SIZE: usize = 1 + 8 + 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    // sent first, for the peers to check that they run the same machine
+    Hello {
+        rom: u32,
+        seed: u32,
+        cycles_per_frame: u32,
+        quirks: Quirks,
+    },
+    // the keys held by a player for a frame, the frames in order
+    Input {
+        frame: u64,
+        keys: u16,
+    },
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HELLO_SIZE.max(INPUT_SIZE));
+        match *self {
+            Message::Hello {
+                rom,
+                seed,
+                cycles_per_frame,
+                quirks,
+            } => {
+                bytes.push(HELLO);
+                bytes.extend_from_slice(&rom.to_le_bytes());
+                bytes.extend_from_slice(&seed.to_le_bytes());
+                bytes.extend_from_slice(&cycles_per_frame.to_le_bytes());
+                bytes.push(quirks.to_bits());
+            }
+            Message::Input { frame, keys } => {
+                bytes.push(INPUT);
+                bytes.extend_from_slice(&frame.to_le_bytes());
+                bytes.extend_from_slice(&keys.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    // The message at the start of `bytes` and its length, or None when only
+    // a part of it is there.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Option<(Message, usize)>> {
+        let size = match bytes.first() {
+            None => return Ok(None),
+            Some(&HELLO) => HELLO_SIZE,
+            Some(&INPUT) => INPUT_SIZE,
+            Some(tag) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown netplay message {}", tag),
+                ))
+            }
+        };
+        if bytes.len() < size {
+            return Ok(None);
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let message = if bytes[0] == HELLO {
+            let quirks = Quirks::from_bits(bytes[13])
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "unknown netplay quirks"))?;
+            Message::Hello {
+                rom: u32_at(1),
+                seed: u32_at(5),
+                cycles_per_frame: u32_at(9),
+                quirks,
+            }
+        } else {
+            Message::Input {
+                frame: u64::from_le_bytes(bytes[1..9].try_into().unwrap()),
+                keys: u16::from_le_bytes(bytes[9..11].try_into().unwrap()),
+            }
+        };
+        Ok(Some((message, size)))
+    }
+}
+
+// The link to the peer. Messages arrive in order, and `receive` never waits.
+pub trait Transport {
+    fn send(&mut self, message: &Message) -> io::Result<()>;
+
+    // The next message of the peer, or None when there is none yet.
+    fn receive(&mut self) -> io::Result<Option<Message>>;
+}
+
+// One end of an in-memory link, for two sessions in the same process.
+pub struct Loopback {
+    sender: Sender<Message>,
+    receiver: Receiver<Message>,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let (first_sender, second_receiver) = mpsc::channel();
+        let (second_sender, first_receiver) = mpsc::channel();
+        (
+            Loopback {
+                sender: first_sender,
+                receiver: first_receiver,
+            },
+            Loopback {
+                sender: second_sender,
+                receiver: second_receiver,
+            },
+        )
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "the peer disconnected")
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.sender.send(*message).map_err(|_| disconnected())
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Message>> {
+        match self.receiver.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(disconnected()),
+        }
+    }
+}
+
+// Reason a session stopped.
+#[derive(Debug)]
+pub enum NetplayError {
+    Io(io::Error),
+    // the peer runs another ROM, seed, speed or quirks, and would desync
+    Mismatch(&'static str),
+    // the keys of the peer for another frame than the next one
+    OutOfOrder { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetplayError::Io(error) => write!(f, "{}", error),
+            NetplayError::Mismatch(what) => write!(f, "the peer runs another {}", what),
+            NetplayError::OutOfOrder { expected, actual } => write!(
+                f,
+                "the peer sent the keys of frame {} instead of frame {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for NetplayError {
+    fn from(error: io::Error) -> Self {
+        NetplayError::Io(error)
+    }
+}
+
+pub struct Session<T: Transport> {
+    cpu: Cpu,
+    transport: T,
+    seed: u32,
+    cycles_per_frame: u32,
+    // keys of the local player, the others being left to the peer
+    keys: u16,
+    // frames run ahead of the peer before waiting for it
+    max_rollback: u64,
+    // the hello and the inputs of the delay went out
+    started: bool,
+    // keys of the players by frame, the local ones `input_delay` frames
+    // ahead of the frame run
+    local: Vec<u16>,
+    remote: Vec<u16>,
+    // the state before every frame run on a prediction, with the keys
+    // predicted for the peer, up to the current frame
+    pending: VecDeque<(Vec<u8>, u16)>,
+    // frames run so far
+    frame: u64,
+    // frames run again after a wrong prediction
+    resimulated: u64,
+}
+
+impl<T: Transport> Session<T> {
+    // A session running `program` with the seed 1 and 10 instructions per
+    // frame, both players holding any key, up to 8 frames ahead of the peer.
+    pub fn new(program: &[u8], transport: T) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(Cartridge::new(program));
+        Session {
+            cpu,
+            transport,
+            seed: 1,
+            cycles_per_frame: 10,
+            keys: 0xFFFF,
+            max_rollback: 8,
+            started: false,
+            local: Vec::new(),
+            remote: Vec::new(),
+            pending: VecDeque::new(),
+            frame: 0,
+            resimulated: 0,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self.cpu.set_seed(seed);
+        self
+    }
+
+    pub fn with_cycles_per_frame(mut self, cycles: u32) -> Self {
+        self.cycles_per_frame = cycles;
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.cpu.set_quirks(quirks);
+        self
+    }
+
+    // Ignore the local keys not in `keys`, for the peer to own them.
+    pub fn with_keys(mut self, keys: u16) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    // Apply the local keys `frames` frames later, giving them time to reach
+    // the peer before it runs these frames, for fewer rollbacks.
+    pub fn with_input_delay(mut self, frames: u64) -> Self {
+        self.local = vec![0; frames as usize];
+        self
+    }
+
+    // Run at most `frames` frames ahead of the last keys of the peer, at
+    // least one.
+    pub fn with_max_rollback(mut self, frames: u64) -> Self {
+        self.max_rollback = frames.max(1);
+        self
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    // Number of frames run so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Number of frames run with the keys of both players known, which no
+    // rollback can change anymore.
+    pub fn confirmed_frame(&self) -> u64 {
+        self.frame.min(self.remote.len() as u64)
+    }
+
+    // Number of frames run again after wrong predictions.
+    pub fn resimulated_frames(&self) -> u64 {
+        self.resimulated
+    }
+
+    // The keys held by both players during every confirmed frame, to replay
+    // the session on a single machine.
+    pub fn inputs(&self) -> impl Iterator<Item = u16> + '_ {
+        self.local
+            .iter()
+            .zip(self.remote.iter())
+            .take(self.confirmed_frame() as usize)
+            .map(|(local, remote)| local | remote)
+    }
+
+    // Run the next frame with the local player holding `keys`, after taking
+    // in the keys of the peer. Returns false, without running the frame,
+    // when the session is too far ahead of the peer and waits for it.
+    pub fn advance(&mut self, keys: u16) -> Result<bool, NetplayError> {
+        if !self.started {
+            self.start()?;
+        }
+        self.poll()?;
+        if self.frame - self.confirmed_frame() >= self.max_rollback {
+            return Ok(false);
+        }
+        let keys = keys & self.keys;
+        self.transport.send(&Message::Input {
+            frame: self.local.len() as u64,
+            keys,
+        })?;
+        self.local.push(keys);
+        self.run_frame();
+        Ok(true)
+    }
+
+    // Take in the messages of the peer, and roll back if they contradict
+    // the predictions.
+    pub fn poll(&mut self) -> Result<(), NetplayError> {
+        while let Some(message) = self.transport.receive()? {
+            match message {
+                Message::Hello {
+                    rom,
+                    seed,
+                    cycles_per_frame,
+                    quirks,
+                } => {
+                    if rom != self.cpu.rom_crc() {
+                        return Err(NetplayError::Mismatch("ROM"));
+                    }
+                    if seed != self.seed {
+                        return Err(NetplayError::Mismatch("seed"));
+                    }
+                    if cycles_per_frame != self.cycles_per_frame {
+                        return Err(NetplayError::Mismatch("speed"));
+                    }
+                    if quirks != self.cpu.quirks() {
+                        return Err(NetplayError::Mismatch("quirks"));
+                    }
+                }
+                Message::Input { frame, keys } => {
+                    let expected = self.remote.len() as u64;
+                    if frame != expected {
+                        return Err(NetplayError::OutOfOrder {
+                            expected,
+                            actual: frame,
+                        });
+                    }
+                    self.remote.push(keys);
+                }
+            }
+        }
+
+        let first = self.frame - self.pending.len() as u64;
+        let wrong = (first..self.confirmed_frame())
+            .find(|&frame| self.remote[frame as usize] != self.pending[(frame - first) as usize].1);
+        if let Some(frame) = wrong {
+            let (state, _) = &self.pending[(frame - first) as usize];
+            self.cpu
+                .load_state(state)
+                .expect("the session restores its own states");
+            self.pending.clear();
+            let end = self.frame;
+            self.frame = frame;
+            while self.frame < end {
+                self.run_frame();
+                self.resimulated += 1;
+            }
+        }
+        let predicted = (self.frame - self.confirmed_frame()) as usize;
+        while self.pending.len() > predicted {
+            self.pending.pop_front();
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        self.transport.send(&Message::Hello {
+            rom: self.cpu.rom_crc(),
+            seed: self.seed,
+            cycles_per_frame: self.cycles_per_frame,
+            quirks: self.cpu.quirks(),
+        })?;
+        // the frames of the input delay, with no local keys
+        for frame in 0..self.local.len() as u64 {
+            self.transport.send(&Message::Input { frame, keys: 0 })?;
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    fn run_frame(&mut self) {
+        let frame = self.frame as usize;
+        let remote = match self.remote.get(frame) {
+            Some(&keys) => keys,
+            None => {
+                // the peer is likely to hold the same keys as before
+                let predicted = self.remote.last().copied().unwrap_or(0);
+                self.pending.push_back((self.cpu.save_state(), predicted));
+                predicted
+            }
+        };
+        self.cpu.set_keypad_state(self.local[frame] | remote);
+        self.cpu.run_frame(self.cycles_per_frame);
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG2: &[u8] = include_bytes!("../web/roms/PONG2.ch8");
+    // 1 and 4 move the left paddle, C and D the right one
+    const LEFT: u16 = 1 << 0x1 | 1 << 0x4;
+    const RIGHT: u16 = 1 << 0xC | 1 << 0xD;
+
+    // Keys changing every few frames, different for every player.
+    fn keys(player: u64, frame: u64) -> u16 {
+        let keys = [1 << 0x1, 0, 1 << 0x4, 1 << 0xC, 1 << 0xD, 0xFFFF];
+        keys[((frame / 7 + player * 3) % keys.len() as u64) as usize]
+    }
+
+    fn pair() -> (Session<Loopback>, Session<Loopback>) {
+        let (left, right) = Loopback::pair();
+        (
+            Session::new(PONG2, left).with_seed(3).with_keys(LEFT),
+            Session::new(PONG2, right).with_seed(3).with_keys(RIGHT),
+        )
+    }
+
+    // Run both sessions to `frames`, the second one `lag` frames behind.
+    fn play<T: Transport>(left: &mut Session<T>, right: &mut Session<T>, frames: u64, lag: u64) {
+        while left.frame() < frames || right.frame() < frames {
+            if left.frame() < frames {
+                left.advance(keys(0, left.frame())).unwrap();
+            }
+            let behind = right.frame() + lag <= left.frame() || left.frame() == frames;
+            if behind && right.frame() < frames {
+                right.advance(keys(1, right.frame())).unwrap();
+            }
+        }
+        left.poll().unwrap();
+        right.poll().unwrap();
+    }
+
+    // The state of a single machine replaying the keys of a session.
+    fn replay<T: Transport>(session: &Session<T>) -> Vec<u8> {
+        let mut cpu = Cpu::new();
+        cpu.set_seed(3);
+        cpu.load_cartridge(Cartridge::new(PONG2));
+        for keys in session.inputs() {
+            cpu.set_keypad_state(keys);
+            cpu.run_frame(10);
+        }
+        cpu.save_state()
+    }
+
+    #[test]
+    fn late_inputs_are_resimulated() {
+        let (mut left, mut right) = pair();
+        play(&mut left, &mut right, 300, 5);
+        assert!(left.resimulated_frames() > 0);
+        assert_eq!(left.confirmed_frame(), 300);
+        assert_eq!(right.confirmed_frame(), 300);
+        assert_eq!(left.cpu().save_state(), right.cpu().save_state());
+        assert_eq!(
+            left.inputs().collect::<Vec<_>>(),
+            right.inputs().collect::<Vec<_>>()
+        );
+        assert_eq!(replay(&left), left.cpu().save_state());
+    }
+
+    #[test]
+    fn sessions_wait_for_a_late_peer() {
+        let (left, mut right) = pair();
+        let mut left = left.with_max_rollback(4);
+        for _ in 0..4 {
+            assert!(left.advance(LEFT).unwrap());
+        }
+        assert!(!left.advance(LEFT).unwrap());
+        assert_eq!(left.frame(), 4);
+
+        right.advance(0).unwrap();
+        assert!(left.advance(LEFT).unwrap());
+        assert_eq!(left.confirmed_frame(), 1);
+    }
+
+    #[test]
+    fn input_delay_avoids_rollbacks() {
+        let (left, right) = Loopback::pair();
+        let mut left = Session::new(PONG2, left).with_seed(3).with_input_delay(2);
+        let mut right = Session::new(PONG2, right).with_seed(3).with_input_delay(2);
+        play(&mut left, &mut right, 200, 1);
+        assert_eq!(left.resimulated_frames(), 0);
+        assert_eq!(right.resimulated_frames(), 0);
+        assert_eq!(left.cpu().save_state(), right.cpu().save_state());
+        assert_eq!(replay(&left), left.cpu().save_state());
+    }
+
+    #[test]
+    fn peers_must_run_the_same_machine() {
+        let (left, right) = Loopback::pair();
+        let mut left = Session::new(PONG2, left);
+        let mut right = Session::new(PONG2, right).with_seed(4);
+        left.advance(0).unwrap();
+        match right.advance(0) {
+            Err(NetplayError::Mismatch("seed")) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (left, right) = Loopback::pair();
+        let mut left = Session::new(PONG2, left);
+        let mut right = Session::new(PONG2, right).with_quirks(Quirks::cosmac_vip());
+        left.advance(0).unwrap();
+        match right.advance(0) {
+            Err(NetplayError::Mismatch("quirks")) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (left, right) = Loopback::pair();
+        let mut left = Session::new(PONG2, left);
+        let mut right = Session::new(&PONG2[..PONG2.len() - 1], right);
+        left.advance(0).unwrap();
+        assert_eq!(
+            right.advance(0).unwrap_err().to_string(),
+            "the peer runs another ROM"
+        );
+        drop(left);
+        let error = right.advance(0).unwrap_err();
+        assert!(
+            matches!(error, NetplayError::Io(error) if error.kind() == ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn messages_are_encoded() {
+        let messages = [
+            Message::Hello {
+                rom: 0xDEAD_BEEF,
+                seed: 7,
+                cycles_per_frame: 10,
+                quirks: Quirks::cosmac_vip(),
+            },
+            Message::Input {
+                frame: 1 << 40,
+                keys: 0x8001,
+            },
+        ];
+        let bytes: Vec<u8> = messages.iter().flat_map(Message::to_bytes).collect();
+        let (first, size) = Message::from_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(first, messages[0]);
+        assert_eq!(
+            Message::from_bytes(&bytes[size..]).unwrap(),
+            Some((messages[1], INPUT_SIZE))
+        );
+        assert_eq!(Message::from_bytes(&bytes[size..size + 3]).unwrap(), None);
+        assert!(Message::from_bytes(&[9]).is_err());
+        let mut hello = messages[0].to_bytes();
+        hello[13] = 0x10;
+        assert!(Message::from_bytes(&hello).is_err());
+    }
+}
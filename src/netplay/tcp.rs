@@ -0,0 +1,126 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use super::{disconnected, Message, Transport};
+
+// A TCP connection to the peer, never blocking: what cannot be sent right
+// away is sent by the next calls.
+pub struct TcpTransport {
+    stream: TcpStream,
+    // bytes received, the last message possibly incomplete
+    incoming: Vec<u8>,
+    // bytes not sent yet
+    outgoing: Vec<u8>,
+    // the peer closed the connection
+    closed: bool,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        // the inputs are tiny and late ones cost a rollback
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpTransport {
+            stream,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            closed: false,
+        })
+    }
+
+    // Connect to a peer waiting on `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        TcpTransport::new(TcpStream::connect(addr)?)
+    }
+
+    // Wait for a peer on `listener`.
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        TcpTransport::new(stream)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(disconnected()),
+                Ok(len) => {
+                    self.outgoing.drain(..len);
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.outgoing.extend_from_slice(&message.to_bytes());
+        self.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Message>> {
+        self.flush()?;
+        let mut buffer = [0; 256];
+        while !self.closed {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(len) => self.incoming.extend_from_slice(&buffer[..len]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        match Message::from_bytes(&self.incoming)? {
+            Some((message, len)) => {
+                self.incoming.drain(..len);
+                Ok(Some(message))
+            }
+            // the messages received before are taken in first
+            None if self.closed => Err(disconnected()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netplay::Session;
+
+    #[test]
+    fn sessions_play_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let first = TcpTransport::connect(listener.local_addr().unwrap()).unwrap();
+        let second = TcpTransport::accept(&listener).unwrap();
+        let rom = include_bytes!("../../web/roms/PONG2.ch8");
+        let mut first = Session::new(rom, first).with_keys(1 << 0x1 | 1 << 0x4);
+        let mut second = Session::new(rom, second).with_keys(1 << 0xC | 1 << 0xD);
+
+        while first.confirmed_frame() < 120 || second.confirmed_frame() < 120 {
+            if first.frame() < 120 {
+                first
+                    .advance(if first.frame() % 20 < 10 { 1 << 0x1 } else { 0 })
+                    .unwrap();
+            } else {
+                first.poll().unwrap();
+            }
+            if second.frame() < 120 {
+                second.advance(1 << 0xD).unwrap();
+            } else {
+                second.poll().unwrap();
+            }
+        }
+        assert_eq!(first.cpu().save_state(), second.cpu().save_state());
+
+        drop(first);
+        let error = loop {
+            if let Err(error) = second.poll() {
+                break error;
+            }
+        };
+        assert_eq!(error.to_string(), "the peer disconnected");
+    }
+}
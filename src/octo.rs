@@ -0,0 +1,1334 @@
+// Compiler for Octo, the structured assembly language most CHIP-8 programs
+// are written in nowadays (https://github.com/JohnEarnest/Octo).
+//
+// Supported: labels (`: name`, `:next`), `:alias`, `:const`, `:calc`,
+// `:byte`, `:org`, `:macro`, `:unpack`, `:call`, `:breakpoint`, the
+// structured `loop ... again` with `while`, `if ... then` and
+// `if ... begin ... else ... end`, and the instructions of CHIP-8,
+// SUPER-CHIP and XO-CHIP. Like Octo, a `jump main` is inserted at 0x200
+// unless `: main` is the first thing in the program.
+
+mod calc;
+mod lexer;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use wasm_bindgen::prelude::*;
+
+use self::calc::Calc;
+use self::lexer::{tokenize, Token};
+use crate::cartridge::Cartridge;
+use crate::opcode::Instruction::{self, *};
+use crate::symbols::SymbolMap;
+
+const ENTRY: usize = 0x200;
+// a macro expanding more than this many times is most likely recursive
+const MAX_EXPANSIONS: usize = 100_000;
+
+const KEYWORDS: &[&str] = &[
+    "clear",
+    "return",
+    "hires",
+    "lores",
+    "exit",
+    "scroll-down",
+    "scroll-up",
+    "scroll-left",
+    "scroll-right",
+    "audio",
+    "plane",
+    "bcd",
+    "save",
+    "load",
+    "saveflags",
+    "loadflags",
+    "sprite",
+    "jump",
+    "jump0",
+    "native",
+    "loop",
+    "again",
+    "while",
+    "if",
+    "then",
+    "begin",
+    "else",
+    "end",
+    "i",
+    "key",
+    "-key",
+    "random",
+    "delay",
+    "buzzer",
+    "pitch",
+    "hex",
+    "bighex",
+    "long",
+];
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Target {
+    fn memory_size(self) -> usize {
+        match self {
+            Target::XoChip => 0x10000,
+            _ => 0x1000,
+        }
+    }
+
+    // Whether the instructions of `target` can run on this one.
+    fn supports(self, target: Target) -> bool {
+        match target {
+            Target::Chip8 => true,
+            Target::SuperChip => self != Target::Chip8,
+            Target::XoChip => self == Target::XoChip,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Chip8 => write!(f, "CHIP-8"),
+            Target::SuperChip => write!(f, "SUPER-CHIP"),
+            Target::XoChip => write!(f, "XO-CHIP"),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_ref() {
+            "chip8" => Ok(Target::Chip8),
+            "schip" => Ok(Target::SuperChip),
+            "xochip" => Ok(Target::XoChip),
+            _ => Err(format!("unknown target: {}", name)),
+        }
+    }
+}
+
+// A compiled program, to be loaded at 0x200.
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub bytes: Vec<u8>,
+    // the labels, and the source line of every instruction
+    pub symbols: SymbolMap,
+    // the addresses of the `:breakpoint` directives
+    pub breakpoints: Vec<u16>,
+}
+
+impl Program {
+    pub fn cartridge(&self) -> Cartridge {
+        Cartridge::new(&self.bytes)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileError {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+// Compile the Octo source of `file`.
+pub fn compile(file: &str, source: &str, target: Target) -> Result<Program, CompileError> {
+    Compiler::new(file, source, target).compile()
+}
+
+// Integer literals: decimal, 0x hexadecimal or 0b binary, optionally
+// negative.
+pub(crate) fn parse_number(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (radix, digits) = if let Some(digits) = text.strip_prefix("0x") {
+        (16, digits)
+    } else if let Some(digits) = text.strip_prefix("0b") {
+        (2, digits)
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+fn register_name(text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(x), None) | (Some('V'), Some(x), None) => {
+            x.to_digit(16).map(|x| x as usize)
+        }
+        _ => None,
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && register_name(text).is_none()
+        && !KEYWORDS.contains(&text)
+}
+
+// How a label is written into the program once its address is known.
+#[derive(Clone, Copy, Debug)]
+enum Fixup {
+    // the 12 bit operand of the instruction at the address
+    Nnn,
+    // a whole 16 bit word, for `i := long`
+    Word,
+    // the high 4 bits of a 12 bit address in the low nibble of a byte
+    HighNibble,
+    HighByte,
+    LowByte,
+}
+
+enum Rhs {
+    Register(usize),
+    Byte(u8),
+}
+
+enum Condition {
+    Eq(usize, Rhs),
+    Ne(usize, Rhs),
+    Lt(usize, Rhs),
+    Gt(usize, Rhs),
+    Le(usize, Rhs),
+    Ge(usize, Rhs),
+    Key(usize),
+    NotKey(usize),
+}
+
+// An open control structure, with the address of its jump to fix when
+// it closes.
+enum Block {
+    Loop { start: usize, breaks: Vec<usize> },
+    If { jump: usize },
+    Else { jump: usize },
+}
+
+#[derive(Clone)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: u32,
+}
+
+struct Compiler<'a> {
+    file: &'a str,
+    target: Target,
+    tokens: Vec<Token>,
+    pos: usize,
+    memory: Vec<u8>,
+    // bytes already emitted, to catch code overlapping after `:org`
+    used: Vec<bool>,
+    here: usize,
+    // one past the last emitted byte
+    end: usize,
+    // 0x200 is reserved for a `jump main`
+    main_jump: bool,
+    labels: BTreeMap<String, u16>,
+    constants: BTreeMap<String, f64>,
+    aliases: BTreeMap<String, usize>,
+    macros: BTreeMap<String, Macro>,
+    expansions: usize,
+    fixups: Vec<(Token, usize, Fixup)>,
+    blocks: Vec<(Token, Block)>,
+    symbols: SymbolMap,
+    breakpoints: Vec<u16>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(file: &'a str, source: &str, target: Target) -> Self {
+        let mut used = vec![false; target.memory_size()];
+        used[ENTRY] = true;
+        used[ENTRY + 1] = true;
+        Compiler {
+            file,
+            target,
+            tokens: tokenize(source),
+            pos: 0,
+            memory: vec![0; target.memory_size()],
+            used,
+            here: ENTRY + 2,
+            end: ENTRY + 2,
+            main_jump: true,
+            labels: BTreeMap::new(),
+            constants: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            macros: BTreeMap::new(),
+            expansions: 0,
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            symbols: SymbolMap::new(),
+            breakpoints: Vec::new(),
+        }
+    }
+
+    fn error(&self, token: &Token, message: String) -> CompileError {
+        CompileError {
+            file: self.file.to_string(),
+            line: token.line,
+            column: token.column,
+            message,
+        }
+    }
+
+    // Errors found at the end of the source point after its last token.
+    fn error_at_end(&self, message: String) -> CompileError {
+        let (line, column) = match self.tokens.last() {
+            Some(token) => (token.line, token.column + token.text.chars().count() as u32),
+            None => (1, 1),
+        };
+        CompileError {
+            file: self.file.to_string(),
+            line,
+            column,
+            message,
+        }
+    }
+
+    fn compile(mut self) -> Result<Program, CompileError> {
+        while self.pos < self.tokens.len() {
+            self.statement()?;
+        }
+        if let Some((token, block)) = self.blocks.last() {
+            let message = match block {
+                Block::Loop { .. } => "'loop' without 'again'",
+                _ => "'begin' without 'end'",
+            };
+            return Err(self.error(token, message.to_string()));
+        }
+        if self.main_jump {
+            let main = match self.labels.get("main") {
+                Some(&main) => main as usize,
+                None => return Err(self.error_at_end("missing ': main' label".to_string())),
+            };
+            self.patch_jump(ENTRY, main)
+                .map_err(|message| self.error_at_end(message))?;
+        }
+        for (token, at, fixup) in std::mem::take(&mut self.fixups) {
+            let addr = match self.labels.get(&token.text) {
+                Some(&addr) => addr as i64,
+                None => return Err(self.error(&token, format!("undefined name '{}'", token.text))),
+            };
+            self.patch(at, fixup, addr)
+                .map_err(|message| self.error(&token, message))?;
+        }
+
+        for (name, &addr) in self.labels.iter() {
+            self.symbols.insert_symbol(name, addr);
+        }
+        Ok(Program {
+            bytes: self.memory[ENTRY..self.end.max(ENTRY)].to_vec(),
+            symbols: self.symbols,
+            breakpoints: self.breakpoints,
+        })
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token, CompileError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(self.error_at_end(format!("expected {}", expected))),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, CompileError> {
+        let token = self.next(&format!("'{}'", text))?;
+        if token.text != text {
+            return Err(self.error(&token, format!("expected '{}', got '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn require(&self, token: &Token, target: Target) -> Result<(), CompileError> {
+        if self.target.supports(target) {
+            return Ok(());
+        }
+        let targets = match target {
+            Target::SuperChip => "SUPER-CHIP or XO-CHIP",
+            _ => "XO-CHIP",
+        };
+        Err(self.error(
+            token,
+            format!(
+                "'{}' is not available on {}, it needs {}",
+                token.text, self.target, targets
+            ),
+        ))
+    }
+
+    // A new name for a label, a constant, an alias or a macro.
+    fn name(&mut self) -> Result<Token, CompileError> {
+        let token = self.next("a name")?;
+        if !is_name(&token.text) {
+            return Err(self.error(&token, format!("'{}' cannot be used as a name", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn register(&mut self) -> Result<usize, CompileError> {
+        let token = self.next("a register")?;
+        self.register_of(&token)
+            .ok_or_else(|| self.error(&token, format!("expected a register, got '{}'", token.text)))
+    }
+
+    fn register_of(&self, token: &Token) -> Option<usize> {
+        register_name(&token.text).or_else(|| self.aliases.get(&token.text).cloned())
+    }
+
+    // A number, a constant or a `{ ... }` expression, with the token it
+    // starts at.
+    fn value(&mut self) -> Result<(Token, i64), CompileError> {
+        let token = self.next("a number")?;
+        let value = self.value_of(&token)?;
+        match value {
+            Some(value) => Ok((token, value)),
+            None => Err(self.error(&token, format!("expected a number, got '{}'", token.text))),
+        }
+    }
+
+    fn value_of(&mut self, token: &Token) -> Result<Option<i64>, CompileError> {
+        if token.text == "{" {
+            return self.calc(token).map(|value| Some(value as i64));
+        }
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(Some(value));
+        }
+        Ok(self.constants.get(&token.text).map(|&value| value as i64))
+    }
+
+    fn calc(&mut self, open: &Token) -> Result<f64, CompileError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(text) if text != "}") {
+            self.pos += 1;
+        }
+        let close = self.expect("}")?;
+        let tokens = &self.tokens[start..self.pos - 1];
+        let here = self.here as f64;
+        let lookup = |name: &str| match name {
+            "HERE" => Some(here),
+            _ => self
+                .constants
+                .get(name)
+                .cloned()
+                .or_else(|| self.labels.get(name).map(|&addr| addr as f64)),
+        };
+        let peek = |addr: f64| {
+            let addr = addr as usize % self.memory.len();
+            self.memory[addr] as f64
+        };
+        Calc::new(tokens, &lookup, &peek)
+            .evaluate()
+            .map_err(|(at, message)| {
+                let token = tokens.get(at).unwrap_or(&close);
+                if tokens.is_empty() {
+                    self.error(open, "empty expression".to_string())
+                } else {
+                    self.error(token, message)
+                }
+            })
+    }
+
+    fn byte(&mut self) -> Result<u8, CompileError> {
+        let (token, value) = self.value()?;
+        if !(-128..=255).contains(&value) {
+            return Err(self.error(&token, format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, CompileError> {
+        let (token, value) = self.value()?;
+        if !(0..=15).contains(&value) {
+            return Err(self.error(&token, format!("{} does not fit in 4 bits", value)));
+        }
+        Ok(value as u8)
+    }
+
+    // The operand of a jump, a call or `i :=`. Names of labels defined
+    // later are written when the program is complete.
+    fn address(&mut self, at: usize, fixup: Fixup) -> Result<(), CompileError> {
+        let token = self.next("an address")?;
+        let value = match self.labels.get(&token.text) {
+            Some(&addr) => Some(addr as i64),
+            None => self.value_of(&token)?,
+        };
+        match value {
+            Some(value) => self
+                .patch(at, fixup, value)
+                .map_err(|message| self.error(&token, message)),
+            None if is_name(&token.text) => {
+                self.fixups.push((token, at, fixup));
+                Ok(())
+            }
+            None => Err(self.error(&token, format!("expected an address, got '{}'", token.text))),
+        }
+    }
+
+    fn patch(&mut self, at: usize, fixup: Fixup, addr: i64) -> Result<(), String> {
+        let limit = match fixup {
+            Fixup::Nnn | Fixup::HighNibble => 0xFFF,
+            _ => 0xFFFF,
+        };
+        if !(0..=limit).contains(&addr) {
+            return Err(format!("address 0x{:X} is out of reach", addr));
+        }
+        let addr = addr as u16;
+        let size = self.memory.len();
+        match fixup {
+            Fixup::Nnn => {
+                self.memory[at] |= (addr >> 8) as u8;
+                self.memory[(at + 1) % size] = addr as u8;
+            }
+            Fixup::Word => {
+                self.memory[at] = (addr >> 8) as u8;
+                self.memory[(at + 1) % size] = addr as u8;
+            }
+            Fixup::HighNibble => self.memory[at] |= (addr >> 8) as u8,
+            Fixup::HighByte => self.memory[at] = (addr >> 8) as u8,
+            Fixup::LowByte => self.memory[at] = addr as u8,
+        }
+        Ok(())
+    }
+
+    fn patch_jump(&mut self, at: usize, target: usize) -> Result<(), String> {
+        self.memory[at] = 0x10;
+        self.patch(at, Fixup::Nnn, target as i64)
+    }
+
+    fn emit_byte(&mut self, token: &Token, byte: u8) -> Result<(), CompileError> {
+        if self.here >= self.memory.len() {
+            let message = format!(
+                "the program does not fit in the {} bytes of {} memory",
+                self.memory.len(),
+                self.target
+            );
+            return Err(self.error(token, message));
+        }
+        if self.used[self.here] {
+            let message = format!("0x{:X} is already used by the program", self.here);
+            return Err(self.error(token, message));
+        }
+        self.memory[self.here] = byte;
+        self.used[self.here] = true;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit_word(&mut self, token: &Token, word: u16) -> Result<(), CompileError> {
+        if self.here < 0x10000 {
+            self.symbols
+                .insert_line(self.here as u16, self.file, token.line);
+        }
+        self.emit_byte(token, (word >> 8) as u8)?;
+        self.emit_byte(token, word as u8)
+    }
+
+    fn emit(&mut self, token: &Token, instruction: Instruction) -> Result<(), CompileError> {
+        self.emit_word(token, instruction.encode())
+    }
+
+    fn define_label(&mut self, token: &Token, addr: usize) -> Result<(), CompileError> {
+        if self.labels.contains_key(&token.text) {
+            return Err(self.error(token, format!("'{}' is already defined", token.text)));
+        }
+        if token.text == "main" && self.main_jump && self.end == ENTRY + 2 && addr == ENTRY + 2 {
+            // nothing to jump over
+            self.main_jump = false;
+            self.used[ENTRY] = false;
+            self.used[ENTRY + 1] = false;
+            self.here = ENTRY;
+            self.end = ENTRY;
+            self.labels.insert(token.text.clone(), ENTRY as u16);
+            return Ok(());
+        }
+        self.labels.insert(token.text.clone(), addr as u16);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), CompileError> {
+        let token = self.next("a statement")?;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let (_, value) = self.value()?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let open = self.expect("{")?;
+                let value = self.calc(&open)?;
+                self.constants.insert(name.text, value);
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(&token, byte)?;
+            }
+            ":org" => {
+                let (at, addr) = self.value()?;
+                if !(ENTRY as i64..self.memory.len() as i64).contains(&addr) {
+                    let message = format!("0x{:X} is outside of the program memory", addr);
+                    return Err(self.error(&at, message));
+                }
+                self.here = addr as usize;
+            }
+            ":macro" => self.define_macro()?,
+            ":unpack" => {
+                // v0 and v1 := the address, with a nibble above it
+                let high = match self.peek() {
+                    Some("long") => {
+                        self.pos += 1;
+                        None
+                    }
+                    _ => Some(self.nibble()?),
+                };
+                let at = self.here;
+                let start = self.pos;
+                match high {
+                    Some(nibble) => {
+                        self.emit(&token, LdByte(0, nibble << 4))?;
+                        self.address(at + 1, Fixup::HighNibble)?;
+                    }
+                    None => {
+                        self.emit(&token, LdByte(0, 0))?;
+                        self.address(at + 1, Fixup::HighByte)?;
+                    }
+                }
+                self.pos = start;
+                self.emit(&token, LdByte(1, 0))?;
+                self.address(at + 3, Fixup::LowByte)?;
+            }
+            ":call" => {
+                let at = self.here;
+                self.emit(&token, Call(0))?;
+                self.address(at, Fixup::Nnn)?;
+            }
+            ":breakpoint" => {
+                self.name()?;
+                self.breakpoints.push(self.here as u16);
+            }
+            "clear" => self.emit(&token, Cls)?,
+            "return" | ";" => self.emit(&token, Ret)?,
+            "hires" | "lores" | "scroll-left" | "scroll-right" | "exit" => {
+                self.require(&token, Target::SuperChip)?;
+                let opcode = match token.text.as_str() {
+                    "hires" => 0x00FF,
+                    "lores" => 0x00FE,
+                    "scroll-left" => 0x00FC,
+                    "scroll-right" => 0x00FB,
+                    _ => 0x00FD,
+                };
+                self.emit_word(&token, opcode)?;
+            }
+            "scroll-down" => {
+                self.require(&token, Target::SuperChip)?;
+                let n = self.nibble()?;
+                self.emit_word(&token, 0x00C0 | n as u16)?;
+            }
+            "scroll-up" => {
+                self.require(&token, Target::XoChip)?;
+                let n = self.nibble()?;
+                self.emit_word(&token, 0x00D0 | n as u16)?;
+            }
+            "audio" => {
+                self.require(&token, Target::XoChip)?;
+                self.emit_word(&token, 0xF002)?;
+            }
+            "plane" => {
+                self.require(&token, Target::XoChip)?;
+                let (at, planes) = self.value()?;
+                if !(0..=3).contains(&planes) {
+                    return Err(self.error(&at, format!("there is no plane {}", planes)));
+                }
+                self.emit_word(&token, 0xF001 | (planes as u16) << 8)?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(&token, LdB(x))?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    self.require(&token, Target::XoChip)?;
+                    self.pos += 1;
+                    let y = self.register()?;
+                    let n = if token.text == "save" { 2 } else { 3 };
+                    self.emit_word(&token, 0x5000 | (x as u16) << 8 | (y as u16) << 4 | n)?;
+                } else if token.text == "save" {
+                    self.emit(&token, LdIVx(x))?;
+                } else {
+                    self.emit(&token, LdVxI(x))?;
+                }
+            }
+            "saveflags" | "loadflags" => {
+                self.require(&token, Target::SuperChip)?;
+                let x = self.register()?;
+                let kk = if token.text == "saveflags" {
+                    0x75
+                } else {
+                    0x85
+                };
+                self.emit_word(&token, 0xF000 | (x as u16) << 8 | kk)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                if n == 0 {
+                    self.require(&token, Target::SuperChip)?;
+                }
+                self.emit(&token, Drw(x, y, n))?;
+            }
+            "jump" | "jump0" | "native" => {
+                let at = self.here;
+                let instruction = match token.text.as_str() {
+                    "jump" => Jp(0),
+                    "jump0" => JpV0(0),
+                    _ => Sys(0),
+                };
+                self.emit(&token, instruction)?;
+                self.address(at, Fixup::Nnn)?;
+            }
+            "loop" => self.blocks.push((
+                token.clone(),
+                Block::Loop {
+                    start: self.here,
+                    breaks: Vec::new(),
+                },
+            )),
+            "while" => {
+                if !self
+                    .blocks
+                    .iter()
+                    .any(|(_, block)| matches!(block, Block::Loop { .. }))
+                {
+                    return Err(self.error(&token, "'while' outside of a loop".to_string()));
+                }
+                let condition = self.condition()?;
+                self.skip(&token, condition, true)?;
+                let at = self.here;
+                self.emit(&token, Jp(0))?;
+                for (_, block) in self.blocks.iter_mut().rev() {
+                    if let Block::Loop { breaks, .. } = block {
+                        breaks.push(at);
+                        break;
+                    }
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((_, Block::Loop { start, breaks })) => {
+                    self.emit(&token, Jp(0))?;
+                    self.patch_jump(self.here - 2, start)
+                        .map_err(|message| self.error(&token, message))?;
+                    for at in breaks {
+                        self.patch_jump(at, self.here)
+                            .map_err(|message| self.error(&token, message))?;
+                    }
+                }
+                Some((open, _)) => {
+                    return Err(self.error(&open, "'begin' without 'end'".to_string()));
+                }
+                None => return Err(self.error(&token, "'again' without 'loop'".to_string())),
+            },
+            "if" => {
+                let condition = self.condition()?;
+                let keyword = self.next("'then' or 'begin'")?;
+                match keyword.text.as_str() {
+                    "then" => self.skip(&token, condition, false)?,
+                    "begin" => {
+                        self.skip(&token, condition, true)?;
+                        let jump = self.here;
+                        self.emit(&token, Jp(0))?;
+                        self.blocks.push((token.clone(), Block::If { jump }));
+                    }
+                    _ => {
+                        let message = format!("expected 'then' or 'begin', got '{}'", keyword.text);
+                        return Err(self.error(&keyword, message));
+                    }
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((open, Block::If { jump })) => {
+                    let at = self.here;
+                    self.emit(&token, Jp(0))?;
+                    self.patch_jump(jump, self.here)
+                        .map_err(|message| self.error(&token, message))?;
+                    self.blocks.push((open, Block::Else { jump: at }));
+                }
+                _ => return Err(self.error(&token, "'else' without 'begin'".to_string())),
+            },
+            "end" => match self.blocks.pop() {
+                Some((_, Block::If { jump })) | Some((_, Block::Else { jump })) => {
+                    self.patch_jump(jump, self.here)
+                        .map_err(|message| self.error(&token, message))?;
+                }
+                _ => return Err(self.error(&token, "'end' without 'begin'".to_string())),
+            },
+            "i" => self.i_statement(&token)?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                match token.text.as_str() {
+                    "delay" => self.emit(&token, LdDtVx(x))?,
+                    "buzzer" => self.emit(&token, LdStVx(x))?,
+                    _ => {
+                        self.require(&token, Target::XoChip)?;
+                        self.emit_word(&token, 0xF03A | (x as u16) << 8)?;
+                    }
+                }
+            }
+            _ => {
+                if let Some(x) = self.register_of(&token) {
+                    return self.register_statement(&token, x);
+                }
+                if let Some(value) = self.value_of(&token)? {
+                    if !(-128..=255).contains(&value) {
+                        return Err(self.error(&token, format!("{} does not fit in a byte", value)));
+                    }
+                    return self.emit_byte(&token, value as u8);
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand(&token);
+                }
+                if !is_name(&token.text) {
+                    return Err(self.error(&token, format!("unexpected '{}'", token.text)));
+                }
+                // a bare name calls a subroutine
+                self.pos -= 1;
+                let at = self.here;
+                self.emit(&token, Call(0))?;
+                self.address(at, Fixup::Nnn)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn i_statement(&mut self, token: &Token) -> Result<(), CompileError> {
+        let op = self.next("':=' or '+='")?;
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.pos += 1;
+                    let x = self.register()?;
+                    self.emit(token, LdF(x))
+                }
+                Some("bighex") => {
+                    let bighex = self.next("'bighex'")?;
+                    self.require(&bighex, Target::SuperChip)?;
+                    let x = self.register()?;
+                    self.emit_word(token, 0xF030 | (x as u16) << 8)
+                }
+                Some("long") => {
+                    let long = self.next("'long'")?;
+                    self.require(&long, Target::XoChip)?;
+                    self.emit_word(token, 0xF000)?;
+                    let at = self.here;
+                    self.emit_byte(token, 0)?;
+                    self.emit_byte(token, 0)?;
+                    self.address(at, Fixup::Word)
+                }
+                _ => {
+                    let at = self.here;
+                    self.emit(token, LdI(0))?;
+                    self.address(at, Fixup::Nnn)
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(token, AddI(x))
+            }
+            _ => Err(self.error(&op, format!("expected ':=' or '+=', got '{}'", op.text))),
+        }
+    }
+
+    fn register_statement(&mut self, token: &Token, x: usize) -> Result<(), CompileError> {
+        let op = self.next("an operator")?;
+        let rhs = self.next("an operand")?;
+        let y = self.register_of(&rhs);
+        let instruction = match (op.text.as_str(), rhs.text.as_str(), y) {
+            (":=", "random", _) => Rnd(x, self.byte()?),
+            (":=", "key", _) => LdVxK(x),
+            (":=", "delay", _) => LdVxDt(x),
+            (":=", _, Some(y)) => LdReg(x, y),
+            ("+=", _, Some(y)) => AddReg(x, y),
+            ("-=", _, Some(y)) => Sub(x, y),
+            ("=-", _, Some(y)) => Subn(x, y),
+            ("|=", _, Some(y)) => Or(x, y),
+            ("&=", _, Some(y)) => And(x, y),
+            ("^=", _, Some(y)) => Xor(x, y),
+            (">>=", _, Some(y)) => Shr(x, y),
+            ("<<=", _, Some(y)) => Shl(x, y),
+            (":=", _, None) | ("+=", _, None) | ("-=", _, None) => {
+                self.pos -= 1;
+                let kk = self.byte()?;
+                match op.text.as_str() {
+                    ":=" => LdByte(x, kk),
+                    "+=" => AddByte(x, kk),
+                    _ => AddByte(x, kk.wrapping_neg()),
+                }
+            }
+            ("=-", _, None)
+            | ("|=", _, None)
+            | ("&=", _, None)
+            | ("^=", _, None)
+            | (">>=", _, None)
+            | ("<<=", _, None) => {
+                let message = format!("expected a register, got '{}'", rhs.text);
+                return Err(self.error(&rhs, message));
+            }
+            _ => return Err(self.error(&op, format!("unknown operator '{}'", op.text))),
+        };
+        self.emit(token, instruction)
+    }
+
+    fn condition(&mut self) -> Result<Condition, CompileError> {
+        let x = self.register()?;
+        let op = self.next("a comparison")?;
+        match op.text.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => (),
+            _ => return Err(self.error(&op, format!("unknown comparison '{}'", op.text))),
+        }
+        let rhs = match self
+            .tokens
+            .get(self.pos)
+            .and_then(|rhs| self.register_of(rhs))
+        {
+            Some(y) => {
+                self.pos += 1;
+                Rhs::Register(y)
+            }
+            None => Rhs::Byte(self.byte()?),
+        };
+        Ok(match op.text.as_str() {
+            "==" => Condition::Eq(x, rhs),
+            "!=" => Condition::Ne(x, rhs),
+            "<" => Condition::Lt(x, rhs),
+            ">" => Condition::Gt(x, rhs),
+            "<=" => Condition::Le(x, rhs),
+            _ => Condition::Ge(x, rhs),
+        })
+    }
+
+    // Emit the instructions skipping the next one when the condition is
+    // `when`. Comparisons go through VF.
+    fn skip(
+        &mut self,
+        token: &Token,
+        condition: Condition,
+        when: bool,
+    ) -> Result<(), CompileError> {
+        let instruction = match condition {
+            Condition::Eq(x, Rhs::Byte(kk)) if when => SeByte(x, kk),
+            Condition::Eq(x, Rhs::Byte(kk)) => SneByte(x, kk),
+            Condition::Ne(x, Rhs::Byte(kk)) if when => SneByte(x, kk),
+            Condition::Ne(x, Rhs::Byte(kk)) => SeByte(x, kk),
+            Condition::Eq(x, Rhs::Register(y)) if when => SeReg(x, y),
+            Condition::Eq(x, Rhs::Register(y)) => SneReg(x, y),
+            Condition::Ne(x, Rhs::Register(y)) if when => SneReg(x, y),
+            Condition::Ne(x, Rhs::Register(y)) => SeReg(x, y),
+            Condition::Key(x) if when => Skp(x),
+            Condition::Key(x) => Sknp(x),
+            Condition::NotKey(x) if when => Sknp(x),
+            Condition::NotKey(x) => Skp(x),
+            // VF is 0 for true
+            Condition::Lt(x, rhs) => {
+                self.compare(token, x, rhs, false)?;
+                SeByte(0xF, !when as u8)
+            }
+            Condition::Gt(x, rhs) => {
+                self.compare(token, x, rhs, true)?;
+                SeByte(0xF, !when as u8)
+            }
+            // VF is 1 for true
+            Condition::Ge(x, rhs) => {
+                self.compare(token, x, rhs, false)?;
+                SeByte(0xF, when as u8)
+            }
+            Condition::Le(x, rhs) => {
+                self.compare(token, x, rhs, true)?;
+                SeByte(0xF, when as u8)
+            }
+        };
+        self.emit(token, instruction)
+    }
+
+    // Set VF to 1 when vx >= rhs, or vx <= rhs when `less`, with the
+    // NOT borrow flag of a subtraction.
+    fn compare(
+        &mut self,
+        token: &Token,
+        x: usize,
+        rhs: Rhs,
+        less: bool,
+    ) -> Result<(), CompileError> {
+        let (load, subtract) = match rhs {
+            Rhs::Register(y) if less => (LdReg(0xF, x), Subn(0xF, y)),
+            Rhs::Register(y) => (LdReg(0xF, x), Sub(0xF, y)),
+            Rhs::Byte(kk) if less => (LdByte(0xF, kk), Sub(0xF, x)),
+            Rhs::Byte(kk) => (LdByte(0xF, kk), Subn(0xF, x)),
+        };
+        self.emit(token, load)?;
+        self.emit(token, subtract)
+    }
+
+    fn define_macro(&mut self) -> Result<(), CompileError> {
+        let name = self.name()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next("'{'")?;
+            if token.text == "{" {
+                break;
+            }
+            if !is_name(&token.text) {
+                return Err(
+                    self.error(&token, format!("'{}' cannot be used as a name", token.text))
+                );
+            }
+            args.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next("'}'").map_err(|_| {
+                self.error(&name, format!("macro '{}' has no closing '}}'", name.text))
+            })?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => (),
+            }
+            body.push(token);
+        }
+        self.macros.insert(
+            name.text,
+            Macro {
+                args,
+                body,
+                calls: 0,
+            },
+        );
+        Ok(())
+    }
+
+    // Replace a macro call by the body of the macro, with its arguments
+    // substituted.
+    fn expand(&mut self, token: &Token) -> Result<(), CompileError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(token, format!("macro '{}' expands forever", token.text)));
+        }
+        let definition = self.macros[&token.text].clone();
+        let mut args = Vec::new();
+        for _ in definition.args.iter() {
+            args.push(self.next(&format!(
+                "{} arguments to '{}'",
+                definition.args.len(),
+                token.text
+            ))?);
+        }
+        let body: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|body_token| {
+                match definition
+                    .args
+                    .iter()
+                    .position(|arg| *arg == body_token.text)
+                {
+                    Some(index) => args[index].clone(),
+                    None if body_token.text == "CALLS" => Token {
+                        text: definition.calls.to_string(),
+                        ..body_token.clone()
+                    },
+                    None => body_token.clone(),
+                }
+            })
+            .collect();
+        if let Some(definition) = self.macros.get_mut(&token.text) {
+            definition.calls += 1;
+        }
+        self.tokens.splice(self.pos..self.pos, body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, Target};
+    use crate::cpu::{Cpu, Register};
+
+    fn bytes(source: &str) -> Vec<u8> {
+        compile("test.8o", source, Target::Chip8).unwrap().bytes
+    }
+
+    fn error(source: &str, target: Target) -> String {
+        compile("test.8o", source, target).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn it_compiles_instructions_and_data() {
+        let program = compile(
+            "test.8o",
+            ": main
+               v0 := 5
+               v1 := v0
+               i := face
+               sprite v0 v1 3
+               loop again
+             : face
+               0xF0 0x90 0xF0",
+            Target::Chip8,
+        )
+        .unwrap();
+        assert_eq!(
+            program.bytes,
+            vec![0x60, 0x05, 0x81, 0x00, 0xA2, 0x0A, 0xD0, 0x13, 0x12, 0x08, 0xF0, 0x90, 0xF0]
+        );
+        assert_eq!(program.symbols.address("face"), Some(0x20A));
+        assert_eq!(program.symbols.source_line(0x206).unwrap().line, 5);
+        assert_eq!(program.symbols.source_line(0x206).unwrap().file, "test.8o");
+    }
+
+    #[test]
+    fn it_jumps_to_main() {
+        assert_eq!(
+            bytes(": data 0x12 : main data"),
+            vec![0x12, 0x03, 0x12, 0x22, 0x02]
+        );
+    }
+
+    #[test]
+    fn it_compiles_control_structures() {
+        assert_eq!(
+            bytes(
+                ": main
+                   loop
+                     v0 += 1
+                     while v0 != 10
+                     if v0 == 3 then v1 := 1
+                     if v0 key begin
+                       clear
+                     else
+                       return
+                     end
+                   again"
+            ),
+            vec![
+                0x70, 0x01, 0x40, 0x0A, 0x12, 0x16, 0x40, 0x03, 0x61, 0x01, 0xE0, 0x9E, 0x12, 0x12,
+                0x00, 0xE0, 0x12, 0x14, 0x00, 0xEE, 0x12, 0x00
+            ]
+        );
+        // comparisons go through VF
+        assert_eq!(
+            bytes(": main if v1 < v2 then v0 := 1 if v1 >= 7 then v0 := 2"),
+            vec![
+                0x8F, 0x10, 0x8F, 0x25, 0x3F, 0x01, 0x60, 0x01, 0x6F, 0x07, 0x8F, 0x17, 0x3F, 0x00,
+                0x60, 0x02
+            ]
+        );
+    }
+
+    #[test]
+    fn it_compiles_directives() {
+        let program = compile(
+            "test.8o",
+            ":alias counter v3
+             :const SPEED 4
+             :calc DOUBLE { SPEED * 2 }
+             :macro twice reg { reg += DOUBLE reg += DOUBLE }
+             : main
+               counter := SPEED
+               twice counter
+               :unpack 0xA target
+               jump target
+             :next patched
+               v4 := -1
+             :org 0x300
+             : target
+               :byte { target >> 4 }",
+            Target::Chip8,
+        )
+        .unwrap();
+        assert_eq!(
+            program.bytes[..0x0E],
+            [0x63, 0x04, 0x73, 0x08, 0x73, 0x08, 0x60, 0xA3, 0x61, 0x00, 0x13, 0x00, 0x64, 0xFF]
+        );
+        assert_eq!(program.bytes.len(), 0x101);
+        assert_eq!(program.bytes[0x100], 0x30);
+        assert_eq!(program.symbols.address("patched"), Some(0x20D));
+    }
+
+    #[test]
+    fn it_compiles_for_the_extended_targets() {
+        let program = compile("test.8o", ": main hires sprite v0 v0 0", Target::SuperChip);
+        assert_eq!(program.unwrap().bytes, vec![0x00, 0xFF, 0xD0, 0x00]);
+        let program = compile("test.8o", ": main i := long data : data", Target::XoChip);
+        assert_eq!(program.unwrap().bytes, vec![0xF0, 0x00, 0x02, 0x04]);
+    }
+
+    #[test]
+    fn it_reports_errors_with_their_position() {
+        assert_eq!(
+            error(": main jump nowhere", Target::Chip8),
+            "test.8o:1:13: undefined name 'nowhere'"
+        );
+        assert_eq!(
+            error("v0 := 1", Target::Chip8),
+            "test.8o:1:8: missing ': main' label"
+        );
+        assert_eq!(
+            error(": main\n  hires", Target::Chip8),
+            "test.8o:2:3: 'hires' is not available on CHIP-8, it needs SUPER-CHIP or XO-CHIP"
+        );
+        assert_eq!(
+            error(": main save v0 - v3", Target::SuperChip),
+            "test.8o:1:8: 'save' is not available on SUPER-CHIP, it needs XO-CHIP"
+        );
+        assert_eq!(
+            error(": main v0 := 256", Target::Chip8),
+            "test.8o:1:14: 256 does not fit in a byte"
+        );
+        assert_eq!(
+            error(": main loop", Target::Chip8),
+            "test.8o:1:8: 'loop' without 'again'"
+        );
+        assert_eq!(
+            error(": main end", Target::Chip8),
+            "test.8o:1:8: 'end' without 'begin'"
+        );
+        assert_eq!(
+            error(": main : main", Target::Chip8),
+            "test.8o:1:10: 'main' is already defined"
+        );
+        assert_eq!(
+            error(": main v0 @ v1", Target::Chip8),
+            "test.8o:1:11: unknown operator '@'"
+        );
+        assert_eq!(
+            error(": main :calc X { 1 + }", Target::Chip8),
+            "test.8o:1:22: incomplete expression"
+        );
+        assert_eq!(
+            error(": main :macro forever { forever } forever", Target::Chip8),
+            "test.8o:1:25: macro 'forever' expands forever"
+        );
+    }
+
+    #[test]
+    fn compiled_programs_run() {
+        let program = compile(
+            "test.8o",
+            ": main
+               v0 := 0
+               loop
+                 v0 += 1
+                 while v0 != 10
+               again
+               v1 := 3
+               v2 := 5
+               if v1 < v2 then v5 := 1
+               if v1 > v2 then v6 := 1
+               i := result
+               save v0
+               loop again
+             : result 0",
+            Target::Chip8,
+        )
+        .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_cartridge(program.cartridge());
+        cpu.run_frame(100);
+        let result = program.symbols.address("result").unwrap();
+        assert_eq!(cpu.peek(result), 10);
+        assert_eq!(cpu.register(Register::V(5)), 1);
+        assert_eq!(cpu.register(Register::V(6)), 0);
+    }
+
+    #[test]
+    fn programs_load_into_the_cpu() {
+        let mut cpu = Cpu::new();
+        assert_eq!(
+            cpu.load_octo("program.8o", ": main jump nowhere", Target::Chip8),
+            Err("program.8o:1:13: undefined name 'nowhere'".to_string())
+        );
+        cpu.load_octo(
+            "program.8o",
+            ": main v0 := 1 :breakpoint stop v0 := 2",
+            Target::Chip8,
+        )
+        .unwrap();
+        cpu.run_frame(10);
+        assert_eq!(cpu.register(Register::V(0)), 1);
+        assert_eq!(
+            cpu.break_message().unwrap(),
+            "breakpoint at main+0x2 (program.8o:1)"
+        );
+    }
+
+    #[test]
+    fn programs_load_for_the_given_target() {
+        let mut cpu = Cpu::new();
+        assert_eq!(
+            cpu.load_octo("game.8o", ": main hires", Target::Chip8),
+            Err(
+                "game.8o:1:8: 'hires' is not available on CHIP-8, it needs SUPER-CHIP or XO-CHIP"
+                    .to_string()
+            )
+        );
+        cpu.load_octo("game.8o", ": main hires", Target::SuperChip)
+            .unwrap();
+        cpu.run_frame(1);
+        assert_eq!(cpu.display_width(), 128);
+    }
+}
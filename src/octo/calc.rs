@@ -0,0 +1,172 @@
+// The expressions of `:calc` and `{ ... }` operands. Like in Octo, there is
+// no operator precedence: binary operators group to the right, so
+// `2 * 3 + 1` is 8. Parentheses group explicitly.
+
+use super::lexer::Token;
+use super::parse_number;
+
+const UNARY: &[&str] = &[
+    "-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor", "@",
+];
+const BINARY: &[&str] = &[
+    "-", "+", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", "<=", ">", ">=",
+    "==", "!=",
+];
+
+// The index of the token an error is about, or the number of tokens when
+// the expression ended too soon.
+pub type CalcError = (usize, String);
+
+pub struct Calc<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    // value of a name: a constant, a label, HERE...
+    lookup: &'a dyn Fn(&str) -> Option<f64>,
+    // byte of the program at an address, for `@`
+    peek: &'a dyn Fn(f64) -> f64,
+}
+
+impl<'a> Calc<'a> {
+    pub fn new(
+        tokens: &'a [Token],
+        lookup: &'a dyn Fn(&str) -> Option<f64>,
+        peek: &'a dyn Fn(f64) -> f64,
+    ) -> Self {
+        Calc {
+            tokens,
+            pos: 0,
+            lookup,
+            peek,
+        }
+    }
+
+    // Evaluate all the tokens as a single expression.
+    pub fn evaluate(mut self) -> Result<f64, CalcError> {
+        let value = self.expression()?;
+        match self.tokens.get(self.pos) {
+            Some(token) => Err((self.pos, format!("unexpected '{}'", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn next(&mut self) -> Result<&'a str, CalcError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| (self.pos, "incomplete expression".to_string()))?;
+        self.pos += 1;
+        Ok(&token.text)
+    }
+
+    fn expression(&mut self) -> Result<f64, CalcError> {
+        let left = self.term()?;
+        let op = match self.tokens.get(self.pos) {
+            Some(token) if BINARY.contains(&token.text.as_str()) => token.text.as_str(),
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.expression()?;
+        let (a, b) = (left as i64, right as i64);
+        let truth = |value: bool| if value { 1.0 } else { 0.0 };
+        Ok(match op {
+            "-" => left - right,
+            "+" => left + right,
+            "*" => left * right,
+            "/" if right == 0.0 => return Err((self.pos - 1, "division by zero".to_string())),
+            "/" => left / right,
+            "%" if b == 0 => return Err((self.pos - 1, "division by zero".to_string())),
+            "%" => a.wrapping_rem(b) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => truth(left < right),
+            "<=" => truth(left <= right),
+            ">" => truth(left > right),
+            ">=" => truth(left >= right),
+            "==" => truth(left == right),
+            _ => truth(left != right),
+        })
+    }
+
+    fn term(&mut self) -> Result<f64, CalcError> {
+        let at = self.pos;
+        let text = self.next()?;
+        if UNARY.contains(&text) {
+            let value = self.term()?;
+            return Ok(match text {
+                "-" => -value,
+                "~" => !(value as i64) as f64,
+                "!" => (value == 0.0) as u8 as f64,
+                "sin" => value.sin(),
+                "cos" => value.cos(),
+                "tan" => value.tan(),
+                "exp" => value.exp(),
+                "log" => value.ln(),
+                "abs" => value.abs(),
+                "sqrt" => value.sqrt(),
+                "sign" if value == 0.0 => 0.0,
+                "sign" => value.signum(),
+                "ceil" => value.ceil(),
+                "floor" => value.floor(),
+                _ => (self.peek)(value),
+            });
+        }
+        if text == "(" {
+            let value = self.expression()?;
+            return match self.next()? {
+                ")" => Ok(value),
+                other => Err((self.pos - 1, format!("expected ')', got '{}'", other))),
+            };
+        }
+        if let Some(value) = parse_number(text) {
+            return Ok(value as f64);
+        }
+        if let Ok(value) = text.parse::<f64>() {
+            return Ok(value);
+        }
+        match text {
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => (self.lookup)(text).ok_or_else(|| (at, format!("undefined name '{}'", text))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Calc;
+    use crate::octo::lexer::tokenize;
+
+    fn calc(expression: &str) -> Result<f64, String> {
+        let tokens = tokenize(expression);
+        let lookup = |name: &str| if name == "WIDTH" { Some(64.0) } else { None };
+        let peek = |addr: f64| addr + 1.0;
+        Calc::new(&tokens, &lookup, &peek)
+            .evaluate()
+            .map_err(|(_, message)| message)
+    }
+
+    #[test]
+    fn it_groups_to_the_right() {
+        assert_eq!(calc("2 * 3 + 1"), Ok(8.0));
+        assert_eq!(calc("( 2 * 3 ) + 1"), Ok(7.0));
+        assert_eq!(calc("WIDTH - 0x10 - 8"), Ok(56.0));
+        assert_eq!(calc("- 1 + 0b11"), Ok(2.0));
+        assert_eq!(calc("1 << 4 | 1"), Ok(32.0));
+        assert_eq!(calc("floor ( 7 / 2 )"), Ok(3.0));
+        assert_eq!(calc("@ 0x200"), Ok(513.0));
+    }
+
+    #[test]
+    fn it_reports_errors() {
+        assert_eq!(calc("1 +"), Err("incomplete expression".to_string()));
+        assert_eq!(calc("HEIGHT"), Err("undefined name 'HEIGHT'".to_string()));
+        assert_eq!(calc("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(calc("1 2"), Err("unexpected '2'".to_string()));
+    }
+}
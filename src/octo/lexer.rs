@@ -0,0 +1,60 @@
+// Octo source is a stream of whitespace separated tokens, with comments
+// from `#` to the end of the line. Braces and parentheses are tokens on
+// their own even when they touch their neighbours.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c == '{' || c == '}' || c == '(' || c == ')'
+}
+
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut start = None;
+        for (offset, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+            if c.is_whitespace() || is_delimiter(c) {
+                if let Some(start) = start.take() {
+                    tokens.push(Token {
+                        text: line[start..offset].to_string(),
+                        line: number as u32 + 1,
+                        column: line[..start].chars().count() as u32 + 1,
+                    });
+                }
+                if is_delimiter(c) {
+                    tokens.push(Token {
+                        text: c.to_string(),
+                        line: number as u32 + 1,
+                        column: line[..offset].chars().count() as u32 + 1,
+                    });
+                }
+            } else if start.is_none() {
+                start = Some(offset);
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tokenize;
+
+    #[test]
+    fn it_splits_tokens() {
+        let tokens = tokenize(": main # entry\n  v0 := {1+2}");
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(texts, vec![":", "main", "v0", ":=", "{", "1+2", "}"]);
+        assert_eq!((tokens[2].line, tokens[2].column), (2, 3));
+        assert_eq!((tokens[6].line, tokens[6].column), (2, 13));
+    }
+}
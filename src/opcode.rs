@@ -0,0 +1,374 @@
+// Decoding of the CHIP-8 instructions, with the mnemonics of Cowgod's
+// Chip-8 Technical Reference v1.0, and of the SUPER-CHIP resolution
+// switches the cpu runs.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // 0nnn - SYS addr
+    Sys(u16),
+    // 00E0 - CLS
+    Cls,
+    // 00EE - RET
+    Ret,
+    // 00FE - LOW, SUPER-CHIP
+    Low,
+    // 00FF - HIGH, SUPER-CHIP
+    High,
+    // 1nnn - JP addr
+    Jp(u16),
+    // 2nnn - CALL addr
+    Call(u16),
+    // 3xkk - SE Vx, byte
+    SeByte(usize, u8),
+    // 4xkk - SNE Vx, byte
+    SneByte(usize, u8),
+    // 5xy0 - SE Vx, Vy
+    SeReg(usize, usize),
+    // 6xkk - LD Vx, byte
+    LdByte(usize, u8),
+    // 7xkk - ADD Vx, byte
+    AddByte(usize, u8),
+    // 8xy0 - LD Vx, Vy
+    LdReg(usize, usize),
+    // 8xy1 - OR Vx, Vy
+    Or(usize, usize),
+    // 8xy2 - AND Vx, Vy
+    And(usize, usize),
+    // 8xy3 - XOR Vx, Vy
+    Xor(usize, usize),
+    // 8xy4 - ADD Vx, Vy
+    AddReg(usize, usize),
+    // 8xy5 - SUB Vx, Vy
+    Sub(usize, usize),
+    // 8xy6 - SHR Vx {, Vy}
+    Shr(usize, usize),
+    // 8xy7 - SUBN Vx, Vy
+    Subn(usize, usize),
+    // 8xyE - SHL Vx {, Vy}
+    Shl(usize, usize),
+    // 9xy0 - SNE Vx, Vy
+    SneReg(usize, usize),
+    // Annn - LD I, addr
+    LdI(u16),
+    // Bnnn - JP V0, addr
+    JpV0(u16),
+    // Cxkk - RND Vx, byte
+    Rnd(usize, u8),
+    // Dxyn - DRW Vx, Vy, nibble
+    Drw(usize, usize, u8),
+    // Ex9E - SKP Vx
+    Skp(usize),
+    // ExA1 - SKNP Vx
+    Sknp(usize),
+    // Fx07 - LD Vx, DT
+    LdVxDt(usize),
+    // Fx0A - LD Vx, K
+    LdVxK(usize),
+    // Fx15 - LD DT, Vx
+    LdDtVx(usize),
+    // Fx18 - LD ST, Vx
+    LdStVx(usize),
+    // Fx1E - ADD I, Vx
+    AddI(usize),
+    // Fx29 - LD F, Vx
+    LdF(usize),
+    // Fx33 - LD B, Vx
+    LdB(usize),
+    // Fx55 - LD [I], Vx
+    LdIVx(usize),
+    // Fx65 - LD Vx, [I]
+    LdVxI(usize),
+}
+
+use self::Instruction::*;
+
+impl Instruction {
+    // Decode an opcode, returning None if it is not a CHIP-8 instruction.
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+
+        let instruction = match ((opcode & 0xF000) >> 12, kk, n) {
+            (0x0, 0xE0, _) if x == 0 => Cls,
+            (0x0, 0xEE, _) if x == 0 => Ret,
+            (0x0, 0xFE, _) if x == 0 => Low,
+            (0x0, 0xFF, _) if x == 0 => High,
+            (0x0, _, _) => Sys(nnn),
+            (0x1, _, _) => Jp(nnn),
+            (0x2, _, _) => Call(nnn),
+            (0x3, _, _) => SeByte(x, kk),
+            (0x4, _, _) => SneByte(x, kk),
+            (0x5, _, 0x0) => SeReg(x, y),
+            (0x6, _, _) => LdByte(x, kk),
+            (0x7, _, _) => AddByte(x, kk),
+            (0x8, _, 0x0) => LdReg(x, y),
+            (0x8, _, 0x1) => Or(x, y),
+            (0x8, _, 0x2) => And(x, y),
+            (0x8, _, 0x3) => Xor(x, y),
+            (0x8, _, 0x4) => AddReg(x, y),
+            (0x8, _, 0x5) => Sub(x, y),
+            (0x8, _, 0x6) => Shr(x, y),
+            (0x8, _, 0x7) => Subn(x, y),
+            (0x8, _, 0xE) => Shl(x, y),
+            (0x9, _, 0x0) => SneReg(x, y),
+            (0xA, _, _) => LdI(nnn),
+            (0xB, _, _) => JpV0(nnn),
+            (0xC, _, _) => Rnd(x, kk),
+            (0xD, _, _) => Drw(x, y, n),
+            (0xE, 0x9E, _) => Skp(x),
+            (0xE, 0xA1, _) => Sknp(x),
+            (0xF, 0x07, _) => LdVxDt(x),
+            (0xF, 0x0A, _) => LdVxK(x),
+            (0xF, 0x15, _) => LdDtVx(x),
+            (0xF, 0x18, _) => LdStVx(x),
+            (0xF, 0x1E, _) => AddI(x),
+            (0xF, 0x29, _) => LdF(x),
+            (0xF, 0x33, _) => LdB(x),
+            (0xF, 0x55, _) => LdIVx(x),
+            (0xF, 0x65, _) => LdVxI(x),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    // Encode the instruction back to its opcode.
+    pub fn encode(&self) -> u16 {
+        let xkk = |op: u16, x: usize, kk: u8| op << 12 | (x as u16) << 8 | kk as u16;
+        let xyn = |op: u16, x: usize, y: usize, n: u8| {
+            op << 12 | (x as u16) << 8 | (y as u16) << 4 | n as u16
+        };
+        match *self {
+            Sys(nnn) => nnn & 0x0FFF,
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            SeByte(x, kk) => xkk(0x3, x, kk),
+            SneByte(x, kk) => xkk(0x4, x, kk),
+            SeReg(x, y) => xyn(0x5, x, y, 0x0),
+            LdByte(x, kk) => xkk(0x6, x, kk),
+            AddByte(x, kk) => xkk(0x7, x, kk),
+            LdReg(x, y) => xyn(0x8, x, y, 0x0),
+            Or(x, y) => xyn(0x8, x, y, 0x1),
+            And(x, y) => xyn(0x8, x, y, 0x2),
+            Xor(x, y) => xyn(0x8, x, y, 0x3),
+            AddReg(x, y) => xyn(0x8, x, y, 0x4),
+            Sub(x, y) => xyn(0x8, x, y, 0x5),
+            Shr(x, y) => xyn(0x8, x, y, 0x6),
+            Subn(x, y) => xyn(0x8, x, y, 0x7),
+            Shl(x, y) => xyn(0x8, x, y, 0xE),
+            SneReg(x, y) => xyn(0x9, x, y, 0x0),
+            LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            JpV0(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Rnd(x, kk) => xkk(0xC, x, kk),
+            Drw(x, y, n) => xyn(0xD, x, y, n),
+            Skp(x) => xkk(0xE, x, 0x9E),
+            Sknp(x) => xkk(0xE, x, 0xA1),
+            LdVxDt(x) => xkk(0xF, x, 0x07),
+            LdVxK(x) => xkk(0xF, x, 0x0A),
+            LdDtVx(x) => xkk(0xF, x, 0x15),
+            LdStVx(x) => xkk(0xF, x, 0x18),
+            AddI(x) => xkk(0xF, x, 0x1E),
+            LdF(x) => xkk(0xF, x, 0x29),
+            LdB(x) => xkk(0xF, x, 0x33),
+            LdIVx(x) => xkk(0xF, x, 0x55),
+            LdVxI(x) => xkk(0xF, x, 0x65),
+        }
+    }
+
+    // The form of the instruction without its operands, as listed in
+    // Cowgod's reference: "LD Vx, byte", "DRW Vx, Vy, nibble"...
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Sys(_) => "SYS addr",
+            Cls => "CLS",
+            Ret => "RET",
+            Low => "LOW",
+            High => "HIGH",
+            Jp(_) => "JP addr",
+            Call(_) => "CALL addr",
+            SeByte(_, _) => "SE Vx, byte",
+            SneByte(_, _) => "SNE Vx, byte",
+            SeReg(_, _) => "SE Vx, Vy",
+            LdByte(_, _) => "LD Vx, byte",
+            AddByte(_, _) => "ADD Vx, byte",
+            LdReg(_, _) => "LD Vx, Vy",
+            Or(_, _) => "OR Vx, Vy",
+            And(_, _) => "AND Vx, Vy",
+            Xor(_, _) => "XOR Vx, Vy",
+            AddReg(_, _) => "ADD Vx, Vy",
+            Sub(_, _) => "SUB Vx, Vy",
+            Shr(_, _) => "SHR Vx {, Vy}",
+            Subn(_, _) => "SUBN Vx, Vy",
+            Shl(_, _) => "SHL Vx {, Vy}",
+            SneReg(_, _) => "SNE Vx, Vy",
+            LdI(_) => "LD I, addr",
+            JpV0(_) => "JP V0, addr",
+            Rnd(_, _) => "RND Vx, byte",
+            Drw(_, _, _) => "DRW Vx, Vy, nibble",
+            Skp(_) => "SKP Vx",
+            Sknp(_) => "SKNP Vx",
+            LdVxDt(_) => "LD Vx, DT",
+            LdVxK(_) => "LD Vx, K",
+            LdDtVx(_) => "LD DT, Vx",
+            LdStVx(_) => "LD ST, Vx",
+            AddI(_) => "ADD I, Vx",
+            LdF(_) => "LD F, Vx",
+            LdB(_) => "LD B, Vx",
+            LdIVx(_) => "LD [I], Vx",
+            LdVxI(_) => "LD Vx, [I]",
+        }
+    }
+
+    // Whether the instruction conditionally skips the next one.
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            *self,
+            SeByte(_, _) | SneByte(_, _) | SeReg(_, _) | SneReg(_, _) | Skp(_) | Sknp(_)
+        )
+    }
+
+    // Memory range written by the instruction, given the value of I.
+    pub fn memory_write(&self, i: u16) -> Option<(u16, u16)> {
+        match *self {
+            LdB(_) => Some((i, 3)),
+            LdIVx(x) => Some((i, x as u16 + 1)),
+            _ => None,
+        }
+    }
+
+    // Memory range read as data by the instruction, given the value of I.
+    // Dxy0 draws a 16x16 sprite of 32 bytes in the high resolution.
+    pub fn memory_read(&self, i: u16) -> Option<(u16, u16)> {
+        match *self {
+            Drw(_, _, 0) => Some((i, 32)),
+            Drw(_, _, n) => Some((i, n as u16)),
+            LdVxI(x) => Some((i, x as u16 + 1)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            Jp(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            SeByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            SneByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            LdByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            JpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Rnd(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            LdF(x) => write!(f, "LD F, V{:X}", x),
+            LdB(x) => write!(f, "LD B, V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
+}
+
+// Disassemble an opcode, unknown opcodes are shown as raw data.
+pub fn disassemble(opcode: u16) -> String {
+    match Instruction::decode(opcode) {
+        Some(instruction) => instruction.to_string(),
+        None => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_instructions() {
+        assert_eq!(Instruction::decode(0x00E0), Some(Cls));
+        assert_eq!(Instruction::decode(0x00EE), Some(Ret));
+        assert_eq!(Instruction::decode(0x0123), Some(Sys(0x123)));
+        assert_eq!(Instruction::decode(0x00FE), Some(Low));
+        assert_eq!(Instruction::decode(0x00FF), Some(High));
+        assert_eq!(Instruction::decode(0x01FF), Some(Sys(0x1FF)));
+        assert_eq!(Instruction::decode(0x2ABC), Some(Call(0xABC)));
+        assert_eq!(Instruction::decode(0x8AB4), Some(AddReg(0xA, 0xB)));
+        assert_eq!(Instruction::decode(0xD125), Some(Drw(1, 2, 5)));
+        assert_eq!(Instruction::decode(0xF365), Some(LdVxI(3)));
+    }
+
+    #[test]
+    fn it_names_instruction_kinds() {
+        assert_eq!(Drw(1, 2, 5).kind(), "DRW Vx, Vy, nibble");
+        assert_eq!(LdIVx(3).kind(), "LD [I], Vx");
+        assert_eq!(Shl(1, 2).kind(), "SHL Vx {, Vy}");
+        assert_eq!(High.kind(), "HIGH");
+    }
+
+    #[test]
+    fn it_rejects_unknown_opcodes() {
+        assert_eq!(Instruction::decode(0x5121), None);
+        assert_eq!(Instruction::decode(0x8128), None);
+        assert_eq!(Instruction::decode(0xE19F), None);
+        assert_eq!(Instruction::decode(0xF0FF), None);
+    }
+
+    #[test]
+    fn encode_is_the_inverse_of_decode() {
+        for opcode in 0..=0xFFFF {
+            if let Some(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{:04X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn it_formats_cowgod_mnemonics() {
+        assert_eq!(disassemble(0x6AFF), "LD VA, 0xFF");
+        assert_eq!(disassemble(0xB300), "JP V0, 0x300");
+        assert_eq!(disassemble(0xD01F), "DRW V0, V1, 15");
+        assert_eq!(disassemble(0xF255), "LD [I], V2");
+        assert_eq!(disassemble(0xFFFF), "DW 0xFFFF");
+        assert_eq!(disassemble(0x00FE), "LOW");
+        assert_eq!(disassemble(0x00FF), "HIGH");
+    }
+
+    #[test]
+    fn it_reports_memory_accesses() {
+        assert_eq!(LdB(0).memory_write(0x300), Some((0x300, 3)));
+        assert_eq!(LdIVx(2).memory_write(0x300), Some((0x300, 3)));
+        assert_eq!(Drw(0, 0, 5).memory_read(0x200), Some((0x200, 5)));
+        assert_eq!(Drw(0, 0, 0).memory_read(0x200), Some((0x200, 32)));
+        assert_eq!(LdVxI(0).memory_read(0x200), Some((0x200, 1)));
+        assert_eq!(Cls.memory_write(0x200), None);
+    }
+}
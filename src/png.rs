@@ -0,0 +1,375 @@
+// Minimal PNG encoder for indexed color images.
+// The pixel data is compressed with a small deflate implementation (greedy
+// LZ77 with the fixed Huffman codes), which works well enough on the large
+// flat areas of scaled up CHIP-8 screenshots.
+
+use std::fmt;
+
+use super::checksum::{adler32, crc32};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// indexed color, one byte per pixel
+const COLOR_TYPE_INDEXED: u8 = 3;
+const BIT_DEPTH: u8 = 8;
+// the PNG specification limits both dimensions to 2^31 - 1
+const MAX_DIMENSION: u32 = i32::MAX as u32;
+
+// Reason an image could not be encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PngError {
+    // a dimension is past the PNG limit, or the image does not fit in memory
+    TooLarge { width: u64, height: u64 },
+    // the pixels are not `width` x `height`
+    BadLength { expected: usize, actual: usize },
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PngError::TooLarge { width, height } => {
+                write!(f, "a {}x{} image is too large to encode", width, height)
+            }
+            PngError::BadLength { expected, actual } => {
+                write!(f, "expected {} pixels, got {}", expected, actual)
+            }
+        }
+    }
+}
+
+// Encode a `width` x `height` image whose pixels are indexes into `palette`.
+pub fn encode_indexed(
+    width: u32,
+    height: u32,
+    palette: &[[u8; 4]],
+    pixels: &[u8],
+) -> Result<Vec<u8>, PngError> {
+    let too_large = PngError::TooLarge {
+        width: width as u64,
+        height: height as u64,
+    };
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(too_large);
+    }
+    // every scanline also starts with its filter type
+    let expected = (width as usize)
+        .checked_mul(height as usize)
+        .ok_or(too_large)?;
+    let scanlines_len = expected.checked_add(height as usize).ok_or(too_large)?;
+    if pixels.len() != expected {
+        return Err(PngError::BadLength {
+            expected,
+            actual: pixels.len(),
+        });
+    }
+    assert!(!palette.is_empty() && palette.len() <= 256);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // bit depth, color type, compression, filter and interlace methods
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_INDEXED, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    let colors: Vec<u8> = palette
+        .iter()
+        .flat_map(|rgba| rgba[0..3].to_vec())
+        .collect();
+    write_chunk(&mut png, b"PLTE", &colors);
+    if palette.iter().any(|rgba| rgba[3] != 0xFF) {
+        let alphas: Vec<u8> = palette.iter().map(|rgba| rgba[3]).collect();
+        write_chunk(&mut png, b"tRNS", &alphas);
+    }
+
+    // every scanline starts with its filter type: 0, no filter
+    let mut scanlines = Vec::with_capacity(scanlines_len);
+    for row in pixels.chunks(width.max(1) as usize) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_compress(&scanlines));
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    // the CRC covers the chunk type and the data
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, no preset dictionary
+    let mut stream = vec![0x78, 0x01];
+    stream.extend_from_slice(&deflate(data));
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+// Write bits least significant first, as deflate expects.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored most significant bit first.
+    fn write_code(&mut self, code: u32, count: u32) {
+        let reversed = code.reverse_bits() >> (32 - count);
+        self.write_bits(reversed, count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+
+// base length and extra bits of the length codes 257..=285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// base distance and extra bits of the distance codes 0..=29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+fn write_literal(writer: &mut BitWriter, symbol: u32) {
+    // fixed Huffman codes from RFC 1951, section 3.2.6
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_literal(writer, 257 + code as u32);
+    writer.write_bits(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    writer.write_code(code as u32, 5);
+    writer.write_bits(
+        (distance - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA[code] as u32,
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    // a single final block compressed with the fixed codes
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    // last position seen for every hash of three bytes
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut length = 0;
+        let mut distance = 0;
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            let candidate = head[h];
+            head[h] = pos;
+            if candidate != usize::MAX && pos - candidate <= WINDOW_SIZE {
+                let max = MAX_MATCH.min(data.len() - pos);
+                while length < max && data[candidate + length] == data[pos + length] {
+                    length += 1;
+                }
+                distance = pos - candidate;
+            }
+        }
+
+        if length >= MIN_MATCH {
+            write_match(&mut writer, length, distance);
+            // index the positions covered by the match
+            for p in pos + 1..(pos + length).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+                head[hash(&data[p..])] = p;
+            }
+            pos += length;
+        } else {
+            write_literal(&mut writer, data[pos] as u32);
+            pos += 1;
+        }
+    }
+    // end of block
+    write_literal(&mut writer, 256);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decoder for the subset of deflate produced by the encoder.
+    fn inflate_fixed(data: &[u8]) -> Vec<u8> {
+        let mut bit = 0;
+        let mut read_bits = |count: u32| -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let b = (data[bit / 8] >> (bit % 8)) & 1;
+                value |= (b as u32) << i;
+                bit += 1;
+            }
+            value
+        };
+        assert_eq!(read_bits(1), 1, "final block");
+        assert_eq!(read_bits(2), 1, "fixed Huffman codes");
+
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            // read a literal/length code, most significant bit first
+            let mut code = 0;
+            let mut len = 0;
+            let symbol = loop {
+                code = (code << 1) | read_bits(1);
+                len += 1;
+                match (len, code) {
+                    (7, 0..=0x17) => break code + 256,
+                    (8, 0x30..=0xBF) => break code - 0x30,
+                    (8, 0xC0..=0xC7) => break code - 0xC0 + 280,
+                    (9, 0x190..=0x1FF) => break code - 0x190 + 144,
+                    _ => assert!(len < 9),
+                }
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let idx = (symbol - 257) as usize;
+                    let length =
+                        LENGTH_BASE[idx] as usize + read_bits(LENGTH_EXTRA[idx] as u32) as usize;
+                    let mut dcode = 0;
+                    for _ in 0..5 {
+                        dcode = (dcode << 1) | read_bits(1);
+                    }
+                    let dcode = dcode as usize;
+                    let distance = DISTANCE_BASE[dcode] as usize
+                        + read_bits(DISTANCE_EXTRA[dcode] as u32) as usize;
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_round_trips() {
+        let mut data = b"hello hello hello world".to_vec();
+        data.extend_from_slice(&[0; 1000]);
+        data.extend((0..=255).cycle().take(3000).map(|b: u16| b as u8));
+        let compressed = deflate(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(inflate_fixed(&compressed), data);
+    }
+
+    #[test]
+    fn deflate_handles_short_inputs() {
+        assert_eq!(inflate_fixed(&deflate(&[])), Vec::<u8>::new());
+        assert_eq!(inflate_fixed(&deflate(&[7, 7])), vec![7, 7]);
+    }
+
+    #[test]
+    fn zlib_stream_has_header_and_checksum() {
+        let stream = zlib_compress(b"abc");
+        assert_eq!(&stream[0..2], &[0x78, 0x01]);
+        assert_eq!((stream[0] as u16 * 256 + stream[1] as u16) % 31, 0);
+        assert_eq!(&stream[stream.len() - 4..], &adler32(b"abc").to_be_bytes());
+    }
+
+    #[test]
+    fn encode_indexed_rejects_bad_sizes() {
+        let palette = [[0, 0, 0, 0xFF]];
+        assert_eq!(
+            encode_indexed(3, 2, &palette, &[0; 5]),
+            Err(PngError::BadLength {
+                expected: 6,
+                actual: 5
+            })
+        );
+        assert_eq!(
+            encode_indexed(u32::MAX, u32::MAX, &palette, &[]),
+            Err(PngError::TooLarge {
+                width: u32::MAX as u64,
+                height: u32::MAX as u64
+            })
+        );
+        assert!(encode_indexed(0x10000, 0x10000, &palette, &[]).is_err());
+    }
+
+    #[test]
+    fn encode_indexed_writes_png_chunks() {
+        let palette = [[0, 0, 0, 0xFF], [0xFF, 0xFF, 0xFF, 0xFF]];
+        let png = encode_indexed(3, 2, &palette, &[0, 1, 0, 1, 0, 1]).unwrap();
+
+        assert_eq!(&png[0..8], &PNG_SIGNATURE);
+        // IHDR
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..24], &[0, 0, 0, 3, 0, 0, 0, 2]);
+        assert_eq!(&png[24..29], &[8, 3, 0, 0, 0]);
+        // PLTE follows IHDR and its CRC
+        assert_eq!(&png[33..41], &[0, 0, 0, 6, b'P', b'L', b'T', b'E']);
+        // IEND
+        assert_eq!(
+            &png[png.len() - 12..],
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+    }
+}
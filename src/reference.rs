@@ -0,0 +1,348 @@
+// Differential testing: the ROMs are run both by `Cpu` and by a second,
+// deliberately simple interpreter written straight from Cowgod's reference,
+// and the full machine state is compared after every instruction.
+
+use std::fmt;
+
+use super::cartridge::Cartridge;
+use super::cpu::{Cpu, CpuState};
+use super::font::FONT_SET;
+use super::rand::ComplementaryMultiplyWithCarryGen;
+use super::{DISPLAY_PIXEL_HEIGHT, DISPLAY_PIXEL_WIDTH, MEMORY_SIZE};
+
+struct ReferenceCpu {
+    memory: Vec<u8>,
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    stack: Vec<u16>,
+    dt: u8,
+    st: u8,
+    screen: Vec<bool>,
+    keys: u16,
+    rand: ComplementaryMultiplyWithCarryGen,
+}
+
+impl ReferenceCpu {
+    fn new(rom: &[u8]) -> ReferenceCpu {
+        let mut memory = vec![0; MEMORY_SIZE];
+        memory[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+        memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+        ReferenceCpu {
+            memory,
+            v: [0; 16],
+            i: 0,
+            pc: 0x200,
+            stack: Vec::new(),
+            dt: 0,
+            st: 0,
+            screen: vec![false; DISPLAY_PIXEL_WIDTH * DISPLAY_PIXEL_HEIGHT],
+            keys: 0,
+            rand: ComplementaryMultiplyWithCarryGen::new(1),
+        }
+    }
+
+    fn pressed(&self, key: u8) -> bool {
+        self.keys & (1 << (key & 0xF)) != 0
+    }
+
+    fn step(&mut self) {
+        let pc = self.pc as usize;
+        let opcode = (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16;
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let kk = (opcode & 0xFF) as u8;
+        let nnn = opcode & 0xFFF;
+
+        self.pc += 2;
+        // the timers count instructions, like the emulator does
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+
+        let skip = |pc: &mut u16, condition: bool| {
+            if condition {
+                *pc += 2;
+            }
+        };
+
+        match opcode >> 12 {
+            0x0 if opcode == 0x00E0 => self.screen.iter_mut().for_each(|p| *p = false),
+            0x0 if opcode == 0x00EE => self.pc = self.stack.pop().unwrap(),
+            0x1 => self.pc = nnn,
+            0x2 => {
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            }
+            0x3 => skip(&mut self.pc, self.v[x] == kk),
+            0x4 => skip(&mut self.pc, self.v[x] != kk),
+            0x5 => skip(&mut self.pc, self.v[x] == self.v[y]),
+            0x6 => self.v[x] = kk,
+            0x7 => self.v[x] = self.v[x].wrapping_add(kk),
+            0x8 => {
+                let (vx, vy) = (self.v[x], self.v[y]);
+                match n {
+                    0x0 => self.v[x] = vy,
+                    0x1 => self.v[x] = vx | vy,
+                    0x2 => self.v[x] = vx & vy,
+                    0x3 => self.v[x] = vx ^ vy,
+                    // the flag is written last: it wins when x is F
+                    0x4 => {
+                        let (sum, carry) = vx.overflowing_add(vy);
+                        self.v[x] = sum;
+                        self.v[0xF] = carry as u8;
+                    }
+                    0x5 => {
+                        self.v[x] = vx.wrapping_sub(vy);
+                        self.v[0xF] = (vx >= vy) as u8;
+                    }
+                    0x6 => {
+                        self.v[x] = vx >> 1;
+                        self.v[0xF] = vx & 1;
+                    }
+                    0x7 => {
+                        self.v[x] = vy.wrapping_sub(vx);
+                        self.v[0xF] = (vy >= vx) as u8;
+                    }
+                    0xE => {
+                        self.v[x] = vx << 1;
+                        self.v[0xF] = vx >> 7;
+                    }
+                    _ => panic!("unknown opcode {:04X}", opcode),
+                }
+            }
+            0x9 => skip(&mut self.pc, self.v[x] != self.v[y]),
+            0xA => self.i = nnn,
+            0xB => self.pc = nnn + self.v[0] as u16,
+            0xC => self.v[x] = self.rand.random() as u8 & kk,
+            0xD => {
+                self.v[0xF] = 0;
+                for row in 0..n {
+                    let byte = self.memory[self.i as usize + row];
+                    for col in 0..8 {
+                        if byte & (0x80 >> col) == 0 {
+                            continue;
+                        }
+                        let px = (self.v[x] as usize + col) % DISPLAY_PIXEL_WIDTH;
+                        let py = (self.v[y] as usize + row) % DISPLAY_PIXEL_HEIGHT;
+                        let pixel = &mut self.screen[py * DISPLAY_PIXEL_WIDTH + px];
+                        if *pixel {
+                            self.v[0xF] = 1;
+                        }
+                        *pixel = !*pixel;
+                    }
+                }
+            }
+            0xE if kk == 0x9E => {
+                let pressed = self.pressed(self.v[x]);
+                skip(&mut self.pc, pressed)
+            }
+            0xE if kk == 0xA1 => {
+                let pressed = self.pressed(self.v[x]);
+                skip(&mut self.pc, !pressed)
+            }
+            0xF => match kk {
+                0x07 => self.v[x] = self.dt,
+                0x0A => match (0..16).find(|&key| self.pressed(key)) {
+                    Some(key) => self.v[x] = key,
+                    // wait: execute this instruction again
+                    None => self.pc -= 2,
+                },
+                0x15 => self.dt = self.v[x],
+                0x18 => self.st = self.v[x],
+                0x1E => self.i += self.v[x] as u16,
+                0x29 => self.i = self.v[x] as u16 * 5,
+                0x33 => {
+                    let i = self.i as usize;
+                    self.memory[i] = self.v[x] / 100;
+                    self.memory[i + 1] = self.v[x] / 10 % 10;
+                    self.memory[i + 2] = self.v[x] % 10;
+                }
+                0x55 => {
+                    for r in 0..=x {
+                        self.memory[self.i as usize + r] = self.v[r];
+                    }
+                }
+                0x65 => {
+                    for r in 0..=x {
+                        self.v[r] = self.memory[self.i as usize + r];
+                    }
+                }
+                _ => panic!("unknown opcode {:04X}", opcode),
+            },
+            _ => panic!("unknown opcode {:04X}", opcode),
+        }
+    }
+
+    fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            i: self.i,
+            sp: self.stack.len() as u8,
+            dt: self.dt,
+            st: self.st,
+            v: self.v,
+            stack: self.stack.clone(),
+            memory: self.memory.clone(),
+            display: self.screen.iter().map(|&on| on as u8).collect(),
+        }
+    }
+}
+
+// First instruction after which the two interpreters disagree.
+struct Divergence {
+    rom: &'static str,
+    instruction: usize,
+    opcode: u16,
+    expected: CpuState,
+    actual: CpuState,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: divergence after instruction #{} (opcode {:04X})",
+            self.rom, self.instruction, self.opcode
+        )?;
+        writeln!(f, "reference: {}", self.expected.summary())?;
+        writeln!(f, "cpu:       {}", self.actual.summary())?;
+        if self.expected.stack != self.actual.stack {
+            writeln!(
+                f,
+                "stack: reference {:03X?}, cpu {:03X?}",
+                self.expected.stack, self.actual.stack
+            )?;
+        }
+        for (addr, (e, a)) in self
+            .expected
+            .memory
+            .iter()
+            .zip(self.actual.memory.iter())
+            .enumerate()
+        {
+            if e != a {
+                writeln!(
+                    f,
+                    "memory[{:03X}]: reference {:02X}, cpu {:02X}",
+                    addr, e, a
+                )?;
+            }
+        }
+        if self.expected.display != self.actual.display {
+            writeln!(f, "the displays differ")?;
+        }
+        Ok(())
+    }
+}
+
+// Keypad state, as a bitmask, for every frame of a run.
+fn input_movie(frames: usize) -> Vec<u16> {
+    // cycle through all the keys, holding each one for a few frames, with
+    // some frames without any key pressed in between
+    (0..frames)
+        .map(|frame| match frame % 12 {
+            0..=7 => 1 << (frame / 12 % 16),
+            _ => 0,
+        })
+        .collect()
+}
+
+fn first_divergence(
+    rom: &'static str,
+    program: &[u8],
+    movie: &[u16],
+    cycles_per_frame: usize,
+) -> Option<Divergence> {
+    let mut cpu = Cpu::new();
+    cpu.load_cartridge(Cartridge::new(program));
+    let mut reference = ReferenceCpu::new(program);
+
+    let mut instruction = 0;
+    for &frame_keys in movie {
+        cpu.set_keypad_state(frame_keys);
+        reference.keys = frame_keys;
+
+        for _ in 0..cycles_per_frame {
+            let pc = reference.pc as usize;
+            let opcode = (reference.memory[pc] as u16) << 8 | reference.memory[pc + 1] as u16;
+            reference.step();
+            cpu.execute_cycle();
+            instruction += 1;
+
+            let (expected, actual) = (reference.state(), cpu.state());
+            if expected != actual {
+                return Some(Divergence {
+                    rom,
+                    instruction,
+                    opcode,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    None
+}
+
+fn assert_no_divergence(rom: &'static str, program: &[u8]) {
+    if let Some(divergence) = first_divergence(rom, program, &input_movie(600), 10) {
+        panic!("{}", divergence);
+    }
+}
+
+#[test]
+fn ibm() {
+    assert_no_divergence("IBM", include_bytes!("../web/roms/IBM.ch8"));
+}
+
+#[test]
+fn invaders() {
+    assert_no_divergence("INVADERS", include_bytes!("../web/roms/INVADERS.ch8"));
+}
+
+#[test]
+fn pong2() {
+    assert_no_divergence("PONG2", include_bytes!("../web/roms/PONG2.ch8"));
+}
+
+#[test]
+fn tetris() {
+    assert_no_divergence("TETRIS", include_bytes!("../web/roms/TETRIS.ch8"));
+}
+
+#[test]
+fn timebomb() {
+    assert_no_divergence("TIMEBOMB", include_bytes!("../web/roms/TIMEBOMB.ch8"));
+}
+
+#[test]
+fn ufo() {
+    assert_no_divergence("UFO", include_bytes!("../web/roms/UFO.ch8"));
+}
+
+#[test]
+fn wipeoff() {
+    assert_no_divergence("WIPEOFF", include_bytes!("../web/roms/WIPEOFF.ch8"));
+}
+
+#[test]
+fn divergence_report_shows_both_states() {
+    let expected = Cpu::new().state();
+    let mut actual = expected.clone();
+    actual.v[0xF] = 0x08;
+    actual.memory[0x300] = 0x42;
+
+    let report = Divergence {
+        rom: "TEST",
+        instruction: 3,
+        opcode: 0x800E,
+        expected,
+        actual,
+    }
+    .to_string();
+    assert!(report.starts_with("TEST: divergence after instruction #3 (opcode 800E)"));
+    assert!(report.contains("reference: PC:0200 V0:00"));
+    assert!(report.contains("VF:08 I:0000"));
+    assert!(report.contains("memory[300]: reference 00, cpu 42"));
+}
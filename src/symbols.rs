@@ -0,0 +1,261 @@
+// Symbol maps: the labels of a program and the source line of each
+// instruction, written by assemblers and read by the debugger, the
+// disassembler and the tracer.
+//
+// The file format is JSON, so that any toolchain can write it:
+//
+//     {
+//       "version": 1,
+//       "symbols": { "main": 512, "draw_player": 678 },
+//       "lines": [
+//         { "address": 512, "file": "game.8o", "line": 12 }
+//       ]
+//     }
+//
+// Addresses are numbers, up to 0xFFFF for the 64 KiB of XO-CHIP. `lines`
+// is optional, and so are labels for the addresses a toolchain knows
+// nothing about.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::json::{self, FileError, Value};
+use crate::opcode::{disassemble, Instruction};
+
+const VERSION: u64 = 1;
+const FILE: &str = "symbol";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+fn invalid(what: &'static str) -> FileError {
+    FileError::invalid(FILE, what)
+}
+
+fn address(value: &Value, what: &'static str) -> Result<u16, FileError> {
+    value
+        .as_u64()
+        .filter(|&addr| addr <= 0xFFFF)
+        .map(|addr| addr as u16)
+        .ok_or_else(|| invalid(what))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    symbols: BTreeMap<String, u16>,
+    // labels by address, the first one in name order for shared addresses
+    labels: BTreeMap<u16, String>,
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        SymbolMap::default()
+    }
+
+    pub fn parse(json: &str) -> Result<Self, FileError> {
+        let root = json::parse_file(json, FILE, VERSION)?;
+
+        let mut map = SymbolMap::new();
+        let symbols = root
+            .get("symbols")
+            .and_then(Value::as_object)
+            .ok_or_else(|| invalid("symbols"))?;
+        for (name, value) in symbols.iter() {
+            map.insert_symbol(name, address(value, "symbol address")?);
+        }
+        if let Some(lines) = root.get("lines") {
+            for line in lines.as_array().ok_or_else(|| invalid("lines"))? {
+                let addr = address(line.get("address").unwrap_or(&Value::Null), "line address")?;
+                let file = line
+                    .get("file")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid("line file"))?;
+                let number = line
+                    .get("line")
+                    .and_then(Value::as_u64)
+                    .filter(|&number| number <= u32::MAX as u64)
+                    .ok_or_else(|| invalid("line number"))?;
+                map.insert_line(addr, file, number as u32);
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn to_json(&self) -> String {
+        let symbols = self
+            .symbols
+            .iter()
+            .fold(Value::object(), |symbols, (name, &addr)| {
+                symbols.with(name, addr)
+            });
+        let lines: Vec<Value> = self
+            .lines
+            .iter()
+            .map(|(&addr, line)| {
+                Value::object()
+                    .with("address", addr)
+                    .with("file", line.file.as_str())
+                    .with("line", line.line)
+            })
+            .collect();
+        Value::object()
+            .with("version", VERSION)
+            .with("symbols", symbols)
+            .with("lines", lines)
+            .to_string()
+    }
+
+    pub fn insert_symbol(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.symbols.insert(name.to_string(), addr) {
+            if self.labels.get(&old).map(String::as_str) == Some(name) {
+                self.labels.remove(&old);
+            }
+        }
+        match self.labels.get(&addr) {
+            Some(label) if label.as_str() <= name => (),
+            _ => {
+                self.labels.insert(addr, name.to_string());
+            }
+        }
+    }
+
+    pub fn insert_line(&mut self, addr: u16, file: &str, line: u32) {
+        self.lines.insert(
+            addr,
+            SourceLine {
+                file: file.to_string(),
+                line,
+            },
+        );
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.symbols
+            .iter()
+            .map(|(name, &addr)| (name.as_str(), addr))
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).cloned()
+    }
+
+    // The label at `addr` exactly.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    pub fn source_line(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.get(&addr)
+    }
+
+    // `label`, `label+0x4` for an address after the closest label, or the
+    // address in hexadecimal when no label comes before it.
+    pub fn format_address(&self, addr: u16) -> String {
+        match self.labels.range(..=addr).next_back() {
+            Some((&start, label)) if start == addr => label.clone(),
+            Some((&start, label)) => format!("{}+0x{:X}", label, addr - start),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    // Parse a location typed by a user: `label`, `label+0x4`, `label+4`,
+    // `0x2A6` or `678`.
+    pub fn resolve(&self, location: &str) -> Option<u16> {
+        let number = |text: &str| match text.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        };
+        let (base, offset) = match location.find('+') {
+            Some(plus) => (&location[..plus], number(location[plus + 1..].trim())?),
+            None => (location, 0),
+        };
+        let base = base.trim();
+        let addr = match self.address(base) {
+            Some(addr) => addr,
+            None => number(base)?,
+        };
+        Some(addr.wrapping_add(offset) & 0xFFF)
+    }
+
+    // Disassemble an opcode, with the addresses it refers to replaced by
+    // their labels.
+    pub fn disassemble(&self, opcode: u16) -> String {
+        match Instruction::decode(opcode) {
+            Some(Instruction::Jp(nnn)) => format!("JP {}", self.format_address(nnn)),
+            Some(Instruction::Call(nnn)) => format!("CALL {}", self.format_address(nnn)),
+            Some(Instruction::LdI(nnn)) => format!("LD I, {}", self.format_address(nnn)),
+            Some(Instruction::JpV0(nnn)) => format!("JP V0, {}", self.format_address(nnn)),
+            _ => disassemble(opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceLine, SymbolMap};
+
+    const MAP: &str = r#"{
+        "version": 1,
+        "symbols": { "main": 512, "draw_player": 678, "sprite": 768 },
+        "lines": [ { "address": 678, "file": "game.8o", "line": 12 } ]
+    }"#;
+
+    #[test]
+    fn it_parses_and_writes_symbol_files() {
+        let map = SymbolMap::parse(MAP).unwrap();
+        assert_eq!(map.address("draw_player"), Some(0x2A6));
+        assert_eq!(map.label(0x200), Some("main"));
+        assert_eq!(
+            map.source_line(0x2A6),
+            Some(&SourceLine {
+                file: "game.8o".to_string(),
+                line: 12
+            })
+        );
+        assert_eq!(map.source_line(0x2A6).unwrap().to_string(), "game.8o:12");
+        assert_eq!(SymbolMap::parse(&map.to_json()), Ok(map));
+
+        let parse = |json: &str| SymbolMap::parse(json).map_err(|e| e.to_string());
+        assert_eq!(
+            parse(r#"{"version": 2, "symbols": {}}"#),
+            Err("unsupported symbol file version 2".to_string())
+        );
+        assert_eq!(
+            parse(r#"{"version": 1, "symbols": {"far": 65536}}"#),
+            Err("invalid symbol address in symbol file".to_string())
+        );
+        assert!(parse("{").unwrap_err().starts_with("invalid symbol file: "));
+    }
+
+    #[test]
+    fn it_symbolizes_addresses() {
+        let map = SymbolMap::parse(MAP).unwrap();
+        assert_eq!(map.format_address(0x2A6), "draw_player");
+        assert_eq!(map.format_address(0x2AA), "draw_player+0x4");
+        assert_eq!(map.format_address(0x1FE), "0x1FE");
+        assert_eq!(map.disassemble(0x22AA), "CALL draw_player+0x4");
+        assert_eq!(map.disassemble(0xA300), "LD I, sprite");
+        assert_eq!(map.disassemble(0x6A02), "LD VA, 0x02");
+    }
+
+    #[test]
+    fn it_resolves_locations() {
+        let map = SymbolMap::parse(MAP).unwrap();
+        assert_eq!(map.resolve("draw_player"), Some(0x2A6));
+        assert_eq!(map.resolve("draw_player+0x4"), Some(0x2AA));
+        assert_eq!(map.resolve("main + 2"), Some(0x202));
+        assert_eq!(map.resolve("0x2A6"), Some(0x2A6));
+        assert_eq!(map.resolve("678"), Some(0x2A6));
+        assert_eq!(map.resolve("missing"), None);
+    }
+}
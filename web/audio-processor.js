@@ -0,0 +1,36 @@
+// Plays the PCM samples generated by the emulator, one frame at a time.
+class Chip8AudioProcessor extends AudioWorkletProcessor {
+    constructor() {
+        super();
+        this.frames = [];
+        this.offset = 0;
+        this.port.onmessage = (event) => {
+            this.frames.push(event.data);
+            // drop the oldest frames if the emulator runs ahead of the audio
+            while (this.frames.length > 8) {
+                this.frames.shift();
+                this.offset = 0;
+            }
+        };
+    }
+
+    process(inputs, outputs) {
+        const output = outputs[0][0];
+        for (let i = 0; i < output.length; i++) {
+            if (this.frames.length === 0) {
+                // underrun: output silence until the next frame arrives
+                output.fill(0, i);
+                break;
+            }
+            const frame = this.frames[0];
+            output[i] = frame[this.offset++];
+            if (this.offset >= frame.length) {
+                this.frames.shift();
+                this.offset = 0;
+            }
+        }
+        return true;
+    }
+}
+
+registerProcessor('chip8-audio', Chip8AudioProcessor);
@@ -1,4 +1,4 @@
-import init, { Cartridge, Cpu } from './chip8.js'
+import init, { Cartridge, Cpu, Palette, PalettePreset, Persistence, SearchFilter } from './chip8.js'
 
 const CANVAS_WIDTH = 64;
 const CANVAS_HEIGHT = 32;
@@ -18,9 +18,56 @@ const ROMS = [
     'WIPEOFF',
 ];
 
+const PALETTES = [
+    ['CLASSIC', PalettePreset.Classic],
+    ['AMBER', PalettePreset.Amber],
+    ['LCD', PalettePreset.Lcd],
+    ['CONTRAST', PalettePreset.HighContrast],
+];
+
+// post-processing to reduce the flickering of sprites redrawn with XOR
+const PERSISTENCE_MODES = [
+    ['OFF', () => null],
+    ['PHOSPHOR', () => Persistence.fade(0.6)],
+    ['BLEND', () => Persistence.blend(3)],
+];
+
+const SEARCH_FILTERS = [
+    ['EQUAL', SearchFilter.Equal],
+    ['CHANGED', SearchFilter.Changed],
+    ['UP', SearchFilter.Increased],
+    ['DOWN', SearchFilter.Decreased],
+    ['VALUE', SearchFilter.Value],
+];
+// locations listed after a search
+const SEARCH_RESULTS = 20;
+
+// bytes per line of the memory view
+const MEMORY_COLUMNS = 8;
+// frames between two refreshes of the profiler heat map
+const HEAT_MAP_INTERVAL = 30;
+
 const romsSelect = document.getElementById("roms");
 const runButton = document.getElementById("run");
+const screenshotButton = document.getElementById("screenshot");
+const recordButton = document.getElementById("record");
+const profileButton = document.getElementById("profile");
+const sourceButton = document.getElementById("source");
+const memoryView = document.querySelector(".memory");
 const gameSpeeds = document.getElementById("game_speeds");
+const palettesSelect = document.getElementById("palettes");
+const persistenceSelect = document.getElementById("persistence");
+const patchInput = document.getElementById("patch");
+const searchStartButton = document.getElementById("search_start");
+const searchFiltersSelect = document.getElementById("search_filters");
+const searchValueInput = document.getElementById("search_value");
+const searchFilterButton = document.getElementById("search_filter");
+const searchResults = document.getElementById("search_results");
+const cheatLocationInput = document.getElementById("cheat_location");
+const cheatValueInput = document.getElementById("cheat_value");
+const cheatFreezeButton = document.getElementById("cheat_freeze");
+const cheatPatchButton = document.getElementById("cheat_patch");
+const cheatList = document.getElementById("cheat_list");
 
 ROMS.forEach(rom => {
     const opt = document.createElement('option');
@@ -36,6 +83,27 @@ GAME_SPEEDS.forEach(speed => {
     gameSpeeds.appendChild(opt);
 });
 
+PALETTES.forEach(([name, preset]) => {
+    const opt = document.createElement('option');
+    opt.appendChild(document.createTextNode(name));
+    opt.value = preset;
+    palettesSelect.appendChild(opt);
+});
+
+SEARCH_FILTERS.forEach(([name, filter]) => {
+    const opt = document.createElement('option');
+    opt.appendChild(document.createTextNode(name));
+    opt.value = filter;
+    searchFiltersSelect.appendChild(opt);
+});
+
+PERSISTENCE_MODES.forEach(([name], idx) => {
+    const opt = document.createElement('option');
+    opt.appendChild(document.createTextNode(name));
+    opt.value = idx;
+    persistenceSelect.appendChild(opt);
+});
+
 function initCanvas(width, height) {
     const canvas = document.getElementById("canvas");
     const ctx = canvas.getContext("2d");
@@ -44,69 +112,285 @@ function initCanvas(width, height) {
     return ctx;
 }
 
-function updateCanvas(displayState, ctx, width, height) {
-    const imageData = ctx.createImageData(width, height);
-    for (let i = 0; i < displayState.length; i++) {
-        imageData.data[i * 4] = displayState[i] === 1 ? 0x33 : 0;
-        imageData.data[i * 4 + 1] = displayState[i] === 1 ? 0xff : 0;
-        imageData.data[i * 4 + 2] = displayState[i] === 1 ? 0x66 : 0;
-        imageData.data[i * 4 + 3] = 255;
+function updateCanvas(emulator, memory, ctx) {
+    // the SUPER-CHIP high resolution has twice as many pixels on each axis,
+    // shown at the same size
+    const width = emulator.display_width();
+    const height = emulator.display_height();
+    if (ctx.canvas.width !== width) {
+        ctx.canvas.width = width;
+        ctx.canvas.height = height;
+        ctx.canvas.style.transform = `scale(${8 * CANVAS_WIDTH / width})`;
     }
-    ctx.putImageData(imageData, 0, 0);
+    // the framebuffer lives in the wasm memory: wrap it without copying.
+    // The view has to be recreated every frame because the memory can grow.
+    const ptr = emulator.render_rgba();
+    const pixels = new Uint8ClampedArray(memory.buffer, ptr, emulator.rgba_len());
+    ctx.putImageData(new ImageData(pixels, width, height), 0, 0);
+}
+
+function download(bytes, type, filename) {
+    const url = URL.createObjectURL(new Blob([bytes], { type }));
+    const link = document.createElement('a');
+    link.href = url;
+    link.download = filename;
+    link.click();
+    URL.revokeObjectURL(url);
 }
 
-async function loadRom(rom, emulator) {
+function hex(value, digits) {
+    return value.toString(16).toUpperCase().padStart(digits, '0');
+}
+
+// Hex dump of the memory, each byte shaded by how often it was executed.
+function updateMemoryView(emulator, memory) {
+    const bytes = new Uint8Array(memory.buffer, emulator.memory_ptr(), emulator.memory_len());
+    const heat = emulator.profile_heat_map();
+    const lines = [];
+    for (let addr = 0; addr < bytes.length; addr += MEMORY_COLUMNS) {
+        let line = `${hex(addr, 3)}:`;
+        for (let i = addr; i < addr + MEMORY_COLUMNS; i++) {
+            const alpha = heat ? heat[i] / 255 : 0;
+            line += alpha > 0
+                ? ` <span class='heat' style='background-color: rgba(255, 64, 0, ${alpha.toFixed(2)})'>${hex(bytes[i], 2)}</span>`
+                : ` ${hex(bytes[i], 2)}`;
+        }
+        lines.push(line);
+    }
+    memoryView.innerHTML = lines.join('<br>');
+}
+
+async function initAudio(emulator) {
+    const ctx = new AudioContext();
+    await ctx.audioWorklet.addModule('audio-processor.js');
+    const node = new AudioWorkletNode(ctx, 'chip8-audio');
+    node.connect(ctx.destination);
+    emulator.enable_audio(ctx.sampleRate);
+    return { ctx, node };
+}
+
+function playFrameAudio(audio, emulator, memory) {
+    const samples = new Float32Array(memory.buffer, emulator.audio_ptr(), emulator.audio_len());
+    // the view is only valid until the next call into the emulator: send a copy
+    audio.node.port.postMessage(samples.slice());
+}
+
+// cheats are saved in the browser, per ROM
+function cheatsKey(emulator) {
+    return `cheats-${emulator.rom_crc().toString(16)}`;
+}
+
+function saveCheats(emulator) {
+    window.localStorage.setItem(cheatsKey(emulator), emulator.cheats_json());
+    updateCheatList(emulator);
+}
+
+function updateCheatList(emulator) {
+    cheatList.innerHTML = '';
+    JSON.parse(emulator.cheats_json()).cheats.forEach((cheat, idx) => {
+        const toggle = document.createElement('input');
+        toggle.type = 'checkbox';
+        toggle.checked = cheat.enabled;
+        toggle.addEventListener("change", () => {
+            emulator.set_cheat_enabled(idx, toggle.checked);
+            saveCheats(emulator);
+        });
+        const remove = document.createElement('button');
+        remove.appendChild(document.createTextNode('DEL'));
+        remove.addEventListener("click", () => {
+            emulator.remove_cheat(idx);
+            saveCheats(emulator);
+        });
+        const row = document.createElement('div');
+        row.appendChild(toggle);
+        row.appendChild(document.createTextNode(cheat.name));
+        row.appendChild(remove);
+        cheatList.appendChild(row);
+    });
+}
+
+// Load a ROM, patched with an IPS or BPS patch when given. Throws when the
+// patch does not apply, leaving the current ROM loaded.
+async function loadRom(rom, emulator, patch = null) {
     const response = await window.fetch(`roms/${rom}.ch8`);
     const program = await response.arrayBuffer();
-    const cartridge = Cartridge.new(new Uint8Array(program));
+    let cartridge = Cartridge.new(new Uint8Array(program));
+    if (patch) {
+        cartridge = cartridge.apply_patch(patch);
+    }
     emulator.reset();
     emulator.load_cartridge(cartridge);
+    const cheats = window.localStorage.getItem(cheatsKey(emulator));
+    if (cheats) {
+        try {
+            emulator.load_cheats(cheats);
+        } catch (e) {
+            // saved in an older format, or for another ROM
+            window.localStorage.removeItem(cheatsKey(emulator));
+        }
+    }
+    updateCheatList(emulator);
+    searchResults.textContent = '';
 }
 
 const mainCtx = initCanvas(CANVAS_WIDTH, CANVAS_HEIGHT);
 (async function run() {
-    await init();
+    const wasm = await init();
 
     const emulator = Cpu.new();
 
     romsSelect.value = 'WIPEOFF';
     await loadRom('WIPEOFF', emulator);
+    updateCanvas(emulator, wasm.memory, mainCtx);
+    updateMemoryView(emulator, wasm.memory);
 
     let gameSpeed = gameSpeeds.value = 1;
      
     let running = false;
+    let audio = null;
+    let frames = 0;
     const runloop = () => {
         if (running) {
-            let result;
             // batch instructions
-            for (let i = 0; i < (gameSpeed * 10); i++) {
-                result = emulator.execute_cycle();
+            emulator.run_frame(gameSpeed * 10);
+            frames++;
+            if (emulator.is_profiling() && frames % HEAT_MAP_INTERVAL === 0) {
+                updateMemoryView(emulator, wasm.memory);
+            }
+            if (audio) {
+                playFrameAudio(audio, emulator, wasm.memory);
+            }
+            // skip the redraw when nothing was drawn during this frame
+            if (emulator.frame_changed()) {
+                updateCanvas(emulator, wasm.memory, mainCtx);
             }
-            const displayState = result.get_display_state();
-            updateCanvas(displayState, mainCtx, CANVAS_WIDTH, CANVAS_HEIGHT);
         }
         window.requestAnimationFrame(runloop);
     }
     window.requestAnimationFrame(runloop);
 
-    runButton.addEventListener("click", () => {
+    runButton.addEventListener("click", async () => {
         if (running) {
             running = false;
             runButton.innerHTML = "Start";
+            if (audio) {
+                await audio.ctx.suspend();
+            }
         } else {
+            // browsers only allow audio to start from a user gesture
+            if (!audio) {
+                audio = await initAudio(emulator);
+            }
+            await audio.ctx.resume();
             running = true;
             runButton.innerHTML = "Stop";
         }
     });
 
+    screenshotButton.addEventListener("click", () => {
+        download(emulator.screenshot_png(8), 'image/png', `${romsSelect.value}.png`);
+    });
+
+    recordButton.addEventListener("click", () => {
+        if (emulator.is_recording()) {
+            download(emulator.stop_recording(), 'image/gif', `${romsSelect.value}.gif`);
+            recordButton.innerHTML = "GIF";
+        } else {
+            emulator.start_recording(4);
+            recordButton.innerHTML = "Save";
+        }
+    });
+
+    profileButton.addEventListener("click", () => {
+        if (emulator.is_profiling()) {
+            download(emulator.profile_json(), 'application/json', `${romsSelect.value}-profile.json`);
+            emulator.disable_profiler();
+            profileButton.innerHTML = "PROFILE";
+        } else {
+            emulator.enable_profiler();
+            profileButton.innerHTML = "REPORT";
+        }
+        updateMemoryView(emulator, wasm.memory);
+    });
+
+    sourceButton.addEventListener("click", async() => {
+        const response = await window.fetch(`roms/${romsSelect.value}.ch8`);
+        const cartridge = Cartridge.new(new Uint8Array(await response.arrayBuffer()));
+        download(cartridge.decompile(), 'text/plain', `${romsSelect.value}.8o`);
+    });
+
+    searchStartButton.addEventListener("click", () => {
+        emulator.search_start();
+        searchResults.textContent = `${emulator.search_count()} locations`;
+    });
+
+    searchFilterButton.addEventListener("click", () => {
+        const count = emulator.search_filter(Number(searchFiltersSelect.value), Number(searchValueInput.value));
+        searchResults.textContent = `${count} locations\n${emulator.search_results(SEARCH_RESULTS)}`;
+    });
+
+    const addCheat = (add, kind) => {
+        const location = cheatLocationInput.value;
+        const value = Number(cheatValueInput.value);
+        try {
+            add(`${kind} ${location} = ${value}`, location, value);
+            saveCheats(emulator);
+        } catch (e) {
+            window.alert(e);
+        }
+    };
+    cheatFreezeButton.addEventListener("click", () => {
+        addCheat((name, location, value) => emulator.add_freeze(name, location, value), 'freeze');
+    });
+    cheatPatchButton.addEventListener("click", () => {
+        addCheat((name, location, value) => emulator.add_patch(name, location, value), 'patch');
+    });
+
     romsSelect.addEventListener("change", async(e) => {
+        // patches are made for a single ROM
+        patchInput.value = '';
         await loadRom(e.target.value, emulator);
+        updateCanvas(emulator, wasm.memory, mainCtx);
+        if (emulator.is_profiling()) {
+            emulator.enable_profiler();
+        }
+        updateMemoryView(emulator, wasm.memory);
+    });
+
+    patchInput.addEventListener("change", async() => {
+        const file = patchInput.files[0];
+        if (!file) {
+            return;
+        }
+        try {
+            await loadRom(romsSelect.value, emulator, new Uint8Array(await file.arrayBuffer()));
+        } catch (e) {
+            patchInput.value = '';
+            window.alert(e);
+        }
+        updateCanvas(emulator, wasm.memory, mainCtx);
+        updateMemoryView(emulator, wasm.memory);
     });
 
     gameSpeeds.addEventListener("change", async(e) => {
         gameSpeed = e.target.value;
     });
 
+    palettesSelect.addEventListener("change", (e) => {
+        emulator.set_palette(Palette.preset(Number(e.target.value)));
+        updateCanvas(emulator, wasm.memory, mainCtx);
+    });
+
+    persistenceSelect.addEventListener("change", (e) => {
+        const persistence = PERSISTENCE_MODES[Number(e.target.value)][1]();
+        if (persistence) {
+            emulator.set_persistence(persistence);
+        } else {
+            emulator.disable_persistence();
+        }
+        updateCanvas(emulator, wasm.memory, mainCtx);
+    });
+
     document.addEventListener('keydown', event => {
         const key = event.key;
         emulator.keypad_down(key);